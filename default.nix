@@ -17,7 +17,7 @@
       default = {};
       description = "Arguments provided to the program. See <https://github.com/Duckulus/mc-honeypot#options>";
       type = lib.types.submodule {
-        freeformType = lib.types.attrsOf (lib.types.oneOf [ lib.types.str lib.types.int ]);
+        freeformType = lib.types.attrsOf (lib.types.oneOf [ lib.types.str lib.types.int lib.types.bool ]);
 
         options = {
           port = lib.mkOption {
@@ -29,7 +29,13 @@
           icon-file = lib.mkOption {
             type = with lib.types; nullOr (oneOf [ str path ] );
             default = null;
-            description = "Path to png image which is displayed as the server icon. Needs to be 64x64 pixels in size.";
+            description = "Path to png image which is displayed as the server icon. Needs to be 64x64 pixels in size unless convert-icon is set.";
+          };
+
+          convert-icon = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = "Resize the icon to 64x64 pixels and convert jpeg, gif and webp images to png instead of rejecting them.";
           };
 
           webhook-url = lib.mkOption {
@@ -26,11 +26,12 @@ in
             lib.concatStringsSep " --" (
               # Main program args
               lib.mapAttrsToList 
-              (n: v: "${n} '${builtins.toString v}'") 
+              # Bools are flags without a value
+              (n: v: if builtins.isBool v then n else "${n} '${builtins.toString v}'")
               (
-                # Filter out the nulls
+                # Filter out the nulls and disabled flags
                 lib.filterAttrs 
-                (n: v: v != null)
+                (n: v: v != null && v != false)
                 (
                   # We do our own processing on this arg
                   builtins.removeAttrs cfg.settings [ "webhook-url-file" ]
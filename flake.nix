@@ -15,7 +15,11 @@
 
     craneLib = crane.mkLib pkgs;
     commonArgs = {
-      src = craneLib.cleanCargoSource ./.;
+      # Keeps the dashboard page that is embedded with include_str! next to the cargo sources
+      src = pkgs.lib.cleanSourceWith {
+        src = ./.;
+        filter = path: type: (pkgs.lib.hasSuffix ".html" path) || (craneLib.filterCargoSources path type);
+      };
       #strictDeps = true;
 
       buildInputs = with pkgs; [
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+
+/// How often we check whether a list file was changed
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Expired marks are only removed once there are this many
+const MAX_MARKS: usize = 100_000;
+/// Minimum time between two sweeps of the marks
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What happens to sources on a list
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListKind {
+    /// Answered like everyone else, but never logged or sent to webhooks
+    Ignore,
+    /// Logged with the `known benign` tag
+    Allow,
+    /// Answered as slowly as possible
+    Tarpit,
+    /// Refused before anything is read from the connection
+    Block,
+}
+
+impl ListKind {
+    pub const ALL: [ListKind; 4] = [ListKind::Ignore, ListKind::Allow, ListKind::Tarpit, ListKind::Block];
+
+    /// Lower is stricter, a source on several lists is treated according to the strictest one
+    fn rank(&self) -> u8 {
+        match self {
+            ListKind::Block => 0,
+            ListKind::Ignore => 1,
+            ListKind::Tarpit => 2,
+            ListKind::Allow => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ListKind::Ignore => "ignore",
+            ListKind::Allow => "allow",
+            ListKind::Tarpit => "tarpit",
+            ListKind::Block => "block",
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network like `192.0.2.0/24`, a plain address matches only itself.
+/// Host bits are cleared when parsing, so `192.0.2.5/24` is the same network as `192.0.2.0/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether the network is a single address
+    pub fn is_single(&self) -> bool {
+        self.prefix == if self.network.is_ipv4() { 32 } else { 128 }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| eyre!("\"{}\" is not an ip address", address))?
+            .to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => bail!("\"{}\" is not a valid prefix length", prefix),
+            },
+            None => max_prefix,
+        };
+        let network = match network {
+            IpAddr::V4(network) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(network) & mask).into())
+            }
+            IpAddr::V6(network) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(network) & mask).into())
+            }
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Parses one network per line, empty lines and everything after a `#` are ignored
+pub fn parse_cidrs(contents: &str) -> Result<Vec<Cidr>> {
+    let mut cidrs = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        cidrs.push(
+            line.parse::<Cidr>()
+                .map_err(|e| eyre!("Line {}: {}", i + 1, e))?,
+        );
+    }
+    Ok(cidrs)
+}
+
+/// A list file that is reloaded once it changes.
+/// If a changed file can't be parsed, the previous version is kept.
+struct CidrList {
+    kind: ListKind,
+    path: PathBuf,
+    cidrs: RwLock<Vec<Cidr>>,
+    state: Mutex<(Instant, Option<SystemTime>)>,
+    hits: AtomicU64,
+}
+
+impl CidrList {
+    fn open(kind: ListKind, path: PathBuf) -> Result<CidrList> {
+        let list = CidrList {
+            kind,
+            cidrs: RwLock::new(Vec::new()),
+            state: Mutex::new((Instant::now(), None)),
+            hits: AtomicU64::new(0),
+            path,
+        };
+        list.reload()?;
+        Ok(list)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    fn reload(&self) -> Result<()> {
+        let modified = self.modified();
+        *self.state.lock().unwrap() = (Instant::now(), modified);
+        let cidrs = parse_cidrs(&fs::read_to_string(&self.path)?)
+            .map_err(|e| eyre!("Unable to parse {}: {}", self.path.display(), e))?;
+        log::info!(
+            "Loaded {} network(s) into the {} list from {}",
+            cidrs.len(),
+            self.kind.name(),
+            self.path.display()
+        );
+        *self.cidrs.write().unwrap() = cidrs;
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) {
+        let changed = {
+            let mut state = self.state.lock().unwrap();
+            if state.0.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            state.0 = Instant::now();
+            self.modified() != state.1
+        };
+        if changed {
+            if let Err(e) = self.reload() {
+                log::warn!("{}, keeping the previous {} list", e, self.kind.name());
+            }
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.reload_if_changed();
+        self.cidrs.read().unwrap().iter().any(|c| c.contains(ip))
+    }
+}
+
+/// Sources treated as if they were on a list until the given time
+#[derive(Default)]
+struct Marks {
+    entries: HashMap<IpAddr, (ListKind, Instant)>,
+    swept: Option<Instant>,
+}
+
+/// Ignore, allow, block and tarpit lists loaded from files.
+/// A source on several lists is treated according to the strictest one: block, then ignore, then tarpit, then allow.
+/// Sources can also be put on a list for a while with [`AccessLists::mark`], e.g. once they were fingerprinted,
+/// or until the next restart with [`AccessLists::insert`].
+#[derive(Default)]
+pub struct AccessLists {
+    lists: Vec<CidrList>,
+    marks: Mutex<Marks>,
+    /// Networks added while running, sorted like the lists
+    runtime: RwLock<Vec<(ListKind, Cidr)>>,
+    /// Connections matched by networks added while running, indexed by [`ListKind::rank`]
+    inserted_hits: [AtomicU64; 4],
+    /// Connections matched by marks, indexed by [`ListKind::rank`]
+    marked_hits: [AtomicU64; 4],
+}
+
+impl AccessLists {
+    pub fn new() -> AccessLists {
+        AccessLists::default()
+    }
+
+    pub fn add(&mut self, kind: ListKind, path: PathBuf) -> Result<()> {
+        self.lists.push(CidrList::open(kind, path)?);
+        self.lists.sort_by_key(|l| l.kind.rank());
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.is_empty() && self.runtime.read().unwrap().is_empty()
+    }
+
+    /// Returns the list `ip` is on and counts the hit, this should happen once per connection
+    pub fn check(&self, ip: IpAddr) -> Option<ListKind> {
+        let list = self.lists.iter().find(|l| l.contains(ip));
+        match (list, self.inserted(ip)) {
+            (Some(list), Some(kind)) if kind.rank() < list.kind.rank() => {
+                self.inserted_hits[kind.rank() as usize].fetch_add(1, Ordering::Relaxed);
+                Some(kind)
+            }
+            (Some(list), _) => {
+                list.hits.fetch_add(1, Ordering::Relaxed);
+                Some(list.kind)
+            }
+            (None, Some(kind)) => {
+                self.inserted_hits[kind.rank() as usize].fetch_add(1, Ordering::Relaxed);
+                Some(kind)
+            }
+            (None, None) => {
+                let kind = self.marked(ip)?;
+                self.marked_hits[kind.rank() as usize].fetch_add(1, Ordering::Relaxed);
+                Some(kind)
+            }
+        }
+    }
+
+    /// Returns the list `ip` is on without counting it as a hit
+    pub fn matching(&self, ip: IpAddr) -> Option<ListKind> {
+        let list = self.lists.iter().find(|l| l.contains(ip)).map(|l| l.kind);
+        match (list, self.inserted(ip)) {
+            (Some(a), Some(b)) => Some(if a.rank() <= b.rank() { a } else { b }),
+            (a, b) => a.or(b).or_else(|| self.marked(ip)),
+        }
+    }
+
+    fn inserted(&self, ip: IpAddr) -> Option<ListKind> {
+        self.runtime
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(_, cidr)| cidr.contains(ip))
+            .map(|(kind, _)| *kind)
+    }
+
+    /// Puts a network on a list until the next restart, returns false if it already was on it
+    pub fn insert(&self, kind: ListKind, cidr: Cidr) -> bool {
+        let mut runtime = self.runtime.write().unwrap();
+        if runtime.contains(&(kind, cidr)) {
+            return false;
+        }
+        runtime.push((kind, cidr));
+        runtime.sort_by_key(|(kind, _)| kind.rank());
+        true
+    }
+
+    /// Takes a network added with [`AccessLists::insert`] off a list again, along with marks of the same address.
+    /// Returns false if it wasn't added, networks in list files have to be removed from the file.
+    pub fn remove(&self, kind: ListKind, cidr: Cidr) -> bool {
+        let mut runtime = self.runtime.write().unwrap();
+        let before = runtime.len();
+        runtime.retain(|entry| *entry != (kind, cidr));
+        let mut marks = self.marks.lock().unwrap();
+        let marked = marks.entries.get(&cidr.network).is_some_and(|(k, _)| *k == kind) && cidr.is_single();
+        if marked {
+            marks.entries.remove(&cidr.network);
+        }
+        runtime.len() < before || marked
+    }
+
+    /// The networks added while running
+    pub fn inserted_networks(&self) -> Vec<(ListKind, Cidr)> {
+        self.runtime.read().unwrap().clone()
+    }
+
+    /// Treats `ip` as if it was on the given list for a while, unless it is on one of the list files
+    pub fn mark(&self, ip: IpAddr, kind: ListKind, duration: Duration) {
+        let mut marks = self.marks.lock().unwrap();
+        if marks.entries.len() >= MAX_MARKS && !marks.entries.contains_key(&ip) {
+            // Sweeping on every new mark would make each connection scan all marks
+            if marks.swept.is_none_or(|at| at.elapsed() >= SWEEP_INTERVAL) {
+                let now = Instant::now();
+                marks.entries.retain(|_, (_, until)| *until > now);
+                marks.swept = Some(now);
+            }
+            if marks.entries.len() >= MAX_MARKS {
+                log::debug!("Not marking {}, too many sources are marked", ip);
+                return;
+            }
+        }
+        marks.entries.insert(ip, (kind, Instant::now() + duration));
+    }
+
+    fn marked(&self, ip: IpAddr) -> Option<ListKind> {
+        match self.marks.lock().unwrap().entries.get(&ip) {
+            Some((kind, until)) if *until > Instant::now() => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// The number of connections each list file matched so far
+    pub fn hits(&self) -> Vec<(ListKind, PathBuf, u64)> {
+        self.lists
+            .iter()
+            .map(|l| (l.kind, l.path.clone(), l.hits.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// The number of connections matched so far by networks added while running and by marks, per list
+    pub fn runtime_hits(&self) -> Vec<(ListKind, u64, u64)> {
+        ListKind::ALL
+            .iter()
+            .map(|kind| {
+                let index = kind.rank() as usize;
+                (
+                    *kind,
+                    self.inserted_hits[index].load(Ordering::Relaxed),
+                    self.marked_hits[index].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ipv4_networks() {
+        let cidr = "192.0.2.0/24".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("192.0.2.1")));
+        assert!(cidr.contains(ip("192.0.2.255")));
+        assert!(!cidr.contains(ip("192.0.3.1")));
+        assert!(!cidr.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn matches_ipv6_networks() {
+        let cidr = "2001:db8::/32".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+        assert!(!cidr.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn handles_prefix_bounds() {
+        let everything = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(everything.contains(ip("203.0.113.7")));
+        assert!("::/0".parse::<Cidr>().unwrap().contains(ip("2001:db8::1")));
+
+        let single = "192.0.2.1".parse::<Cidr>().unwrap();
+        assert!(single.is_single());
+        assert_eq!(single, "192.0.2.1/32".parse::<Cidr>().unwrap());
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+        assert!("2001:db8::1/128".parse::<Cidr>().unwrap().is_single());
+
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/-1".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_addresses_as_ipv4() {
+        let cidr = "192.0.2.0/24".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("::ffff:192.0.2.1")));
+        // A mapped network is stored as the IPv4 network
+        let mapped = "::ffff:192.0.2.1".parse::<Cidr>().unwrap();
+        assert_eq!(mapped.to_string(), "192.0.2.1/32");
+        assert!(mapped.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn clears_host_bits() {
+        let cidr = "192.0.2.5/24".parse::<Cidr>().unwrap();
+        assert_eq!(cidr, "192.0.2.0/24".parse::<Cidr>().unwrap());
+        assert_eq!(cidr.to_string(), "192.0.2.0/24");
+        assert_eq!("2001:db8::1/32".parse::<Cidr>().unwrap().to_string(), "2001:db8::/32");
+        assert_eq!("203.0.113.7/0".parse::<Cidr>().unwrap().to_string(), "0.0.0.0/0");
+
+        let access = AccessLists::default();
+        assert!(access.insert(ListKind::Block, "192.0.2.5/24".parse().unwrap()));
+        assert!(!access.insert(ListKind::Block, "192.0.2.0/24".parse().unwrap()));
+        assert!(access.remove(ListKind::Block, "192.0.2.0/24".parse().unwrap()));
+        assert!(access.inserted_networks().is_empty());
+    }
+
+    #[test]
+    fn parses_list_files() {
+        let cidrs = parse_cidrs("# scanners\n192.0.2.0/24\n\n2001:db8::/32 # v6\n").unwrap();
+        assert_eq!(cidrs.len(), 2);
+        let error = parse_cidrs("192.0.2.0/24\nnot an ip\n").unwrap_err();
+        assert!(error.to_string().starts_with("Line 2:"));
+    }
+
+    #[test]
+    fn picks_the_strictest_list() {
+        let access = AccessLists::default();
+        access.insert(ListKind::Allow, "192.0.2.0/24".parse().unwrap());
+        access.insert(ListKind::Block, "192.0.2.1".parse().unwrap());
+        assert_eq!(access.matching(ip("192.0.2.1")), Some(ListKind::Block));
+        assert_eq!(access.matching(ip("192.0.2.2")), Some(ListKind::Allow));
+        assert_eq!(access.matching(ip("198.51.100.1")), None);
+    }
+
+    #[test]
+    fn marks_only_apply_to_unlisted_sources() {
+        let access = AccessLists::default();
+        access.insert(ListKind::Allow, "192.0.2.0/24".parse().unwrap());
+        access.mark(ip("192.0.2.1"), ListKind::Tarpit, Duration::from_secs(60));
+        access.mark(ip("198.51.100.1"), ListKind::Tarpit, Duration::from_secs(60));
+        access.mark(ip("198.51.100.2"), ListKind::Tarpit, Duration::ZERO);
+        assert_eq!(access.matching(ip("192.0.2.1")), Some(ListKind::Allow));
+        assert_eq!(access.matching(ip("198.51.100.1")), Some(ListKind::Tarpit));
+        assert_eq!(access.matching(ip("198.51.100.2")), None);
+    }
+
+    #[test]
+    fn counts_hits_of_inserted_networks_and_marks() {
+        let access = AccessLists::default();
+        access.insert(ListKind::Block, "192.0.2.0/24".parse().unwrap());
+        access.mark(ip("198.51.100.1"), ListKind::Tarpit, Duration::from_secs(60));
+        assert_eq!(access.check(ip("192.0.2.1")), Some(ListKind::Block));
+        assert_eq!(access.check(ip("192.0.2.2")), Some(ListKind::Block));
+        assert_eq!(access.check(ip("198.51.100.1")), Some(ListKind::Tarpit));
+        assert_eq!(access.check(ip("203.0.113.1")), None);
+        // Looking a source up without checking it isn't a hit
+        assert_eq!(access.matching(ip("192.0.2.3")), Some(ListKind::Block));
+
+        let hits = access.runtime_hits();
+        assert!(hits.contains(&(ListKind::Block, 2, 0)));
+        assert!(hits.contains(&(ListKind::Tarpit, 0, 1)));
+        assert!(hits.contains(&(ListKind::Allow, 0, 0)));
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::access::{AccessLists, Cidr, ListKind};
+use crate::connections::ActiveConnections;
+use crate::delivery::DeliveryStats;
+use crate::persona::parse_players;
+use crate::pipeline::Pipeline;
+use crate::routing::Router;
+
+/// Requests with a larger body are rejected
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Where the admin api listens, `127.0.0.1:8081` or `unix:/run/mc-honeypot/admin.sock`
+#[derive(Clone, Debug)]
+pub enum AdminListen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for AdminListen {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(AdminListen::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(eyre!("Unix sockets are not supported on this platform: {}", path));
+        }
+        s.parse::<SocketAddr>()
+            .map(AdminListen::Tcp)
+            .map_err(|_| eyre!("\"{}\" is neither an ip:port nor unix:<path>", s))
+    }
+}
+
+impl Display for AdminListen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminListen::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            AdminListen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Changes to a persona, fields that are left out stay as they are
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PersonaUpdate {
+    version_string: Option<String>,
+    protocol_version: Option<i32>,
+    max_players: Option<i32>,
+    online_players: Option<i32>,
+    /// `NAME:UUID`, like `--players`
+    players: Option<Vec<String>>,
+    motd: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NetworkRequest {
+    network: String,
+}
+
+/// A local HTTP api to inspect and control the running honeypot, every request needs the token as a bearer token
+pub struct AdminApi {
+    pub token: String,
+    pub connections: Arc<ActiveConnections>,
+    pub router: Arc<Router>,
+    pub access: Arc<AccessLists>,
+    pub pipeline: Arc<Pipeline>,
+    pub delivery: Arc<DeliveryStats>,
+}
+
+type Reply = (u16, Value);
+
+impl AdminApi {
+    /// Listens in the background
+    pub fn start(self, listen: &AdminListen) -> Result<()> {
+        let server = match listen {
+            AdminListen::Tcp(address) => {
+                if !address.ip().is_loopback() {
+                    log::warn!("The admin api listens on {}, which is reachable from other hosts", address);
+                }
+                Server::http(address).map_err(|e| eyre!("{}", e))
+            }
+            #[cfg(unix)]
+            AdminListen::Unix(path) => bind_unix(path),
+        }
+        .map_err(|e| eyre!("Unable to listen on {}: {}", listen, e))?;
+        log::info!("Admin api listening on {}", listen);
+        self.serve(server);
+        Ok(())
+    }
+
+    fn serve(self, server: Server) {
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                self.handle(request);
+            }
+        });
+    }
+
+    fn handle(&self, mut request: Request) {
+        let (status, body) = if self.authorized(&request) {
+            let url = request.url().to_string();
+            let path = url.split('?').next().unwrap_or_default().trim_end_matches('/');
+            let segments = path.split('/').skip(1).collect::<Vec<&str>>();
+            let method = request.method().clone();
+            match (method, segments.as_slice()) {
+                (Method::Get, ["api", "connections"]) => (200, json!(self.connections.list())),
+                (Method::Get, ["api", "counters"]) => (200, self.counters()),
+                (Method::Get, ["api", "personas"]) => (200, self.personas()),
+                (Method::Patch, ["api", "personas", name]) => self.update_persona(name, &mut request),
+                (Method::Get, ["api", "lists"]) => (200, self.lists()),
+                (Method::Post, ["api", "lists", kind]) => self.change_list(kind, true, &mut request),
+                (Method::Delete, ["api", "lists", kind]) => self.change_list(kind, false, &mut request),
+                (Method::Post, ["api", "flush"]) => {
+                    self.pipeline.flush();
+                    log::info!("Flushing all sinks as asked through the admin api");
+                    (200, json!({"flushed": true}))
+                }
+                (Method::Post, ["api", "pause"]) => {
+                    self.pipeline.set_paused(true);
+                    (200, json!({"paused": true}))
+                }
+                (Method::Post, ["api", "resume"]) => {
+                    self.pipeline.set_paused(false);
+                    (200, json!({"paused": false}))
+                }
+                _ => (404, json!({"error": "not found"})),
+            }
+        } else {
+            (401, json!({"error": "missing or wrong token"}))
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        if let Err(e) = request.respond(response) {
+            log::debug!("Unable to respond to an admin api request: {}", e);
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            // Compared in constant time so response times don't reveal how much of a guess was right
+            .is_some_and(|token| bool::from(token.trim().as_bytes().ct_eq(self.token.as_bytes())))
+    }
+
+    fn counters(&self) -> Value {
+        let hits = self
+            .access
+            .hits()
+            .into_iter()
+            .map(|(kind, path, hits)| json!({"list": kind.name(), "file": path, "hits": hits}))
+            .collect::<Vec<Value>>();
+        let runtime_hits = self
+            .access
+            .runtime_hits()
+            .into_iter()
+            .map(|(kind, inserted, marked)| json!({"list": kind.name(), "inserted": inserted, "marked": marked}))
+            .collect::<Vec<Value>>();
+        json!({
+            "active_connections": self.connections.len(),
+            "pipeline": self.pipeline.counters(),
+            "delivery": {
+                "delivered": self.delivery.delivered.load(Ordering::Relaxed),
+                "retried": self.delivery.retried.load(Ordering::Relaxed),
+                "rate_limited": self.delivery.rate_limited.load(Ordering::Relaxed),
+                "spooled": self.delivery.spooled.load(Ordering::Relaxed),
+                "dropped": self.delivery.dropped.load(Ordering::Relaxed),
+            },
+            "access_list_hits": hits,
+            "runtime_hits": runtime_hits,
+        })
+    }
+
+    fn personas(&self) -> Value {
+        let personas = self
+            .router
+            .personas()
+            .into_iter()
+            .map(|(name, persona)| {
+                let mut value = json!(persona);
+                value["name"] = json!(name);
+                value
+            })
+            .collect::<Vec<Value>>();
+        json!(personas)
+    }
+
+    fn update_persona(&self, name: &str, request: &mut Request) -> Reply {
+        let update: PersonaUpdate = match read_json(request) {
+            Ok(update) => update,
+            Err(reply) => return reply,
+        };
+        let result = self.router.update_persona(name, |persona| {
+            if let Some(version_string) = update.version_string {
+                persona.version_string = version_string;
+            }
+            if let Some(protocol_version) = update.protocol_version {
+                persona.protocol_version = protocol_version;
+            }
+            if let Some(max_players) = update.max_players {
+                persona.max_players = max_players;
+            }
+            if let Some(online_players) = update.online_players {
+                persona.online_players = Some(online_players);
+            }
+            if let Some(players) = update.players {
+                persona.players = parse_players(&players);
+            }
+            if let Some(motd) = update.motd {
+                persona.motd = motd;
+            }
+        });
+        match result {
+            Ok(persona) => {
+                log::info!("Persona \"{}\" was changed through the admin api", name);
+                (200, json!(persona))
+            }
+            Err(e) => (404, json!({"error": e.to_string()})),
+        }
+    }
+
+    fn lists(&self) -> Value {
+        let files = self
+            .access
+            .hits()
+            .into_iter()
+            .map(|(kind, path, _)| json!({"list": kind.name(), "file": path}))
+            .collect::<Vec<Value>>();
+        let inserted = self
+            .access
+            .inserted_networks()
+            .into_iter()
+            .map(|(kind, cidr)| json!({"list": kind.name(), "network": cidr.to_string()}))
+            .collect::<Vec<Value>>();
+        json!({"files": files, "inserted": inserted})
+    }
+
+    fn change_list(&self, kind: &str, insert: bool, request: &mut Request) -> Reply {
+        let Some(kind) = ListKind::ALL.into_iter().find(|k| k.name() == kind) else {
+            return (404, json!({"error": format!("Unknown list \"{}\"", kind)}));
+        };
+        let network: NetworkRequest = match read_json(request) {
+            Ok(network) => network,
+            Err(reply) => return reply,
+        };
+        let cidr = match network.network.parse::<Cidr>() {
+            Ok(cidr) => cidr,
+            Err(e) => return (400, json!({"error": e.to_string()})),
+        };
+        if insert {
+            if self.access.insert(kind, cidr) {
+                log::info!("Added {} to the {} list through the admin api", cidr, kind.name());
+                (201, json!({"list": kind.name(), "network": cidr.to_string()}))
+            } else {
+                (200, json!({"list": kind.name(), "network": cidr.to_string()}))
+            }
+        } else if self.access.remove(kind, cidr) {
+            log::info!("Removed {} from the {} list through the admin api", cidr, kind.name());
+            (200, json!({"list": kind.name(), "network": cidr.to_string()}))
+        } else {
+            let error = format!("{} wasn't added to the {} list through the api, list files have to be edited", cidr, kind.name());
+            (404, json!({"error": error}))
+        }
+    }
+}
+
+/// Binds a socket only the owner can connect to
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<Server> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket left behind by a previous run would make binding fail, anything else at the path is left alone
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(eyre!("{} exists and isn't a socket", path.display())),
+        Err(_) => {}
+    }
+    // Created with these permissions right away instead of changing them after binding, when others could already
+    // have connected. The umask applies to the whole process, files created meanwhile end up private as well.
+    // SAFETY: umask only swaps the mask of the process
+    let umask = unsafe { libc::umask(0o177) };
+    let server = Server::http_unix(path);
+    // SAFETY: see above
+    unsafe { libc::umask(umask) };
+    server.map_err(|e| eyre!("{}", e))
+}
+
+fn read_json<T: DeserializeOwned>(request: &mut Request) -> std::result::Result<T, Reply> {
+    let mut body = Vec::new();
+    if let Err(e) = request.as_reader().take(MAX_BODY_SIZE + 1).read_to_end(&mut body) {
+        return Err((400, json!({"error": e.to_string()})));
+    }
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err((413, json!({"error": "body too large"})));
+    }
+    serde_json::from_slice(&body).map_err(|e| (400, json!({"error": e.to_string()})))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::blocking::Client;
+
+    use crate::fingerprint::FingerprintRules;
+    use crate::persona::Persona;
+    use crate::pipeline::Enrichment;
+
+    use super::*;
+
+    const TOKEN: &str = "admin-secret";
+
+    fn api() -> AdminApi {
+        let persona = Persona {
+            version_string: String::from("1.21"),
+            protocol_version: 767,
+            max_players: 20,
+            online_players: None,
+            players: vec![],
+            motd: String::from("A Minecraft Server"),
+        };
+        let router = Arc::new(Router::new(persona));
+        let access = Arc::new(AccessLists::new());
+        let enrichment = Enrichment::new(router.clone(), access.clone(), FingerprintRules::default());
+        AdminApi {
+            token: String::from(TOKEN),
+            connections: Arc::new(ActiveConnections::new()),
+            router,
+            access,
+            pipeline: Arc::new(Pipeline::builder(enrichment).build().unwrap()),
+            delivery: Arc::new(DeliveryStats::default()),
+        }
+    }
+
+    /// Serves the api on a random port and returns its base url
+    fn serve(api: AdminApi) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        api.serve(server);
+        url
+    }
+
+    fn call(method: reqwest::Method, url: &str, body: Option<Value>) -> (u16, Value) {
+        let mut request = Client::new().request(method, url).bearer_auth(TOKEN);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().unwrap();
+        (response.status().as_u16(), response.json().unwrap())
+    }
+
+    #[test]
+    fn needs_the_token() {
+        let url = serve(api());
+        let client = Client::new();
+        let missing = client.get(format!("{}/api/counters", url)).send().unwrap();
+        assert_eq!(missing.status().as_u16(), 401);
+        let wrong = client.get(format!("{}/api/counters", url)).bearer_auth("admin-secreT").send().unwrap();
+        assert_eq!(wrong.status().as_u16(), 401);
+        let basic = client.get(format!("{}/api/counters", url)).basic_auth(TOKEN, None::<&str>).send().unwrap();
+        assert_eq!(basic.status().as_u16(), 401);
+        // Without a token nothing is routed, not even unknown paths
+        assert_eq!(client.get(format!("{}/nothing", url)).send().unwrap().status().as_u16(), 401);
+
+        let (status, counters) = call(reqwest::Method::GET, &format!("{}/api/counters", url), None);
+        assert_eq!(status, 200);
+        assert_eq!(counters["active_connections"], 0);
+    }
+
+    #[test]
+    fn routes_requests() {
+        let url = serve(api());
+        let get = |path: &str| call(reqwest::Method::GET, &format!("{}{}", url, path), None);
+        assert_eq!(get("/api/connections"), (200, json!([])));
+        assert_eq!(get("/api/personas/").1[0]["name"], "default");
+        assert_eq!(get("/api/lists?verbose").0, 200);
+        assert_eq!(get("/api/unknown").0, 404);
+        assert_eq!(call(reqwest::Method::DELETE, &format!("{}/api/counters", url), None).0, 404);
+
+        let (status, persona) =
+            call(reqwest::Method::PATCH, &format!("{}/api/personas/default", url), Some(json!({"motd": "Maintenance"})));
+        assert_eq!(status, 200);
+        assert_eq!(persona["motd"], "Maintenance");
+        assert_eq!(
+            call(reqwest::Method::PATCH, &format!("{}/api/personas/lobby", url), Some(json!({"motd": "x"}))).0,
+            404
+        );
+        assert_eq!(
+            call(reqwest::Method::PATCH, &format!("{}/api/personas/default", url), Some(json!({"color": "red"}))).0,
+            400
+        );
+
+        let network = Some(json!({"network": "192.0.2.5/24"}));
+        let lists = format!("{}/api/lists/block", url);
+        assert_eq!(call(reqwest::Method::POST, &lists, network.clone()).0, 201);
+        assert_eq!(call(reqwest::Method::POST, &lists, network.clone()).0, 200);
+        assert_eq!(get("/api/lists").1["inserted"], json!([{"list": "block", "network": "192.0.2.0/24"}]));
+        assert_eq!(call(reqwest::Method::DELETE, &lists, network.clone()).0, 200);
+        assert_eq!(call(reqwest::Method::DELETE, &lists, network.clone()).0, 404);
+        assert_eq!(call(reqwest::Method::POST, &format!("{}/api/lists/deny", url), network).0, 404);
+        assert_eq!(call(reqwest::Method::POST, &lists, Some(json!({"network": "example.com"}))).0, 400);
+
+        assert_eq!(call(reqwest::Method::POST, &format!("{}/api/pause", url), None), (200, json!({"paused": true})));
+        assert_eq!(get("/api/counters").1["pipeline"]["paused"], true);
+        assert_eq!(call(reqwest::Method::POST, &format!("{}/api/resume", url), None).1, json!({"paused": false}));
+        assert_eq!(call(reqwest::Method::POST, &format!("{}/api/flush", url), None).1, json!({"flushed": true}));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_a_private_socket_and_replaces_only_stale_sockets() {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let directory = std::env::temp_dir().join(format!(
+            "mc-honeypot-admin-{}-{}",
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let file = directory.join("admin.toml");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(api().start(&AdminListen::Unix(file.clone())).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+        let socket = directory.join("admin.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        api().start(&AdminListen::Unix(socket.clone())).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        write!(stream, "GET /api/connections HTTP/1.1\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n", TOKEN)
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Chunks sent in the same direction less than this many milliseconds apart are stored as one record
+const COALESCE_MS: u64 = 10;
+
+/// Captures are skipped while the directory holds this many files
+pub const MAX_CAPTURE_FILES: usize = 10_000;
+
+/// Bytes recorded per connection, both directions combined, anything beyond is left out of the file
+pub const MAX_CAPTURE_BYTES: usize = 64 * 1024;
+
+/// How often a full directory is counted again, so captures resume once files were removed
+const RECOUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The first line of a capture file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CaptureHeader {
+    pub remote_address: SocketAddr,
+    /// The listener that accepted the connection, missing in older captures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_address: Option<SocketAddr>,
+    pub started: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Sent by the client
+    Inbound,
+    /// Sent by the honeypot
+    Outbound,
+}
+
+/// Every following line of a capture file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CaptureRecord {
+    /// Milliseconds since the connection was accepted
+    pub offset_ms: u64,
+    pub direction: Direction,
+    #[serde(serialize_with = "serialize_base64", deserialize_with = "deserialize_base64")]
+    pub data: Vec<u8>,
+}
+
+/// Records the raw traffic of a single connection into a JSON lines file
+pub struct SessionCapture {
+    file: BufWriter<File>,
+    started: Instant,
+    pending: Option<CaptureRecord>,
+    /// When data was last added to the pending record
+    pending_ms: u64,
+    /// Bytes recorded so far, including the pending record
+    recorded: usize,
+}
+
+impl SessionCapture {
+    /// Creates a file named after the time and source of the connection in `directory`
+    pub fn create(
+        directory: &Path,
+        remote_address: SocketAddr,
+        local_address: SocketAddr,
+    ) -> Result<SessionCapture> {
+        let header = CaptureHeader {
+            remote_address,
+            local_address: Some(local_address),
+            started: Utc::now(),
+        };
+        let name = format!(
+            "{}_{}_{}.jsonl",
+            header.started.format("%Y%m%dT%H%M%S%.3fZ"),
+            remote_address.ip(),
+            remote_address.port()
+        );
+        let mut file = BufWriter::new(File::create(directory.join(name))?);
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+
+        Ok(SessionCapture {
+            file,
+            started: Instant::now(),
+            pending: None,
+            pending_ms: 0,
+            recorded: 0,
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, data: &[u8]) {
+        let data = &data[..data.len().min(MAX_CAPTURE_BYTES - self.recorded)];
+        if data.is_empty() {
+            return;
+        }
+        self.recorded += data.len();
+        let offset_ms = self.started.elapsed().as_millis() as u64;
+        if let Some(pending) = &mut self.pending {
+            if pending.direction == direction && offset_ms - self.pending_ms <= COALESCE_MS {
+                pending.data.extend_from_slice(data);
+                self.pending_ms = offset_ms;
+                return;
+            }
+        }
+        self.flush_pending();
+        self.pending = Some(CaptureRecord {
+            offset_ms,
+            direction,
+            data: data.to_vec(),
+        });
+        self.pending_ms = offset_ms;
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some(record) = self.pending.take() {
+            let result = serde_json::to_string(&record)
+                .map_err(|e| e.into())
+                .and_then(|line| writeln!(self.file, "{}", line));
+            if let Err(e) = result {
+                log::debug!("Unable to write capture record: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for SessionCapture {
+    fn drop(&mut self) {
+        self.flush_pending();
+        let _ = self.file.flush();
+    }
+}
+
+/// The directory given by `--capture-dir`, which stops taking new captures once it holds [MAX_CAPTURE_FILES] files
+pub struct CaptureDir {
+    path: PathBuf,
+    /// The number of files and when they were last counted
+    files: Mutex<(usize, Instant)>,
+}
+
+impl CaptureDir {
+    pub fn new(path: PathBuf) -> CaptureDir {
+        let files = count_captures(&path);
+        CaptureDir {
+            path,
+            files: Mutex::new((files, Instant::now())),
+        }
+    }
+
+    /// Starts capturing a connection, `None` while the directory is full
+    pub fn capture(&self, remote_address: SocketAddr, local_address: SocketAddr) -> Result<Option<SessionCapture>> {
+        {
+            let mut files = self.files.lock().unwrap();
+            if files.0 >= MAX_CAPTURE_FILES {
+                if files.1.elapsed() < RECOUNT_INTERVAL {
+                    return Ok(None);
+                }
+                *files = (count_captures(&self.path), Instant::now());
+                if files.0 >= MAX_CAPTURE_FILES {
+                    log::warn!(
+                        "{} holds {} captures, no further connections are captured until some are removed",
+                        self.path.display(),
+                        files.0
+                    );
+                    return Ok(None);
+                }
+            }
+            files.0 += 1;
+        }
+        SessionCapture::create(&self.path, remote_address, local_address).map(Some)
+    }
+}
+
+fn count_captures(directory: &Path) -> usize {
+    fs::read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().is_some_and(|e| e == "jsonl"))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+/// A capture file read back from disk
+#[derive(Clone, Debug)]
+pub struct CapturedSession {
+    pub header: CaptureHeader,
+    pub records: Vec<CaptureRecord>,
+}
+
+impl CapturedSession {
+    pub fn load(path: &Path) -> Result<CapturedSession> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents.lines().filter(|l| !l.trim().is_empty());
+        let header = lines
+            .next()
+            .ok_or_else(|| eyre!("{} is empty", path.display()))?;
+        let header: CaptureHeader = serde_json::from_str(header)
+            .map_err(|e| eyre!("Unable to parse the header of {}: {}", path.display(), e))?;
+        let records = lines
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| eyre!("Unable to parse record #{} of {}: {}", i + 1, path.display(), e))
+            })
+            .collect::<Result<Vec<CaptureRecord>>>()?;
+        Ok(CapturedSession { header, records })
+    }
+
+    /// All bytes sent in one direction, in order
+    pub fn bytes(&self, direction: Direction) -> Vec<u8> {
+        self.records
+            .iter()
+            .filter(|r| r.direction == direction)
+            .flat_map(|r| r.data.iter().copied())
+            .collect()
+    }
+}
+
+fn serialize_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64_STANDARD.encode(data))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64_STANDARD
+        .decode(encoded)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn directory() -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "mc-honeypot-capture-{}-{}",
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn captures(directory: &Path) -> Vec<PathBuf> {
+        fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()).collect()
+    }
+
+    #[test]
+    fn stops_recording_at_the_byte_limit() {
+        let directory = directory();
+        let dir = CaptureDir::new(directory.clone());
+        let mut capture = dir
+            .capture("192.0.2.1:50000".parse().unwrap(), "198.51.100.1:25565".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        capture.record(Direction::Inbound, &[1; 1000]);
+        capture.record(Direction::Outbound, &vec![2; MAX_CAPTURE_BYTES]);
+        capture.record(Direction::Inbound, &[3; 10]);
+        drop(capture);
+
+        let session = CapturedSession::load(&captures(&directory)[0]).unwrap();
+        assert_eq!(session.bytes(Direction::Inbound), vec![1; 1000]);
+        assert_eq!(session.bytes(Direction::Outbound), vec![2; MAX_CAPTURE_BYTES - 1000]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn skips_captures_while_the_directory_is_full() {
+        let directory = directory();
+        let remote = "192.0.2.1:50000".parse().unwrap();
+        let local = "198.51.100.1:25565".parse().unwrap();
+        let dir = CaptureDir {
+            path: directory.clone(),
+            files: Mutex::new((MAX_CAPTURE_FILES - 1, Instant::now())),
+        };
+        assert!(dir.capture(remote, local).unwrap().is_some());
+        assert!(dir.capture(remote, local).unwrap().is_none());
+        assert_eq!(captures(&directory).len(), 1);
+
+        // Once the recount finds room again, captures resume
+        *dir.files.lock().unwrap() = (MAX_CAPTURE_FILES, Instant::now() - RECOUNT_INTERVAL);
+        assert!(dir.capture(remote, local).unwrap().is_some());
+        assert_eq!(dir.files.lock().unwrap().0, 2);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
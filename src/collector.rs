@@ -0,0 +1,422 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig};
+
+use crate::pipeline::Pipeline;
+use crate::sensor::{EventBatch, EVENTS_PATH};
+
+/// Requests with a larger body are rejected
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+/// Number of event ids remembered to recognize events a sensor sent twice
+const SEEN_EVENTS: usize = 100_000;
+const WORKERS: usize = 4;
+
+#[derive(Deserialize)]
+struct TokensFile {
+    #[serde(default)]
+    sensor: Vec<SensorToken>,
+}
+
+#[derive(Deserialize)]
+struct SensorToken {
+    id: String,
+    token: String,
+}
+
+/// The sensors allowed to send events, by their token
+pub struct SensorTokens {
+    sensors: HashMap<String, String>,
+}
+
+impl SensorTokens {
+    pub fn load(path: &Path) -> Result<SensorTokens> {
+        Self::parse(&fs::read_to_string(path)?).map_err(|e| eyre!("Unable to parse {}: {}", path.display(), e))
+    }
+
+    fn parse(content: &str) -> Result<SensorTokens> {
+        let file: TokensFile = toml::from_str(content)?;
+        let mut sensors = HashMap::new();
+        for sensor in file.sensor {
+            if sensor.token.is_empty() {
+                return Err(eyre!("Sensor \"{}\" has an empty token", sensor.id));
+            }
+            if sensors.insert(sensor.token, sensor.id.clone()).is_some() {
+                return Err(eyre!("Sensor \"{}\" shares its token with another sensor", sensor.id));
+            }
+        }
+        Ok(SensorTokens { sensors })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    fn sensor(&self, token: &str) -> Option<&str> {
+        self.sensors.get(token).map(String::as_str)
+    }
+}
+
+pub struct CollectorConfig {
+    pub listen: SocketAddr,
+    pub tokens: SensorTokens,
+    /// PEM encoded certificate chain and private key, the collector speaks plain HTTP without them
+    pub tls: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Remembers the most recent event ids, evicting the oldest once full
+struct SeenEvents {
+    capacity: usize,
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenEvents {
+    fn new(capacity: usize) -> SeenEvents {
+        SeenEvents {
+            capacity,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns whether the id is new
+    fn insert(&mut self, id: String) -> bool {
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+struct Collector {
+    tokens: SensorTokens,
+    pipeline: Arc<Pipeline>,
+    seen: Mutex<SeenEvents>,
+    sensors: Mutex<HashSet<String>>,
+}
+
+/// Accepts events from sensors and hands them to the pipeline, blocking the calling thread
+pub fn run_collector(config: CollectorConfig, pipeline: Arc<Pipeline>) -> Result<()> {
+    let server = match config.tls {
+        Some((certificate, private_key)) => Server::https(
+            config.listen,
+            SslConfig {
+                certificate,
+                private_key,
+            },
+        ),
+        None => Server::http(config.listen),
+    }
+    .map_err(|e| eyre!("Unable to listen on {}: {}", config.listen, e))?;
+    log::info!("Collector listening on {}", config.listen);
+
+    let server = Arc::new(server);
+    let collector = Arc::new(Collector {
+        tokens: config.tokens,
+        pipeline,
+        seen: Mutex::new(SeenEvents::new(SEEN_EVENTS)),
+        sensors: Mutex::new(HashSet::new()),
+    });
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let collector = collector.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    collector.handle(request);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+impl Collector {
+    fn handle(&self, mut request: Request) {
+        let (status, body) = match (request.method(), request.url()) {
+            (Method::Get, "/health") => (200, json!({"status": "ok"})),
+            (Method::Post, EVENTS_PATH) => self.receive(&mut request),
+            _ => (404, json!({"error": "not found"})),
+        };
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        let address = request_address(&request);
+        if let Err(e) = request.respond(response) {
+            log::debug!("Unable to respond to {}: {}", address, e);
+        }
+    }
+
+    fn receive(&self, request: &mut Request) -> (u16, serde_json::Value) {
+        let token = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            .map(str::trim);
+        let Some(sensor) = token.and_then(|t| self.tokens.sensor(t)).map(String::from) else {
+            log::warn!("Rejected events from {} with a missing or unknown token", request_address(request));
+            return (401, json!({"error": "unknown token"}));
+        };
+
+        let mut body = Vec::new();
+        if let Err(e) = request.as_reader().take(MAX_BODY_SIZE + 1).read_to_end(&mut body) {
+            return (400, json!({"error": e.to_string()}));
+        }
+        if body.len() as u64 > MAX_BODY_SIZE {
+            return (413, json!({"error": "body too large"}));
+        }
+        let batch: EventBatch = match serde_json::from_slice(&body) {
+            Ok(batch) => batch,
+            Err(e) => return (400, json!({"error": e.to_string()})),
+        };
+        if batch.sensor != sensor {
+            log::warn!(
+                "Rejected events from sensor \"{}\" sent with the token of \"{}\"",
+                batch.sensor,
+                sensor
+            );
+            return (403, json!({"error": "token belongs to another sensor"}));
+        }
+
+        if self.sensors.lock().unwrap().insert(sensor.clone()) {
+            log::info!("Receiving events from sensor \"{}\" at {}", sensor, request_address(request));
+        }
+
+        let mut accepted = 0;
+        let mut duplicates = 0;
+        for envelope in batch.events {
+            if !self.seen.lock().unwrap().insert(format!("{}:{}", sensor, envelope.id)) {
+                duplicates += 1;
+                continue;
+            }
+            let mut event = envelope.event;
+            // The token decides which sensor an event is attributed to, not the event itself
+            event.sensor = Some(sensor.clone());
+            self.pipeline.forward(event);
+            accepted += 1;
+        }
+        (200, json!({"accepted": accepted, "duplicates": duplicates}))
+    }
+}
+
+fn request_address(request: &Request) -> String {
+    request
+        .remote_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::blocking::Client;
+
+    use crate::access::AccessLists;
+    use crate::event::Event;
+    use crate::fingerprint::FingerprintRules;
+    use crate::persona::Persona;
+    use crate::pipeline::Enrichment;
+    use crate::routing::Router;
+    use crate::sensor::Envelope;
+    use crate::sink::EventSink;
+    use crate::types::{Connection, ConnectionTrace, Request, RequestType, ServerListPingRequest};
+
+    use super::*;
+
+    fn ping() -> Event {
+        Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        })
+    }
+
+    /// Collects the events the collector forwards, observers get them on the forwarding thread
+    struct Forwarded(Arc<Mutex<Vec<Event>>>);
+
+    impl EventSink for Forwarded {
+        fn send(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    /// Runs a collector on a random port with the tokens of `eu-1` and `us-1`, returns its events url
+    fn collector(forwarded: Arc<Mutex<Vec<Event>>>) -> String {
+        let persona = Persona {
+            version_string: String::from("1.21"),
+            protocol_version: 767,
+            max_players: 20,
+            online_players: None,
+            players: vec![],
+            motd: String::from("A Minecraft Server"),
+        };
+        let enrichment = Enrichment::new(
+            Arc::new(Router::new(persona)),
+            Arc::new(AccessLists::new()),
+            FingerprintRules::default(),
+        );
+        let pipeline = Pipeline::builder(enrichment)
+            .observer(Box::new(Forwarded(forwarded)))
+            .build()
+            .unwrap();
+        let collector = Collector {
+            tokens: SensorTokens::parse(
+                "[[sensor]]\nid = \"eu-1\"\ntoken = \"secret-1\"\n[[sensor]]\nid = \"us-1\"\ntoken = \"secret-2\"",
+            )
+            .unwrap(),
+            pipeline: Arc::new(pipeline),
+            seen: Mutex::new(SeenEvents::new(SEEN_EVENTS)),
+            sensors: Mutex::new(HashSet::new()),
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{}", server.server_addr().to_ip().unwrap(), EVENTS_PATH);
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                collector.handle(request);
+            }
+        });
+        url
+    }
+
+    fn batch(sensor: &str, ids: &[&str]) -> Vec<u8> {
+        let events = ids
+            .iter()
+            .map(|id| Envelope {
+                id: id.to_string(),
+                event: ping(),
+            })
+            .collect();
+        serde_json::to_vec(&EventBatch {
+            sensor: sensor.to_string(),
+            events,
+        })
+        .unwrap()
+    }
+
+    fn post(url: &str, token: &str, body: Vec<u8>) -> (u16, serde_json::Value) {
+        let response = Client::new().post(url).bearer_auth(token).body(body).send().unwrap();
+        (response.status().as_u16(), response.json().unwrap())
+    }
+
+    #[test]
+    fn forwards_events_once_per_sensor() {
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let url = collector(forwarded.clone());
+
+        let (status, body) = post(&url, "secret-1", batch("eu-1", &["1", "2"]));
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({"accepted": 2, "duplicates": 0}));
+        // A retry of a request whose response got lost
+        let (status, body) = post(&url, "secret-1", batch("eu-1", &["2", "3"]));
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({"accepted": 1, "duplicates": 1}));
+        // Ids are only unique per sensor
+        let (_, body) = post(&url, "secret-2", batch("us-1", &["1"]));
+        assert_eq!(body, json!({"accepted": 1, "duplicates": 0}));
+
+        let sensors = forwarded
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.sensor.clone().unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(sensors, ["eu-1", "eu-1", "eu-1", "us-1"]);
+    }
+
+    #[test]
+    fn rejects_unknown_tokens_other_sensors_and_large_bodies() {
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let url = collector(forwarded.clone());
+
+        assert_eq!(post(&url, "secret-3", batch("eu-1", &["1"])).0, 401);
+        let response = Client::new().post(&url).body(batch("eu-1", &["1"])).send().unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+        // The token of eu-1 can't be used to send events as us-1
+        assert_eq!(post(&url, "secret-1", batch("us-1", &["1"])).0, 403);
+        assert_eq!(post(&url, "secret-1", vec![b' '; MAX_BODY_SIZE as usize + 1]).0, 413);
+        assert_eq!(post(&url, "secret-1", b"not json".to_vec()).0, 400);
+        assert!(forwarded.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recognizes_events_sent_twice() {
+        let mut seen = SeenEvents::new(3);
+        assert!(seen.insert(String::from("a:1")));
+        assert!(!seen.insert(String::from("a:1")));
+        assert!(seen.insert(String::from("b:1")));
+    }
+
+    #[test]
+    fn forgets_the_oldest_ids() {
+        let mut seen = SeenEvents::new(3);
+        for id in ["1", "2", "3", "4"] {
+            assert!(seen.insert(id.to_string()));
+        }
+        assert_eq!(seen.ids.len(), 3);
+        assert!(!seen.insert(String::from("4")));
+        assert!(!seen.insert(String::from("2")));
+        // Evicted, so it counts as new again
+        assert!(seen.insert(String::from("1")));
+    }
+
+    #[test]
+    fn parses_tokens() {
+        let tokens = SensorTokens::parse(
+            r#"
+            [[sensor]]
+            id = "eu-1"
+            token = "secret-1"
+
+            [[sensor]]
+            id = "us-1"
+            token = "secret-2"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens.sensor("secret-2"), Some("us-1"));
+        assert_eq!(tokens.sensor("secret-3"), None);
+        assert!(SensorTokens::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_and_shared_tokens() {
+        let empty = SensorTokens::parse("[[sensor]]\nid = \"eu-1\"\ntoken = \"\"").err().unwrap();
+        assert!(empty.to_string().contains("empty token"));
+        let shared = SensorTokens::parse(
+            "[[sensor]]\nid = \"eu-1\"\ntoken = \"secret\"\n[[sensor]]\nid = \"us-1\"\ntoken = \"secret\"",
+        )
+        .err()
+        .unwrap();
+        assert!(shared.to_string().contains("\"us-1\" shares its token"));
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// A connection the honeypot is currently handling
+#[derive(Serialize, Clone, Debug)]
+pub struct ActiveConnection {
+    pub id: u64,
+    pub remote_address: SocketAddr,
+    pub local_address: Option<SocketAddr>,
+    pub started: DateTime<Utc>,
+    /// Silent connections are answered but never reported
+    pub silent: bool,
+}
+
+/// The connections the server is handling right now, shared with whatever wants to list them
+#[derive(Default)]
+pub struct ActiveConnections {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, ActiveConnection>>,
+}
+
+impl ActiveConnections {
+    pub fn new() -> ActiveConnections {
+        ActiveConnections::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The active connections, oldest first
+    pub fn list(&self) -> Vec<ActiveConnection> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Counts a connection as active until the returned guard is dropped
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        remote_address: SocketAddr,
+        local_address: Option<SocketAddr>,
+        silent: bool,
+    ) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(
+            id,
+            ActiveConnection {
+                id,
+                remote_address,
+                local_address,
+                started: Utc::now(),
+                silent,
+            },
+        );
+        ConnectionGuard {
+            id,
+            connections: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    id: u64,
+    connections: Arc<ActiveConnections>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.connections.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,249 @@
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::prelude::*;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::event::EventKind;
+use crate::routing::{Router, DEFAULT_PERSONA};
+use crate::store::EventStore;
+
+pub const DASHBOARD_PAGE: &str = include_str!("../dashboard.html");
+/// Events returned when the request doesn't ask for a number
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// Entries in each of the top lists
+const TOP: usize = 10;
+/// Comments sent on idle streams so proxies keep them open and closed ones are noticed
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Live streams open at once, each one holds a thread
+const MAX_STREAMS: usize = 32;
+/// A stream is closed once a write blocks this long, so clients that stopped reading don't keep their slot
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct DashboardConfig {
+    pub listen: SocketAddr,
+    /// Required as the password of HTTP basic auth if set, any username is accepted
+    pub password: Option<String>,
+}
+
+/// Serves a page with live events, statistics and the personas in use, reading from the event store
+pub struct Dashboard {
+    store: Arc<EventStore>,
+    router: Arc<Router>,
+    password: Option<String>,
+    streams: AtomicUsize,
+}
+
+impl Dashboard {
+    /// Listens in the background, a thread is spawned for every live stream up to `MAX_STREAMS`
+    pub fn start(config: DashboardConfig, store: Arc<EventStore>, router: Arc<Router>) -> Result<()> {
+        let server = bind(config.listen)
+            .map_err(|e| e.into())
+            .and_then(|listener| Server::from_listener(listener, None))
+            .map_err(|e| eyre!("Unable to listen on {}: {}", config.listen, e))?;
+        log::info!("Dashboard listening on http://{}", config.listen);
+        if !config.listen.ip().is_loopback() && config.password.is_none() {
+            log::warn!(
+                "The dashboard listens on {} without a password, which is reachable from other hosts",
+                config.listen
+            );
+        }
+        let dashboard = Arc::new(Dashboard {
+            store,
+            router,
+            password: config.password,
+            streams: AtomicUsize::new(0),
+        });
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                dashboard.handle(request);
+            }
+        });
+        Ok(())
+    }
+
+    fn handle(self: &Arc<Self>, request: Request) {
+        if !self.authorized(&request) {
+            let response = Response::from_string("Unauthorized")
+                .with_status_code(401)
+                .with_header(Header::from_bytes("WWW-Authenticate", "Basic realm=\"mc-honeypot\"").unwrap());
+            let _ = request.respond(response);
+            return;
+        }
+        if request.method() != &Method::Get {
+            let _ = request.respond(Response::from_string("Method not allowed").with_status_code(405));
+            return;
+        }
+
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let limit = query_param(query, "limit")
+            .and_then(|l| l.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_LIMIT)
+            .min(MAX_LIMIT);
+
+        let body = match path {
+            "/" => {
+                let response = Response::from_string(DASHBOARD_PAGE)
+                    .with_header(Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap());
+                let _ = request.respond(response);
+                return;
+            }
+            "/api/stream" => {
+                if self.streams.fetch_add(1, Ordering::Relaxed) >= MAX_STREAMS {
+                    self.streams.fetch_sub(1, Ordering::Relaxed);
+                    let _ = request.respond(Response::from_string("Too many streams").with_status_code(503));
+                    return;
+                }
+                let dashboard = self.clone();
+                std::thread::spawn(move || {
+                    dashboard.stream(request);
+                    dashboard.streams.fetch_sub(1, Ordering::Relaxed);
+                });
+                return;
+            }
+            "/api/events" => Some(json!(self.store.recent(limit))),
+            "/api/stats" => Some(json!(self.store.stats(TOP))),
+            "/api/personas" => Some(self.personas()),
+            _ => match path.strip_prefix("/api/sources/").map(|ip| ip.parse::<IpAddr>()) {
+                Some(Ok(ip)) => Some(json!(self.store.by_source(ip, limit))),
+                _ => None,
+            },
+        };
+        let response = match body {
+            Some(body) => Response::from_string(body.to_string())
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+            None => Response::from_string("Not found").with_status_code(404),
+        };
+        let _ = request.respond(response);
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(password) = &self.password else {
+            return true;
+        };
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Basic "))
+            .and_then(|credentials| BASE64_STANDARD.decode(credentials.trim()).ok())
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .and_then(|credentials| credentials.split_once(':').map(|(_, p)| p.to_string()))
+            // Compared in constant time so response times don't reveal how much of a guess was right
+            .is_some_and(|p| bool::from(p.as_bytes().ct_eq(password.as_bytes())))
+    }
+
+    /// Every persona, with the one that answered the most recent connection marked as active
+    fn personas(&self) -> Value {
+        let active = self.store.find_map(|e| match &e.kind {
+            EventKind::Connection(c) if !c.persona.is_empty() => Some(c.persona.clone()),
+            _ => None,
+        });
+        let personas = self
+            .router
+            .personas()
+            .into_iter()
+            .map(|(name, persona)| {
+                json!({
+                    "default": name == DEFAULT_PERSONA,
+                    "active": active.as_deref() == Some(name.as_str()),
+                    "name": name,
+                    "version": persona.version_string,
+                    "protocol_version": persona.protocol_version,
+                    "motd": persona.motd,
+                    "max_players": persona.max_players,
+                    "online_players": persona.online_players.unwrap_or(persona.players.len() as i32),
+                })
+            })
+            .collect::<Vec<Value>>();
+        json!({ "active": active, "personas": personas })
+    }
+
+    /// Sends new events as server-sent events until the client goes away
+    fn stream(&self, request: Request) {
+        let events = self.store.subscribe();
+        let mut writer = request.into_writer();
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+        loop {
+            let message = match events.recv_timeout(KEEP_ALIVE) {
+                Ok(event) => match serde_json::to_string(&event) {
+                    Ok(json) => format!("event: {}\ndata: {}\n\n", event.name(), json),
+                    Err(e) => {
+                        log::error!("Unable to serialize event: {}", e);
+                        continue;
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => String::from(": keep-alive\n\n"),
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if writer.write_all(message.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// tiny_http doesn't expose the sockets it accepts, but they inherit the write timeout of the listener
+fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(address)?;
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsRawFd;
+
+        let timeout = libc::timeval {
+            tv_sec: WRITE_TIMEOUT.as_secs() as libc::time_t,
+            tv_usec: 0,
+        };
+        // SAFETY: the option value is a timeval that outlives the call, as SO_SNDTIMEO expects
+        let result = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(listener)
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn accepted_sockets_time_out_writes() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert_eq!(accepted.write_timeout().unwrap(), Some(WRITE_TIMEOUT));
+    }
+}
@@ -0,0 +1,504 @@
+use std::fs;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// The backoff doubles after every failed attempt, up to this
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// How long we wait before retrying spooled requests after one of them failed
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counts what happened to requests across all sinks
+#[derive(Default, Debug)]
+pub struct DeliveryStats {
+    pub delivered: AtomicU64,
+    pub retried: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub spooled: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+impl DeliveryStats {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} delivered, {} retried, {} rate limited, {} spooled, {} dropped",
+            self.delivered.load(Ordering::Relaxed),
+            self.retried.load(Ordering::Relaxed),
+            self.rate_limited.load(Ordering::Relaxed),
+            self.spooled.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeliveryConfig {
+    /// Attempts after the first one before a request is spooled or dropped
+    pub retries: u32,
+    /// Requests that could not be delivered are stored here and retried later, they are dropped if not set
+    pub spool_dir: Option<PathBuf>,
+    /// The maximum number of spooled requests per sink, the oldest ones are dropped beyond it
+    pub spool_limit: usize,
+    pub stats: Arc<DeliveryStats>,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            retries: 5,
+            spool_dir: None,
+            spool_limit: 1000,
+            stats: Arc::new(DeliveryStats::default()),
+        }
+    }
+}
+
+/// A request as it is stored in the spool
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct OutgoingRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+enum Outcome {
+    Delivered,
+    /// Worth trying again, after the given time if the server told us
+    Retry(Option<Duration>),
+    Failed,
+}
+
+/// Delivers requests for a single sink with a persistent client, retries and a spool.
+/// It blocks while backing off, so it belongs on the sink's worker thread.
+pub(crate) struct Delivery {
+    name: String,
+    client: Client,
+    config: DeliveryConfig,
+    spool: Option<PathBuf>,
+    /// Set from the rate limit headers of the last response
+    blocked_until: Option<Instant>,
+    next_spool_retry: Instant,
+    /// Set once a request ran out of retries, until then new requests are spooled without trying them
+    failing_until: Option<Instant>,
+    /// Looks for problems in the body of successful responses
+    check: Option<fn(&str) -> Option<String>>,
+}
+
+impl Delivery {
+    /// `name` identifies the sink in logs and names its spool directory, so it should be stable across restarts
+    pub fn new(name: String, config: DeliveryConfig) -> Delivery {
+        let spool = config.spool_dir.as_ref().map(|dir| dir.join(&name));
+        if let Some(spool) = &spool {
+            if let Err(e) = create_private_dir(spool) {
+                log::error!("Unable to create spool directory {}: {}", spool.display(), e);
+            }
+        }
+        Delivery {
+            name,
+            client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            config,
+            spool,
+            blocked_until: None,
+            next_spool_retry: Instant::now(),
+            failing_until: None,
+            check: None,
+        }
+    }
+
+    /// Logs the problem `check` finds in a successful response, e.g. items a bulk api rejected.
+    /// These requests are not retried.
+    pub fn with_check(mut self, check: fn(&str) -> Option<String>) -> Delivery {
+        self.check = Some(check);
+        self
+    }
+
+    /// Sends the request, retrying with exponential backoff. Undeliverable requests are spooled if possible.
+    /// Once a request ran out of retries, new ones are spooled right away until the sink works again,
+    /// so an outage doesn't hold up the sink's queue.
+    pub fn deliver(&mut self, request: OutgoingRequest) {
+        self.retry_spooled();
+        if self.failing_until.is_some_and(|until| Instant::now() < until) {
+            self.spool(&request);
+            return;
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                self.config.stats.retried.fetch_add(1, Ordering::Relaxed);
+            }
+            let outcome = self.attempt(&request);
+            if !matches!(outcome, Outcome::Retry(_)) {
+                self.recovered();
+            }
+            match outcome {
+                Outcome::Delivered => return,
+                Outcome::Failed => {
+                    self.config.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                // There is no point in waiting after the last attempt
+                Outcome::Retry(_) if attempt == self.config.retries => {}
+                Outcome::Retry(Some(wait)) => std::thread::sleep(wait),
+                Outcome::Retry(None) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        if self.failing_until.is_none() {
+            log::warn!(
+                "{} sink is failing, {} new requests without retrying them for {}s",
+                self.name,
+                if self.spool.is_some() { "spooling" } else { "dropping" },
+                SPOOL_RETRY_INTERVAL.as_secs()
+            );
+        }
+        self.failing_until = Some(Instant::now() + SPOOL_RETRY_INTERVAL);
+        self.spool(&request);
+    }
+
+    fn recovered(&mut self) {
+        if self.failing_until.take().is_some() {
+            log::info!("{} sink works again", self.name);
+        }
+    }
+
+    fn attempt(&mut self, request: &OutgoingRequest) -> Outcome {
+        if let Some(until) = self.blocked_until.take() {
+            std::thread::sleep(until.saturating_duration_since(Instant::now()));
+        }
+
+        let method = Method::from_bytes(request.method.as_bytes()).unwrap_or(Method::POST);
+        let mut builder = self
+            .client
+            .request(method, &request.url)
+            .body(request.body.clone());
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+
+        let response = match builder.send() {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!("Unable to reach {} sink: {}", self.name, e);
+                return Outcome::Retry(None);
+            }
+        };
+        self.blocked_until = rate_limit_reset(response.headers()).map(|d| Instant::now() + d);
+
+        let status = response.status();
+        if status.is_success() {
+            self.config.stats.delivered.fetch_add(1, Ordering::Relaxed);
+            if let Some(check) = self.check {
+                if let Some(problem) = check(&response.text().unwrap_or_default()) {
+                    log::warn!("{} sink accepted a request partially: {}", self.name, problem);
+                }
+            }
+            Outcome::Delivered
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            self.config.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+            let wait = retry_after(response);
+            log::warn!(
+                "{} sink is rate limited, retrying in {:.1}s",
+                self.name,
+                wait.unwrap_or(INITIAL_BACKOFF).as_secs_f32()
+            );
+            Outcome::Retry(Some(wait.unwrap_or(INITIAL_BACKOFF)))
+        } else if status.is_server_error() {
+            log::warn!("{} sink responded with {}", self.name, status);
+            Outcome::Retry(None)
+        } else {
+            log::error!(
+                "{} sink rejected a request with {}: {}",
+                self.name,
+                status,
+                response.text().unwrap_or_default()
+            );
+            Outcome::Failed
+        }
+    }
+
+    fn spool(&mut self, request: &OutgoingRequest) {
+        let Some(spool) = &self.spool else {
+            log::error!("Dropping an undeliverable request for {} sink", self.name);
+            self.config.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        let mut files = spooled_files(spool);
+        while files.len() >= self.config.spool_limit.max(1) {
+            let oldest = files.remove(0);
+            if fs::remove_file(&oldest).is_ok() {
+                log::warn!("Spool of {} sink is full, dropping its oldest request", self.name);
+                self.config.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path = spool.join(format!("{:024}.json", nanos));
+        let result = serde_json::to_vec(request)
+            .map_err(|e| e.into())
+            .and_then(|json| write_private(&path, &json));
+        match result {
+            Ok(()) => {
+                log::warn!("Spooled a request for {} sink to {}", self.name, path.display());
+                self.config.stats.spooled.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                log::error!("Unable to spool a request for {} sink: {}", self.name, e);
+                self.config.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.next_spool_retry = Instant::now() + SPOOL_RETRY_INTERVAL;
+    }
+
+    /// Sends spooled requests oldest first, stopping at the first one that fails.
+    /// Should be called regularly, even if there is nothing new to deliver.
+    pub fn retry_spooled(&mut self) {
+        let Some(spool) = self.spool.clone() else {
+            return;
+        };
+        if Instant::now() < self.next_spool_retry {
+            return;
+        }
+
+        for path in spooled_files(&spool) {
+            let request = match fs::read(&path)
+                .ok()
+                .and_then(|json| serde_json::from_slice::<OutgoingRequest>(&json).ok())
+            {
+                Some(request) => request,
+                None => {
+                    log::error!("Dropping unreadable spooled request {}", path.display());
+                    let _ = fs::remove_file(&path);
+                    self.config.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            match self.attempt(&request) {
+                Outcome::Delivered => {
+                    let _ = fs::remove_file(&path);
+                    self.recovered();
+                }
+                Outcome::Failed => {
+                    let _ = fs::remove_file(&path);
+                    self.config.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    self.recovered();
+                }
+                Outcome::Retry(_) => {
+                    self.next_spool_retry = Instant::now() + SPOOL_RETRY_INTERVAL;
+                    if self.failing_until.is_some() {
+                        self.failing_until = Some(self.next_spool_retry);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Spooled requests carry credentials such as authorization headers and webhook urls, so only the owner may read them
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path)?;
+    // Directories left by earlier versions were created with the default permissions
+    #[cfg(unix)]
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)?.write_all(content)
+}
+
+/// Spooled requests, oldest first
+fn spooled_files(spool: &Path) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(spool)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|e| e == "json"))
+                .collect::<Vec<PathBuf>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Discord announces an exhausted bucket with `X-RateLimit-Remaining: 0` and when it resets
+fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let remaining = headers.get("x-ratelimit-remaining")?.to_str().ok()?;
+    if remaining.trim() != "0" {
+        return None;
+    }
+    let reset_after = headers.get("x-ratelimit-reset-after")?.to_str().ok()?;
+    reset_after
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s >= 0.0)
+        .map(|s| Duration::from_secs_f64(s.min(MAX_BACKOFF.as_secs_f64())))
+}
+
+/// Reads how long to wait from the `retry_after` field Discord sends, or the standard `Retry-After` header
+fn retry_after(response: Response) -> Option<Duration> {
+    let header = response
+        .headers()
+        .get("retry-after")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().parse::<f64>().ok());
+    let body = response
+        .json::<serde_json::Value>()
+        .ok()
+        .and_then(|json| json.get("retry_after").and_then(|r| r.as_f64()));
+    body.or(header)
+        .filter(|s| s.is_finite() && *s >= 0.0)
+        .map(|s| Duration::from_secs_f64(s.min(MAX_BACKOFF.as_secs_f64())))
+}
+
+/// A stable name for a sink, derived from its kind and url
+pub(crate) fn sink_name(kind: &str, url: &str) -> String {
+    let digest = format!("{:x}", md5::compute(url.as_bytes()));
+    format!("{}-{}", kind, &digest[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tiny_http::{Header, Response, Server};
+
+    use super::*;
+
+    /// Answers requests with the given statuses and headers in order, then stops listening
+    fn stub(responses: Vec<(u16, Option<(&'static str, &'static str)>)>) -> (String, Arc<AtomicUsize>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        std::thread::spawn(move || {
+            for (status, header) in responses {
+                let request = server.recv().unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut response = Response::from_string("").with_status_code(status);
+                if let Some((name, value)) = header {
+                    response = response.with_header(Header::from_bytes(name, value).unwrap());
+                }
+                request.respond(response).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn request(url: &str) -> OutgoingRequest {
+        OutgoingRequest {
+            method: String::from("POST"),
+            url: url.to_string(),
+            headers: vec![],
+            body: String::from("{}"),
+        }
+    }
+
+    fn delivery(retries: u32, spool_dir: Option<PathBuf>) -> Delivery {
+        Delivery::new(
+            String::from("test"),
+            DeliveryConfig {
+                retries,
+                spool_dir,
+                ..DeliveryConfig::default()
+            },
+        )
+    }
+
+    #[test]
+    fn delivers_on_success() {
+        let (url, received) = stub(vec![(204, None)]);
+        let mut delivery = delivery(2, None);
+        delivery.deliver(request(&url));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert_eq!(delivery.config.stats.delivered.load(Ordering::Relaxed), 1);
+        assert_eq!(delivery.config.stats.retried.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn retries_server_errors_then_spools() {
+        let spool_dir = std::env::temp_dir().join(format!(
+            "mc-honeypot-spool-{}-{}",
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        let (url, received) = stub(vec![(500, None), (503, None)]);
+        let mut delivery = delivery(1, Some(spool_dir.clone()));
+        let started = Instant::now();
+        delivery.deliver(request(&url));
+        // A single backoff between the two attempts, none after the last one
+        assert!(started.elapsed() < INITIAL_BACKOFF * 2);
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!(delivery.config.stats.retried.load(Ordering::Relaxed), 1);
+        assert_eq!(delivery.config.stats.spooled.load(Ordering::Relaxed), 1);
+
+        // The sink is failing now, so the next request is spooled without trying it
+        delivery.deliver(request(&url));
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!(delivery.config.stats.spooled.load(Ordering::Relaxed), 2);
+        let files = spooled_files(&spool_dir.join("test"));
+        assert_eq!(files.len(), 2);
+        #[cfg(unix)]
+        {
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&spool_dir.join("test")), 0o700);
+            assert_eq!(mode(&files[0]), 0o600);
+        }
+        fs::remove_dir_all(spool_dir).unwrap();
+    }
+
+    #[test]
+    fn waits_for_retry_after() {
+        let (url, received) = stub(vec![(429, Some(("Retry-After", "0.2"))), (200, None)]);
+        let mut delivery = delivery(2, None);
+        let started = Instant::now();
+        delivery.deliver(request(&url));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < INITIAL_BACKOFF);
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!(delivery.config.stats.rate_limited.load(Ordering::Relaxed), 1);
+        assert_eq!(delivery.config.stats.delivered.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drops_client_errors() {
+        let (url, received) = stub(vec![(400, None)]);
+        let mut delivery = delivery(2, None);
+        delivery.deliver(request(&url));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert_eq!(delivery.config.stats.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(delivery.config.stats.retried.load(Ordering::Relaxed), 0);
+        assert!(delivery.failing_until.is_none());
+    }
+}
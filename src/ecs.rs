@@ -0,0 +1,234 @@
+use serde_json::{json, Map, Value};
+
+use crate::event::{Event, EventKind};
+use crate::report::Report;
+use crate::rules::Severity;
+use crate::types::RequestType;
+
+const ECS_VERSION: &str = "8.11.0";
+const DATASET_PREFIX: &str = "mc_honeypot";
+
+/// Maps an event to the Elastic Common Schema. Fields without an ECS equivalent go below `minecraft`.
+pub fn event_to_ecs(event: &Event) -> Value {
+    let (action, dataset) = match &event.kind {
+        EventKind::Connection(c) => (
+            match c.request {
+                RequestType::Join(_) => "join",
+                RequestType::ModernPing(_) => "ping",
+                RequestType::LegacyPing(_) => "legacy_ping",
+            },
+            "connection",
+        ),
+        EventKind::Visit(_) => ("visit", "visit"),
+    };
+
+    let mut document = json!({
+        "@timestamp": event.timestamp,
+        "ecs": { "version": ECS_VERSION },
+        "event": {
+            "kind": if event.alert.is_some() { "alert" } else { "event" },
+            "category": ["network"],
+            "type": ["connection"],
+            "action": action,
+            "module": DATASET_PREFIX,
+            "dataset": format!("{}.{}", DATASET_PREFIX, dataset),
+        },
+        "observer": { "type": "honeypot", "product": "mc-honeypot" },
+        "network": { "protocol": "minecraft", "transport": "tcp" },
+        "source": source(event),
+    });
+    if let Some(sensor) = &event.sensor {
+        document["observer"]["name"] = json!(sensor);
+    }
+    if !event.tags.is_empty() {
+        document["tags"] = json!(event.tags);
+    }
+    if let Some(alert) = &event.alert {
+        document["rule"] = json!({ "name": alert.rule });
+        document["event"]["severity"] = json!(severity_number(alert.severity));
+    }
+
+    match &event.kind {
+        EventKind::Connection(c) => {
+            let handshake = match &c.request {
+                RequestType::Join(req) => &req.handshake,
+                RequestType::ModernPing(req) | RequestType::LegacyPing(req) => req,
+            };
+            document["source"]["port"] = json!(c.remote_address.port());
+            document["destination"] = json!({
+                "domain": handshake.server_address,
+                "port": handshake.server_port,
+            });
+            document["event"]["duration"] = json!(c.trace.duration_ms * 1_000_000);
+            let mut minecraft = json!({
+                "protocol_version": handshake.protocol_version,
+                "persona": c.persona,
+                "response": c.action,
+            });
+            if let RequestType::Join(req) = &c.request {
+                document["user"] = json!({ "name": req.player.name, "id": req.player.id });
+                minecraft["player"] = json!(req.analysis);
+            }
+            if let Some(fingerprint) = &c.fingerprint {
+                minecraft["fingerprint"] = json!(fingerprint);
+            }
+            if let Some(reputation) = &c.reputation {
+                minecraft["reputation"] = json!(reputation);
+            }
+            document["minecraft"] = minecraft;
+        }
+        EventKind::Visit(visit) => {
+            document["event"]["start"] = json!(visit.first_seen);
+            document["event"]["end"] = json!(visit.last_seen);
+            document["event"]["duration"] = json!(visit.duration().num_milliseconds() * 1_000_000);
+            if let [username] = visit.usernames.as_slice() {
+                document["user"] = json!({ "name": username });
+            }
+            document["minecraft"] = json!({
+                "visit": {
+                    "connections": visit.connections,
+                    "pings": visit.pings,
+                    "legacy_pings": visit.legacy_pings,
+                    "joins": visit.joins,
+                    "usernames": visit.usernames,
+                    "protocol_versions": visit.protocol_versions,
+                    "server_addresses": visit.server_addresses,
+                    "fingerprints": visit.fingerprints,
+                }
+            });
+        }
+    }
+    document
+}
+
+/// Maps a report to an ECS metric document
+pub fn report_to_ecs(report: &Report) -> Value {
+    json!({
+        "@timestamp": report.end,
+        "ecs": { "version": ECS_VERSION },
+        "event": {
+            "kind": "metric",
+            "module": DATASET_PREFIX,
+            "dataset": format!("{}.report", DATASET_PREFIX),
+            "start": report.start,
+            "end": report.end,
+        },
+        "observer": { "type": "honeypot", "product": "mc-honeypot" },
+        "minecraft": { "report": report },
+    })
+}
+
+fn source(event: &Event) -> Value {
+    let mut source = Map::new();
+    source.insert(String::from("ip"), json!(event.source));
+    if let Some(ptr) = &event.ptr {
+        source.insert(String::from("domain"), json!(ptr));
+    }
+    if let Some(geo) = &event.geo {
+        let mut location = Map::new();
+        if let Some(country) = &geo.country {
+            location.insert(String::from("country_iso_code"), json!(country));
+        }
+        if let Some(name) = &geo.country_name {
+            location.insert(String::from("country_name"), json!(name));
+        }
+        if let Some(city) = &geo.city {
+            location.insert(String::from("city_name"), json!(city));
+        }
+        if !location.is_empty() {
+            source.insert(String::from("geo"), Value::Object(location));
+        }
+        let mut autonomous_system = Map::new();
+        if let Some(asn) = geo.asn {
+            autonomous_system.insert(String::from("number"), json!(asn));
+        }
+        if let Some(organization) = &geo.organization {
+            autonomous_system.insert(String::from("organization"), json!({ "name": organization }));
+        }
+        if !autonomous_system.is_empty() {
+            source.insert(String::from("as"), Value::Object(autonomous_system));
+        }
+    }
+    Value::Object(source)
+}
+
+/// ECS leaves the scale to the source, we use the syslog numbers where lower is more severe
+fn severity_number(severity: Severity) -> u8 {
+    match severity {
+        Severity::Critical => 2,
+        Severity::Warning => 4,
+        Severity::Notice => 5,
+        Severity::Info => 6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::geoip::GeoInfo;
+    use crate::rules::Alert;
+    use crate::types::{Connection, ConnectionTrace, Request, ServerListPingRequest};
+
+    use super::*;
+
+    fn ping() -> Event {
+        Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        })
+    }
+
+    #[test]
+    fn maps_connections() {
+        let mut event = ping();
+        event.tags = vec![String::from("cloud VPS")];
+        event.sensor = Some(String::from("eu-1"));
+        event.ptr = Some(String::from("scanner.example.net"));
+        event.geo = Some(GeoInfo {
+            country: Some(String::from("DE")),
+            country_name: None,
+            city: None,
+            asn: Some(3320),
+            organization: None,
+        });
+        let document = event_to_ecs(&event);
+        assert_eq!(document["event"]["kind"], "event");
+        assert_eq!(document["event"]["action"], "ping");
+        assert_eq!(document["event"]["dataset"], "mc_honeypot.connection");
+        assert_eq!(document["observer"]["name"], "eu-1");
+        assert_eq!(document["tags"], json!(["cloud VPS"]));
+        assert_eq!(
+            document["source"],
+            json!({
+                "ip": "192.0.2.1",
+                "port": 50000,
+                "domain": "scanner.example.net",
+                "geo": { "country_iso_code": "DE" },
+                "as": { "number": 3320 },
+            })
+        );
+        assert_eq!(document["destination"], json!({ "domain": "play.example.com", "port": 25565 }));
+        assert_eq!(document["minecraft"]["protocol_version"], 767);
+        assert!(document.get("rule").is_none());
+    }
+
+    #[test]
+    fn marks_routed_events_as_alerts() {
+        let mut event = ping();
+        event.alert = Some(Alert {
+            rule: String::from("joins"),
+            severity: Severity::Warning,
+        });
+        let document = event_to_ecs(&event);
+        assert_eq!(document["event"]["kind"], "alert");
+        assert_eq!(document["event"]["severity"], 4);
+        assert_eq!(document["rule"]["name"], "joins");
+    }
+}
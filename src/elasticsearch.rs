@@ -0,0 +1,278 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::delivery::{sink_name, Delivery, DeliveryConfig, OutgoingRequest};
+use crate::ecs::{event_to_ecs, report_to_ecs};
+use crate::event::Event;
+use crate::report::Report;
+use crate::sink::{BufferedSender, EventSink};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ElasticsearchSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to elasticsearch-1, elasticsearch-2, ...
+    pub name: Option<String>,
+    /// Base url of the cluster, e.g. `http://localhost:9200`
+    pub url: String,
+    /// Formatted with the timestamp of each document, see `chrono::format::strftime`
+    #[serde(default = "default_index")]
+    pub index: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub api_key: Option<String>,
+    /// Seconds between bulk requests, documents are buffered in between
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    /// Documents per bulk request, a full batch is sent right away
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+}
+
+fn default_index() -> String {
+    String::from("mc-honeypot-%Y.%m.%d")
+}
+
+fn default_flush_interval() -> u64 {
+    5
+}
+
+fn default_max_batch() -> usize {
+    500
+}
+
+/// Indexes events as Elastic Common Schema documents through the `_bulk` api of Elasticsearch or OpenSearch
+pub struct ElasticsearchSink {
+    index: String,
+    buffer: BufferedSender<String>,
+}
+
+impl ElasticsearchSink {
+    pub fn new(config: ElasticsearchSinkConfig, delivery: DeliveryConfig) -> Result<ElasticsearchSink> {
+        if StrftimeItems::new(&config.index).any(|item| item == Item::Error) {
+            return Err(eyre!("Invalid index pattern \"{}\"", config.index));
+        }
+        let mut headers = vec![(String::from("Content-Type"), String::from("application/x-ndjson"))];
+        match (&config.api_key, &config.username) {
+            (Some(_), Some(_)) => return Err(eyre!("Only one of api_key and username can be set")),
+            (Some(key), None) => headers.push((String::from("Authorization"), format!("ApiKey {}", key))),
+            (None, Some(username)) => {
+                let credentials = format!("{}:{}", username, config.password.as_deref().unwrap_or_default());
+                headers.push((
+                    String::from("Authorization"),
+                    format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+                ));
+            }
+            (None, None) => {}
+        }
+
+        let url = format!("{}/_bulk", config.url.trim_end_matches('/'));
+        let mut delivery = Delivery::new(sink_name("elasticsearch", &url), delivery).with_check(bulk_errors);
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(config.flush_interval.max(1) as i64),
+            config.max_batch.max(1),
+            move |documents: Vec<String>| {
+                if documents.is_empty() {
+                    delivery.retry_spooled();
+                    return;
+                }
+                delivery.deliver(OutgoingRequest {
+                    method: String::from("POST"),
+                    url: url.clone(),
+                    headers: headers.clone(),
+                    body: documents.concat(),
+                });
+            },
+        );
+
+        Ok(ElasticsearchSink {
+            index: config.index,
+            buffer,
+        })
+    }
+
+    /// Adds the action and document lines of a bulk request
+    fn index(&self, timestamp: DateTime<Utc>, document: Value) {
+        let action = json!({ "create": { "_index": timestamp.format(&self.index).to_string() } });
+        self.buffer.add(format!("{}\n{}\n", action, document));
+    }
+}
+
+impl EventSink for ElasticsearchSink {
+    fn send(&self, event: &Event) {
+        self.index(event.timestamp, event_to_ecs(event));
+    }
+
+    fn report(&self, report: &Report) {
+        self.index(report.end, report_to_ecs(report));
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+/// Bulk requests succeed even if single documents are rejected, which is only visible in the response
+fn bulk_errors(body: &str) -> Option<String> {
+    let response: Value = serde_json::from_str(body).ok()?;
+    if response.get("errors").and_then(|e| e.as_bool()) != Some(true) {
+        return None;
+    }
+    let errors = response
+        .get("items")
+        .and_then(|i| i.as_array())
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|item| item.as_object()?.values().next()?.get("error"))
+                .collect::<Vec<&Value>>()
+        })
+        .unwrap_or_default();
+    let reason = errors
+        .first()
+        .and_then(|e| e.get("reason"))
+        .and_then(|r| r.as_str())
+        .unwrap_or("unknown reason");
+    Some(format!("{} document(s) were rejected, e.g. {}", errors.len(), reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+
+    use crate::types::{Connection, ConnectionTrace, Request, RequestType, ServerListPingRequest};
+
+    use super::*;
+
+    /// A bulk request as the stub received it
+    struct Received {
+        authorization: Option<String>,
+        content_type: Option<String>,
+        body: String,
+    }
+
+    /// Answers every bulk request with `response`
+    fn stub(response: &'static str) -> (String, Receiver<Received>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|h| h.field.equiv(name))
+                        .map(|h| h.value.to_string())
+                };
+                let authorization = header("Authorization");
+                let content_type = header("Content-Type");
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                assert_eq!(request.url(), "/_bulk");
+                let _ = tx.send(Received {
+                    authorization,
+                    content_type,
+                    body,
+                });
+                let _ = request.respond(tiny_http::Response::from_string(response));
+            }
+        });
+        (url, rx)
+    }
+
+    fn sink(url: &str) -> ElasticsearchSink {
+        ElasticsearchSink::new(
+            ElasticsearchSinkConfig {
+                name: None,
+                url: format!("{}/", url),
+                index: String::from("honeypot-%Y.%m"),
+                username: None,
+                password: None,
+                api_key: Some(String::from("c2VjcmV0")),
+                flush_interval: 3600,
+                max_batch: 500,
+            },
+            DeliveryConfig::default(),
+        )
+        .unwrap()
+    }
+
+    fn ping(month: u32) -> Event {
+        let mut event = Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        });
+        event.timestamp = Utc.with_ymd_and_hms(2024, month, 31, 23, 59, 0).unwrap();
+        event
+    }
+
+    #[test]
+    fn indexes_ndjson_into_dated_indices() {
+        let (url, received) = stub(r#"{"errors":false,"items":[]}"#);
+        let sink = sink(&url);
+        sink.send(&ping(5));
+        sink.send(&ping(7));
+        sink.flush();
+        let request = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request.authorization.as_deref(), Some("ApiKey c2VjcmV0"));
+        assert_eq!(request.content_type.as_deref(), Some("application/x-ndjson"));
+        assert!(request.body.ends_with('\n'));
+        let lines = request
+            .body
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<Value>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], json!({ "create": { "_index": "honeypot-2024.05" } }));
+        assert_eq!(lines[1]["@timestamp"], "2024-05-31T23:59:00Z");
+        assert_eq!(lines[1]["event"]["action"], "ping");
+        assert_eq!(lines[2], json!({ "create": { "_index": "honeypot-2024.07" } }));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let config = |index: &str, username: Option<&str>| ElasticsearchSinkConfig {
+            name: None,
+            url: String::from("http://localhost:9200"),
+            index: index.to_string(),
+            username: username.map(String::from),
+            password: None,
+            api_key: Some(String::from("key")),
+            flush_interval: 5,
+            max_batch: 500,
+        };
+        assert!(ElasticsearchSink::new(config("honeypot-%Q", None), DeliveryConfig::default()).is_err());
+        assert!(ElasticsearchSink::new(config("honeypot", Some("elastic")), DeliveryConfig::default()).is_err());
+    }
+
+    #[test]
+    fn finds_rejected_documents() {
+        assert_eq!(bulk_errors(r#"{"errors":false,"items":[{"create":{"status":201}}]}"#), None);
+        assert_eq!(bulk_errors("not json"), None);
+        let body = r#"{"errors":true,"items":[
+            {"create":{"status":201}},
+            {"create":{"status":400,"error":{"type":"mapper_parsing_exception","reason":"failed to parse field [source.ip]"}}},
+            {"create":{"status":429,"error":{"type":"es_rejected_execution_exception","reason":"rejected"}}}
+        ]}"#;
+        assert_eq!(
+            bulk_errors(body).as_deref(),
+            Some("2 document(s) were rejected, e.g. failed to parse field [source.ip]")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::fingerprint::Fingerprint;
+use crate::geoip::GeoInfo;
+use crate::reputation::Reputation;
+use crate::rules::Alert;
+use crate::session::Visit;
+use crate::types::{Connection, ConnectionTrace, RequestType};
+
+/// Something that happened on the honeypot together with everything we know about the source, as handed to logs and webhooks
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    pub source: IpAddr,
+    #[serde(flatten)]
+    pub kind: EventKind,
+    pub tags: Vec<String>,
+    pub geo: Option<GeoInfo>,
+    /// The reverse DNS record of the source
+    pub ptr: Option<String>,
+    /// The alert rule that routed the event to a sink, if rules are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert: Option<Alert>,
+    /// The id of the sensor that saw the event, if it runs in sensor mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A single connection
+    Connection(ConnectionEvent),
+    /// A summary of all connections from one source, emitted once it went idle
+    Visit(Visit),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConnectionEvent {
+    pub remote_address: SocketAddr,
+    /// The address of the listener that accepted the connection
+    pub local_address: SocketAddr,
+    pub request: RequestType,
+    /// The persona that answered the request
+    pub persona: String,
+    /// What the honeypot did: status, kick, drop or tarpit
+    pub action: String,
+    pub trace: ConnectionTrace,
+    pub fingerprint: Option<Fingerprint>,
+    /// Only looked up for join attempts
+    pub reputation: Option<Reputation>,
+}
+
+impl Event {
+    pub fn connection(connection: Connection) -> Event {
+        Event {
+            timestamp: Utc::now(),
+            source: connection.request.remote_address.ip(),
+            kind: EventKind::Connection(ConnectionEvent {
+                remote_address: connection.request.remote_address,
+                local_address: connection.request.local_address,
+                request: connection.request.request_type,
+                persona: String::new(),
+                action: String::new(),
+                trace: connection.trace,
+                fingerprint: None,
+                reputation: None,
+            }),
+            tags: vec![],
+            geo: None,
+            ptr: None,
+            alert: None,
+            sensor: None,
+        }
+    }
+
+    pub fn visit(visit: Visit) -> Event {
+        Event {
+            timestamp: Utc::now(),
+            source: visit.source,
+            kind: EventKind::Visit(visit),
+            tags: vec![],
+            geo: None,
+            ptr: None,
+            alert: None,
+            sensor: None,
+        }
+    }
+
+    /// Identifies the kind of event: join, ping, legacy_ping or visit
+    pub fn name(&self) -> &'static str {
+        match &self.kind {
+            EventKind::Connection(c) => match c.request {
+                RequestType::Join(_) => "join",
+                RequestType::ModernPing(_) => "ping",
+                RequestType::LegacyPing(_) => "legacy_ping",
+            },
+            EventKind::Visit(_) => "visit",
+        }
+    }
+
+    /// A readable name of the kind of event for titles and messages, e.g. `Join attempt`
+    pub fn title(&self) -> &'static str {
+        match self.name() {
+            "join" => "Join attempt",
+            "ping" => "Ping",
+            "legacy_ping" => "Legacy ping",
+            _ => "Visit",
+        }
+    }
+
+    /// Formats the fingerprint for log lines, e.g. ` (mcstatus (Python) #0123456789abcdef)`
+    pub fn fingerprint_suffix(&self) -> String {
+        match &self.kind {
+            EventKind::Connection(ConnectionEvent {
+                fingerprint: Some(fingerprint),
+                ..
+            }) => format!(" ({} #{})", fingerprint.label, fingerprint.hash),
+            _ => String::new(),
+        }
+    }
+
+    /// Formats the location and PTR record for log lines, e.g. ` {DE, Berlin, AS3320 Deutsche Telekom AG, p5b0a1c2d.dip0.t-ipconnect.de}`
+    pub fn geo_suffix(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(geo) = &self.geo {
+            parts.push(geo.summary());
+        }
+        if let Some(ptr) = &self.ptr {
+            parts.push(ptr.clone());
+        }
+        if parts.is_empty() {
+            String::new()
+        } else {
+            format!(" {{{}}}", parts.join(", "))
+        }
+    }
+
+    /// Formats the tags for log lines, e.g. ` [targeted, hypixel]`
+    pub fn tag_suffix(&self) -> String {
+        if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", self.tags.join(", "))
+        }
+    }
+
+    /// Names the sensor that saw the event for log lines, e.g. ` via eu-1`
+    pub fn sensor_suffix(&self) -> String {
+        match &self.sensor {
+            Some(sensor) => format!(" via {}", sensor),
+            None => String::new(),
+        }
+    }
+}
@@ -1,21 +1,279 @@
-use std::fs;
-use std::fs::File;
-use std::io::Read;
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, io};
 
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
+use image::imageops::FilterType;
+use image::{ImageError, ImageFormat};
 
-pub fn read_favicon_from_file(file_name: &String) -> std::io::Result<String> {
-    let bytes = read_bytes_from_file(file_name)?;
-    Ok("data:image/png;base64,".to_owned() + &BASE64_STANDARD.encode(bytes))
+pub const FAVICON_SIZE: u32 = 64;
+
+const FAVICON_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "gif", "webp"];
+
+#[derive(Debug)]
+pub enum FaviconError {
+    Io(PathBuf, io::Error),
+    Decode(PathBuf, ImageError),
+    Encode(PathBuf, ImageError),
+    UnsupportedFormat(PathBuf),
+    /// A supported format other than png, which is only accepted with --convert-icon
+    NotPng(PathBuf, ImageFormat),
+    WrongSize(PathBuf, u32, u32),
+    EmptyDirectory(PathBuf),
+}
+
+impl Display for FaviconError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaviconError::Io(path, e) => write!(f, "Unable to read {}: {}", path.display(), e),
+            FaviconError::Decode(path, e) => {
+                write!(f, "Unable to decode {}: {}", path.display(), e)
+            }
+            FaviconError::Encode(path, e) => {
+                write!(f, "Unable to convert {} to png: {}", path.display(), e)
+            }
+            FaviconError::UnsupportedFormat(path) => write!(
+                f,
+                "{} is not a png, jpeg, gif or webp image",
+                path.display()
+            ),
+            FaviconError::NotPng(path, format) => write!(
+                f,
+                "{} is a {} image but needs to be a png (use --convert-icon to convert it)",
+                path.display(),
+                format!("{:?}", format).to_lowercase()
+            ),
+            FaviconError::WrongSize(path, width, height) => write!(
+                f,
+                "{} is {}x{} pixels but needs to be {}x{} (use --convert-icon to resize it)",
+                path.display(),
+                width,
+                height,
+                FAVICON_SIZE,
+                FAVICON_SIZE
+            ),
+            FaviconError::EmptyDirectory(path) => {
+                write!(f, "{} does not contain any images", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for FaviconError {}
+
+/// Reads an image and returns it as a png data url.
+/// If `convert` is set, images that are not 64x64 png files are resized and re-encoded instead of rejected.
+pub fn read_favicon_from_file(path: &Path, convert: bool) -> Result<String, FaviconError> {
+    let bytes = fs::read(path).map_err(|e| FaviconError::Io(path.to_path_buf(), e))?;
+    let format =
+        image::guess_format(&bytes).map_err(|_| FaviconError::UnsupportedFormat(path.to_path_buf()))?;
+    if !matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+    ) {
+        return Err(FaviconError::UnsupportedFormat(path.to_path_buf()));
+    }
+
+    let image = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|e| FaviconError::Decode(path.to_path_buf(), e))?;
+    let correct_size = image.width() == FAVICON_SIZE && image.height() == FAVICON_SIZE;
+
+    let png = if format == ImageFormat::Png && correct_size {
+        bytes
+    } else if convert {
+        let resized = if correct_size {
+            image
+        } else {
+            image.resize_exact(FAVICON_SIZE, FAVICON_SIZE, FilterType::Lanczos3)
+        };
+        let mut buffer = Cursor::new(Vec::new());
+        resized
+            .write_to(&mut buffer, ImageFormat::Png)
+            .map_err(|e| FaviconError::Encode(path.to_path_buf(), e))?;
+        buffer.into_inner()
+    } else if !correct_size {
+        return Err(FaviconError::WrongSize(
+            path.to_path_buf(),
+            image.width(),
+            image.height(),
+        ));
+    } else {
+        return Err(FaviconError::NotPng(path.to_path_buf(), format));
+    };
+
+    Ok("data:image/png;base64,".to_owned() + &BASE64_STANDARD.encode(png))
+}
+
+struct Favicon {
+    name: String,
+    data: String,
+}
+
+/// One or more favicons loaded from a file or a directory.
+/// Icons from a directory rotate per request unless one is named after the requested persona.
+pub struct FaviconSet {
+    icons: Vec<Favicon>,
+    next: AtomicUsize,
 }
 
-fn read_bytes_from_file(filename: &String) -> std::io::Result<Vec<u8>> {
-    let mut f = File::open(filename)?;
-    let metadata = fs::metadata(filename)?;
-    let size = metadata.len() as usize;
-    let mut buffer = vec![0; size];
-    f.read_exact(&mut buffer)?;
+impl FaviconSet {
+    pub fn load(path: &Path, convert: bool) -> Result<FaviconSet, FaviconError> {
+        let mut icons = Vec::new();
+        if path.is_dir() {
+            let mut files = fs::read_dir(path)
+                .map_err(|e| FaviconError::Io(path.to_path_buf(), e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && has_image_extension(p))
+                .collect::<Vec<PathBuf>>();
+            files.sort();
+            for file in files {
+                icons.push(Favicon {
+                    name: file_stem(&file),
+                    data: read_favicon_from_file(&file, convert)?,
+                });
+            }
+            if icons.is_empty() {
+                return Err(FaviconError::EmptyDirectory(path.to_path_buf()));
+            }
+        } else {
+            icons.push(Favicon {
+                name: file_stem(path),
+                data: read_favicon_from_file(path, convert)?,
+            });
+        }
+
+        Ok(FaviconSet {
+            icons,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.icons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.icons.is_empty()
+    }
+
+    /// Returns the icon named after `persona` if there is one, otherwise the next icon in the rotation.
+    pub fn get(&self, persona: Option<&str>) -> String {
+        if let Some(icon) = persona.and_then(|name| self.icons.iter().find(|i| i.name == name)) {
+            return icon.data.clone();
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.icons.len();
+        self.icons[index].data.clone()
+    }
+}
+
+fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| FAVICON_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mc-honeypot-favicon-{}-{}",
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_image(path: &Path, size: u32, red: u8) {
+        RgbImage::from_pixel(size, size, Rgb([red, 0, 0])).save(path).unwrap();
+    }
+
+    fn decode(data: &str) -> image::DynamicImage {
+        let png = BASE64_STANDARD
+            .decode(data.strip_prefix("data:image/png;base64,").unwrap())
+            .unwrap();
+        image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap()
+    }
+
+    #[test]
+    fn uses_64x64_pngs_as_they_are() {
+        let dir = temp_dir();
+        let path = dir.join("icon.png");
+        write_image(&path, 64, 255);
+        let data = read_favicon_from_file(&path, false).unwrap();
+        assert_eq!(data, format!("data:image/png;base64,{}", BASE64_STANDARD.encode(fs::read(&path).unwrap())));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn converts_only_when_asked_to() {
+        let dir = temp_dir();
+        let small = dir.join("small.png");
+        write_image(&small, 32, 255);
+        let jpeg = dir.join("icon.jpg");
+        write_image(&jpeg, 64, 255);
+
+        let error = read_favicon_from_file(&small, false).unwrap_err();
+        assert!(matches!(error, FaviconError::WrongSize(_, 32, 32)));
+        let error = read_favicon_from_file(&jpeg, false).unwrap_err();
+        assert!(matches!(error, FaviconError::NotPng(_, ImageFormat::Jpeg)));
+        assert!(error.to_string().ends_with("is a jpeg image but needs to be a png (use --convert-icon to convert it)"));
+
+        for path in [small, jpeg] {
+            let icon = decode(&read_favicon_from_file(&path, true).unwrap());
+            assert_eq!((icon.width(), icon.height()), (FAVICON_SIZE, FAVICON_SIZE));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = temp_dir();
+        let path = dir.join("icon.png");
+        fs::write(&path, "not an image").unwrap();
+        assert!(matches!(
+            read_favicon_from_file(&path, true),
+            Err(FaviconError::UnsupportedFormat(_))
+        ));
+        assert!(matches!(
+            read_favicon_from_file(&dir.join("missing.png"), true),
+            Err(FaviconError::Io(_, _))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_icons_of_a_directory() {
+        let dir = temp_dir();
+        assert!(matches!(FaviconSet::load(&dir, false), Err(FaviconError::EmptyDirectory(_))));
+        write_image(&dir.join("a.png"), 64, 10);
+        write_image(&dir.join("lobby.png"), 64, 20);
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
 
-    Ok(buffer)
+        let icons = FaviconSet::load(&dir, false).unwrap();
+        assert_eq!(icons.len(), 2);
+        let red = |data: String| decode(&data).to_rgba8().get_pixel(0, 0)[0];
+        assert_eq!(red(icons.get(None)), 10);
+        assert_eq!(red(icons.get(None)), 20);
+        assert_eq!(red(icons.get(Some("unknown"))), 10);
+        assert_eq!(red(icons.get(Some("lobby"))), 20);
+        assert_eq!(red(icons.get(Some("lobby"))), 20);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,87 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::ecs::{event_to_ecs, report_to_ecs};
+use crate::event::Event;
+use crate::report::Report;
+use crate::sink::EventSink;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct FileSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to file-1, file-2, ...
+    pub name: Option<String>,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: FileFormat,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// Events as they are handed to the sinks
+    #[default]
+    Json,
+    /// Elastic Common Schema, e.g. for Filebeat
+    Ecs,
+}
+
+/// Appends events and reports to a file as JSON lines.
+/// The file is reopened for every batch, so it can be rotated.
+pub struct FileSink {
+    path: PathBuf,
+    format: FileFormat,
+    lock: Mutex<()>,
+}
+
+impl FileSink {
+    pub fn new(config: FileSinkConfig) -> FileSink {
+        FileSink {
+            path: config.path,
+            format: config.format,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Opens the file once for all values
+    fn append<T: Serialize>(&self, values: &[T]) {
+        let _lock = self.lock.lock().unwrap();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                values.iter().try_for_each(|value| {
+                    let line = serde_json::to_string(value)?;
+                    writeln!(file, "{}", line)
+                })
+            });
+        if let Err(e) = result {
+            log::error!("Unable to write to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl EventSink for FileSink {
+    fn send(&self, event: &Event) {
+        self.send_batch(std::slice::from_ref(event));
+    }
+
+    fn send_batch(&self, events: &[Event]) {
+        match self.format {
+            FileFormat::Json => self.append(events),
+            FileFormat::Ecs => self.append(&events.iter().map(event_to_ecs).collect::<Vec<Value>>()),
+        }
+    }
+
+    fn report(&self, report: &Report) {
+        match self.format {
+            FileFormat::Json => self.append(std::slice::from_ref(report)),
+            FileFormat::Ecs => self.append(&[report_to_ecs(report)]),
+        }
+    }
+}
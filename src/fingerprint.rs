@@ -0,0 +1,258 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::routing::normalize_host;
+use crate::types::{ConnectionTrace, PacketKind, Request, RequestType};
+
+/// The signatures used if no rules file is given
+pub const DEFAULT_RULES: &str = include_str!("../fingerprints.toml");
+
+pub const UNKNOWN_LABEL: &str = "unknown";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Fingerprint {
+    /// The name of the first matching signature or "unknown"
+    pub label: String,
+    /// Hash over the observed client behaviour, identical for identical tools even if no signature matches
+    pub hash: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostnameKind {
+    IpLiteral,
+    Empty,
+    Name,
+}
+
+impl HostnameKind {
+    fn of(host: &str) -> HostnameKind {
+        if host.is_empty() {
+            HostnameKind::Empty
+        } else if host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok()
+        {
+            HostnameKind::IpLiteral
+        } else {
+            HostnameKind::Name
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadKind {
+    Zero,
+    /// Fits into 32 bits, e.g. a counter or a monotonic clock
+    Small,
+    /// Within a year of the current unix time in seconds
+    UnixSeconds,
+    /// Within a year of the current unix time in milliseconds
+    UnixMillis,
+    Random,
+}
+
+impl PayloadKind {
+    fn of(payload: i64) -> PayloadKind {
+        const YEAR_SECONDS: i64 = 365 * 24 * 60 * 60;
+        let now = Utc::now().timestamp();
+        if payload == 0 {
+            PayloadKind::Zero
+        } else if (now - YEAR_SECONDS..now + YEAR_SECONDS).contains(&payload) {
+            PayloadKind::UnixSeconds
+        } else if ((now - YEAR_SECONDS) * 1000..(now + YEAR_SECONDS) * 1000).contains(&payload) {
+            PayloadKind::UnixMillis
+        } else if (0..=u32::MAX as i64).contains(&payload) {
+            PayloadKind::Small
+        } else {
+            PayloadKind::Random
+        }
+    }
+}
+
+/// Conditions that are left out match anything
+#[derive(Deserialize)]
+struct SignatureConfig {
+    name: String,
+    #[serde(default)]
+    protocol_versions: Vec<i32>,
+    packets: Option<Vec<PacketKind>>,
+    hostname: Option<HostnameKind>,
+    hostname_regex: Option<String>,
+    server_port: Option<u16>,
+    ping_payload: Option<PayloadKind>,
+    ping_payload_value: Option<i64>,
+    min_gap_ms: Option<u64>,
+    max_gap_ms: Option<u64>,
+    username_regex: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RulesConfig {
+    #[serde(default)]
+    signatures: Vec<SignatureConfig>,
+}
+
+struct Signature {
+    config: SignatureConfig,
+    hostname_regex: Option<Regex>,
+    username_regex: Option<Regex>,
+}
+
+impl Signature {
+    fn matches(&self, features: &Features) -> bool {
+        let c = &self.config;
+        (c.protocol_versions.is_empty() || c.protocol_versions.contains(&features.protocol_version))
+            && c.packets.as_ref().is_none_or(|p| *p == features.packets)
+            && c.hostname.is_none_or(|h| h == features.hostname_kind)
+            && self
+                .hostname_regex
+                .as_ref()
+                .is_none_or(|r| r.is_match(&features.hostname))
+            && c.server_port.is_none_or(|p| p == features.server_port)
+            && c.ping_payload.is_none_or(|p| Some(p) == features.payload_kind)
+            && c.ping_payload_value.is_none_or(|p| Some(p) == features.ping_payload)
+            && c.min_gap_ms.is_none_or(|g| features.max_gap_ms >= g)
+            && c.max_gap_ms.is_none_or(|g| features.max_gap_ms <= g)
+            && self.username_regex.as_ref().is_none_or(|r| {
+                features.username.as_ref().is_some_and(|u| r.is_match(u))
+            })
+    }
+}
+
+/// The parts of a connection the classifier looks at
+struct Features {
+    protocol_version: i32,
+    packets: Vec<PacketKind>,
+    hostname: String,
+    hostname_kind: HostnameKind,
+    server_port: u16,
+    next_state: Option<i32>,
+    ping_payload: Option<i64>,
+    payload_kind: Option<PayloadKind>,
+    max_gap_ms: u64,
+    username: Option<String>,
+}
+
+impl Features {
+    fn extract(request: &Request, trace: &ConnectionTrace) -> Features {
+        let (handshake, username) = match &request.request_type {
+            RequestType::Join(req) => (&req.handshake, Some(req.player.name.clone())),
+            RequestType::ModernPing(req) | RequestType::LegacyPing(req) => (req, None),
+        };
+        let hostname = normalize_host(&handshake.server_address);
+        Features {
+            protocol_version: handshake.protocol_version,
+            packets: trace.packets.iter().map(|p| p.kind).collect(),
+            hostname_kind: HostnameKind::of(&hostname),
+            hostname,
+            server_port: handshake.server_port,
+            next_state: trace.next_state,
+            ping_payload: trace.ping_payload,
+            payload_kind: trace.ping_payload.map(PayloadKind::of),
+            max_gap_ms: trace.max_gap_ms(),
+            username,
+        }
+    }
+
+    /// Builds a stable hash over the behaviour of the client.
+    /// Values that differ between runs of the same tool (exact timings, payloads, usernames) are bucketed or left out.
+    fn hash(&self) -> String {
+        let timing = match self.max_gap_ms {
+            0..=9 => "burst",
+            10..=199 => "fast",
+            _ => "slow",
+        };
+        let canonical = format!(
+            "v={};packets={:?};host={:?};port={};next={:?};payload={:?};timing={}",
+            self.protocol_version,
+            self.packets,
+            self.hostname_kind,
+            self.server_port,
+            self.next_state,
+            self.payload_kind,
+            timing
+        );
+        format!("{:016x}", fnv1a(canonical.as_bytes()))
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Labels connections with the scanning tool that most likely made them
+pub struct FingerprintRules {
+    signatures: Vec<Signature>,
+}
+
+impl FingerprintRules {
+    pub fn parse(rules: &str) -> Result<FingerprintRules> {
+        let config: RulesConfig = toml::from_str(rules)?;
+        let mut signatures = Vec::new();
+        for signature in config.signatures {
+            signatures.push(Signature {
+                hostname_regex: signature
+                    .hostname_regex
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()?,
+                username_regex: signature
+                    .username_regex
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()?,
+                config: signature,
+            });
+        }
+        Ok(FingerprintRules { signatures })
+    }
+
+    pub fn load(path: &Path) -> Result<FingerprintRules> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| eyre!("Unable to parse {}: {}", path.display(), e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    pub fn classify(&self, request: &Request, trace: &ConnectionTrace) -> Fingerprint {
+        let features = Features::extract(request, trace);
+        let label = self
+            .signatures
+            .iter()
+            .find(|s| s.matches(&features))
+            .map(|s| s.config.name.clone())
+            .unwrap_or(UNKNOWN_LABEL.to_string());
+        Fingerprint {
+            label,
+            hash: features.hash(),
+        }
+    }
+}
+
+impl Default for FingerprintRules {
+    fn default() -> Self {
+        Self::parse(DEFAULT_RULES).expect("The default fingerprint rules are invalid")
+    }
+}
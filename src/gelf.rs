@@ -0,0 +1,306 @@
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::delivery::{DeliveryConfig, DeliveryStats};
+use crate::event::{Event, EventKind};
+use crate::report::Report;
+use crate::rules::Severity;
+use crate::sink::{BufferedSender, EventSink};
+use crate::socket::{Protocol, SocketTarget};
+use crate::types::RequestType;
+
+/// Datagrams above this size are split into chunks, small enough to pass most links unfragmented
+const CHUNK_SIZE: usize = 1420;
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+/// Magic bytes, message id, sequence number and sequence count
+const CHUNK_HEADER: usize = 12;
+/// Graylog discards messages with more chunks
+const MAX_CHUNKS: usize = 128;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GelfSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to gelf-1, gelf-2, ...
+    pub name: Option<String>,
+    /// `udp://host:port` or `tcp://host:port`, Graylog listens on 12201 by default
+    pub address: String,
+    /// Sent as the host, defaults to the hostname of the system
+    pub host: Option<String>,
+}
+
+/// Sends events as GELF 1.1 messages to Graylog, chunked over udp and null terminated over tcp
+pub struct GelfSink {
+    host: String,
+    buffer: BufferedSender<Vec<u8>>,
+}
+
+impl GelfSink {
+    pub fn new(config: GelfSinkConfig, delivery: DeliveryConfig) -> Result<GelfSink> {
+        let mut target = SocketTarget::parse(&config.address)?;
+        if !matches!(target.protocol(), Protocol::Udp | Protocol::Tcp) {
+            return Err(eyre!("GELF is only supported over udp and tcp"));
+        }
+        let host = config
+            .host
+            .or_else(|| {
+                fs::read_to_string("/proc/sys/kernel/hostname")
+                    .ok()
+                    .map(|h| h.trim().to_string())
+            })
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| String::from("mc-honeypot"));
+
+        let address = config.address.clone();
+        let stats = delivery.stats;
+        let message_ids = AtomicU64::new(0);
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(5),
+            1,
+            move |messages: Vec<Vec<u8>>| {
+                for message in messages {
+                    let id = message_id(&message_ids);
+                    deliver(&mut target, &address, message, id, &stats);
+                }
+            },
+        );
+
+        Ok(GelfSink { host, buffer })
+    }
+
+    fn enqueue(&self, message: Value) {
+        match serde_json::to_vec(&message) {
+            Ok(bytes) => self.buffer.add(bytes),
+            Err(e) => log::error!("Unable to serialize GELF message: {}", e),
+        }
+    }
+}
+
+impl EventSink for GelfSink {
+    fn send(&self, event: &Event) {
+        let level = match &event.alert {
+            Some(alert) => match alert.severity {
+                Severity::Critical => 2,
+                Severity::Warning => 4,
+                Severity::Notice => 5,
+                Severity::Info => 6,
+            },
+            None => match &event.kind {
+                EventKind::Connection(c) if matches!(c.request, RequestType::Join(_)) => 5,
+                _ => 6,
+            },
+        };
+        let mut message = Map::new();
+        message.insert(String::from("version"), json!("1.1"));
+        message.insert(String::from("host"), json!(self.host));
+        message.insert(String::from("short_message"), json!(short_message(event)));
+        message.insert(String::from("timestamp"), json!(timestamp(event.timestamp)));
+        message.insert(String::from("level"), json!(level));
+        for (key, value) in fields(event) {
+            message.insert(format!("_{}", key), value);
+        }
+        self.enqueue(Value::Object(message));
+    }
+
+    fn report(&self, report: &Report) {
+        self.enqueue(json!({
+            "version": "1.1",
+            "host": self.host,
+            "short_message": format!("{} report: {}", report.period, report.summary()),
+            "full_message": serde_json::to_string_pretty(report).unwrap_or_default(),
+            "timestamp": timestamp(report.end),
+            "level": 6,
+            "_kind": "report",
+            "_period": report.period,
+            "_connections": report.connections,
+            "_unique_sources": report.unique_sources,
+        }));
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+fn timestamp(timestamp: chrono::DateTime<chrono::Utc>) -> f64 {
+    timestamp.timestamp_millis() as f64 / 1000.0
+}
+
+fn short_message(event: &Event) -> String {
+    match &event.kind {
+        EventKind::Connection(c) => match &c.request {
+            RequestType::Join(req) => format!("Join attempt from {} as {}", c.remote_address, req.player.name),
+            RequestType::ModernPing(_) => format!("Ping from {}", c.remote_address),
+            RequestType::LegacyPing(_) => format!("Legacy ping from {}", c.remote_address),
+        },
+        EventKind::Visit(visit) => format!(
+            "Visit from {} ended after {} connection(s)",
+            event.source, visit.connections
+        ),
+    }
+}
+
+/// Additional fields, sent with an underscore prefix
+fn fields(event: &Event) -> Vec<(&'static str, Value)> {
+    let mut fields = vec![
+        ("kind", json!(event.name())),
+        ("source_ip", json!(event.source.to_string())),
+    ];
+    match &event.kind {
+        EventKind::Connection(c) => {
+            let handshake = match &c.request {
+                RequestType::Join(req) => &req.handshake,
+                RequestType::ModernPing(req) | RequestType::LegacyPing(req) => req,
+            };
+            fields.push(("source_port", json!(c.remote_address.port())));
+            fields.push(("listener", json!(c.local_address.port())));
+            fields.push(("server_address", json!(handshake.server_address)));
+            fields.push(("server_port", json!(handshake.server_port)));
+            fields.push(("protocol_version", json!(handshake.protocol_version)));
+            fields.push(("persona", json!(c.persona)));
+            fields.push(("action", json!(c.action)));
+            if let RequestType::Join(req) = &c.request {
+                fields.push(("username", json!(req.player.name)));
+            }
+            if let Some(fingerprint) = &c.fingerprint {
+                fields.push(("fingerprint", json!(format!("{} #{}", fingerprint.label, fingerprint.hash))));
+            }
+        }
+        EventKind::Visit(visit) => {
+            fields.push(("connections", json!(visit.connections)));
+            if !visit.usernames.is_empty() {
+                fields.push(("usernames", json!(visit.usernames.join(","))));
+            }
+        }
+    }
+    if let Some(ptr) = &event.ptr {
+        fields.push(("ptr", json!(ptr)));
+    }
+    if let Some(geo) = &event.geo {
+        if let Some(country) = &geo.country {
+            fields.push(("country", json!(country)));
+        }
+        if let Some(asn) = geo.asn {
+            fields.push(("asn", json!(asn)));
+        }
+    }
+    if let Some(sensor) = &event.sensor {
+        fields.push(("sensor", json!(sensor)));
+    }
+    if !event.tags.is_empty() {
+        fields.push(("tags", json!(event.tags.join(","))));
+    }
+    if let Some(alert) = &event.alert {
+        fields.push(("rule", json!(alert.rule)));
+        fields.push(("severity", json!(alert.severity.name())));
+    }
+    fields
+}
+
+/// Message ids only need to be unique among the chunks in flight, mixing in the hash keeps restarts apart
+fn message_id(counter: &AtomicU64) -> [u8; 8] {
+    let count = counter.fetch_add(1, Ordering::Relaxed);
+    let digest = md5::compute(format!("{}-{}-{}", std::process::id(), chrono::Utc::now(), count));
+    let mut id = [0; 8];
+    id.copy_from_slice(&digest.0[..8]);
+    id
+}
+
+/// Splits a message into GELF chunks, or returns `None` if it needs more than allowed
+fn chunks(message: &[u8], id: [u8; 8]) -> Option<Vec<Vec<u8>>> {
+    if message.len() <= CHUNK_SIZE {
+        return Some(vec![message.to_vec()]);
+    }
+    let parts = message.chunks(CHUNK_SIZE - CHUNK_HEADER).collect::<Vec<&[u8]>>();
+    if parts.len() > MAX_CHUNKS {
+        return None;
+    }
+    let count = parts.len() as u8;
+    let chunks = parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| {
+            let mut chunk = Vec::with_capacity(CHUNK_HEADER + part.len());
+            chunk.extend_from_slice(&CHUNK_MAGIC);
+            chunk.extend_from_slice(&id);
+            chunk.push(i as u8);
+            chunk.push(count);
+            chunk.extend_from_slice(part);
+            chunk
+        })
+        .collect();
+    Some(chunks)
+}
+
+fn deliver(target: &mut SocketTarget, address: &str, mut message: Vec<u8>, id: [u8; 8], stats: &DeliveryStats) {
+    let result = match target.protocol() {
+        Protocol::Udp => match chunks(&message, id) {
+            Some(chunks) => chunks.iter().try_for_each(|chunk| target.send(chunk)),
+            None => {
+                log::error!(
+                    "Unable to send to GELF server {}: message of {} bytes exceeds {} chunks",
+                    address,
+                    message.len(),
+                    MAX_CHUNKS
+                );
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        },
+        _ => {
+            // Messages over tcp are terminated by a null byte
+            message.push(0);
+            // One retry covers connections the server closed in the meantime
+            target.send(&message).or_else(|_| target.send(&message))
+        }
+    };
+    match result {
+        Ok(()) => {
+            stats.delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            log::error!("Unable to send to GELF server {}: {}", address, e);
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn sends_small_messages_unchunked() {
+        let message = vec![b'x'; CHUNK_SIZE];
+        assert_eq!(chunks(&message, ID), Some(vec![message]));
+    }
+
+    #[test]
+    fn chunks_large_messages() {
+        let message = (0..CHUNK_SIZE * 2).map(|i| i as u8).collect::<Vec<u8>>();
+        let chunks = chunks(&message, ID).unwrap();
+        assert_eq!(chunks.len(), 3);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.len() <= CHUNK_SIZE);
+            assert_eq!(chunk[..2], CHUNK_MAGIC);
+            assert_eq!(chunk[2..10], ID);
+            assert_eq!(chunk[10], i as u8);
+            assert_eq!(chunk[11], 3);
+        }
+        let joined = chunks.iter().flat_map(|c| c[CHUNK_HEADER..].to_vec()).collect::<Vec<u8>>();
+        assert_eq!(joined, message);
+    }
+
+    #[test]
+    fn rejects_messages_needing_too_many_chunks() {
+        let largest = vec![0; MAX_CHUNKS * (CHUNK_SIZE - CHUNK_HEADER)];
+        assert_eq!(chunks(&largest, ID).map(|c| c.len()), Some(MAX_CHUNKS));
+        let too_large = vec![0; largest.len() + 1];
+        assert_eq!(chunks(&too_large, ID), None);
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+
+/// How often we check whether a database file was replaced
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 code
+    pub country: Option<String>,
+    pub country_name: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    pub organization: Option<String>,
+}
+
+impl GeoInfo {
+    /// Formats the location for log lines and webhooks, e.g. `DE, Berlin, AS3320 Deutsche Telekom AG`
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        if let Some(city) = &self.city {
+            parts.push(city.clone());
+        }
+        match (self.asn, &self.organization) {
+            (Some(asn), Some(org)) => parts.push(format!("AS{} {}", asn, org)),
+            (Some(asn), None) => parts.push(format!("AS{}", asn)),
+            (None, Some(org)) => parts.push(org.clone()),
+            (None, None) => {}
+        }
+        parts.join(", ")
+    }
+}
+
+/// A MaxMind database that is reloaded once its file changes.
+/// If a changed file can't be read, the previous version is kept. Lookups are skipped until the file was read once.
+struct Database {
+    path: PathBuf,
+    reader: RwLock<Option<Reader<Vec<u8>>>>,
+    state: Mutex<(Instant, Option<SystemTime>)>,
+}
+
+impl Database {
+    fn open(path: PathBuf) -> Database {
+        let database = Database {
+            path,
+            reader: RwLock::new(None),
+            state: Mutex::new((Instant::now(), None)),
+        };
+        database.reload();
+        database
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    fn reload(&self) {
+        let modified = self.modified();
+        *self.state.lock().unwrap() = (Instant::now(), modified);
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                log::info!("Loaded GeoIP database {}", self.path.display());
+                *self.reader.write().unwrap() = Some(reader);
+            }
+            Err(e) if self.reader.read().unwrap().is_some() => {
+                log::warn!(
+                    "Unable to reload GeoIP database {}, keeping the previous version: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+            Err(e) => {
+                log::warn!(
+                    "Unable to load GeoIP database {}, skipping it: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        let changed = {
+            let mut state = self.state.lock().unwrap();
+            if state.0.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            state.0 = Instant::now();
+            self.modified() != state.1
+        };
+        if changed {
+            self.reload();
+        }
+    }
+}
+
+/// Looks up the location and network of ip addresses in local GeoLite2/GeoIP2 City and ASN databases
+pub struct GeoIp {
+    city: Option<Database>,
+    asn: Option<Database>,
+}
+
+impl GeoIp {
+    pub fn new(city_database: Option<PathBuf>, asn_database: Option<PathBuf>) -> GeoIp {
+        GeoIp {
+            city: city_database.map(Database::open),
+            asn: asn_database.map(Database::open),
+        }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let mut info = GeoInfo::default();
+        let mut found = false;
+
+        if let Some(database) = &self.city {
+            database.reload_if_changed();
+            if let Some(reader) = database.reader.read().unwrap().as_ref() {
+                if let Ok(city) = reader.lookup::<geoip2::City>(ip) {
+                    found = true;
+                    if let Some(country) = city.country {
+                        info.country = country.iso_code.map(String::from);
+                        info.country_name = english_name(&country.names);
+                    }
+                    info.city = city.city.and_then(|c| english_name(&c.names));
+                }
+            }
+        }
+
+        if let Some(database) = &self.asn {
+            database.reload_if_changed();
+            if let Some(reader) = database.reader.read().unwrap().as_ref() {
+                if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                    found = true;
+                    info.asn = asn.autonomous_system_number;
+                    info.organization = asn.autonomous_system_organization.map(String::from);
+                }
+            }
+        }
+
+        found.then_some(info)
+    }
+}
+
+fn english_name(names: &Option<std::collections::BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .as_ref()
+        .and_then(|n| n.get("en"))
+        .map(|n| n.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    /// A database without any networks, just enough metadata for the reader to accept it
+    fn empty_database() -> Vec<u8> {
+        fn key(data: &mut Vec<u8>, key: &str) {
+            data.push(0x40 | key.len() as u8);
+            data.extend(key.as_bytes());
+        }
+        // The data section separator, the search tree has no nodes
+        let mut data = vec![0; 16];
+        data.extend(b"\xab\xcd\xefMaxMind.com");
+        data.push(0xe9);
+        key(&mut data, "binary_format_major_version");
+        data.extend([0xa1, 2]);
+        key(&mut data, "binary_format_minor_version");
+        data.push(0xa0);
+        key(&mut data, "build_epoch");
+        data.extend([0x00, 2]);
+        key(&mut data, "database_type");
+        data.extend(b"\x44Test");
+        key(&mut data, "description");
+        data.push(0xe0);
+        key(&mut data, "ip_version");
+        data.extend([0xa1, 6]);
+        key(&mut data, "languages");
+        data.extend([0x00, 4]);
+        key(&mut data, "node_count");
+        data.push(0xc0);
+        key(&mut data, "record_size");
+        data.extend([0xa1, 24]);
+        data
+    }
+
+    #[test]
+    fn keeps_the_previous_database_if_a_changed_file_is_broken() {
+        let path = std::env::temp_dir().join(format!(
+            "mc-honeypot-geoip-{}-{}.mmdb",
+            std::process::id(),
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        fs::write(&path, empty_database()).unwrap();
+        let database = Database::open(path.clone());
+        assert!(database.reader.read().unwrap().is_some());
+
+        fs::write(&path, b"not a database").unwrap();
+        database.reload();
+        assert!(database.reader.read().unwrap().is_some());
+
+        fs::remove_file(&path).unwrap();
+        assert!(Database::open(path).reader.read().unwrap().is_none());
+    }
+}
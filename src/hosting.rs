@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::geoip::GeoInfo;
+use crate::routing::wildcard_to_regex;
+
+/// The categories used if no patterns file is given
+pub const DEFAULT_PATTERNS: &str = include_str!("../hosting.toml");
+
+#[derive(Deserialize)]
+struct CategoryConfig {
+    tag: String,
+    #[serde(default)]
+    ptr: Vec<String>,
+    #[serde(default)]
+    asns: Vec<u32>,
+    #[serde(default)]
+    organizations: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PatternsConfig {
+    #[serde(default)]
+    categories: Vec<CategoryConfig>,
+}
+
+struct Category {
+    tag: String,
+    ptr: Vec<Regex>,
+    asns: Vec<u32>,
+    /// Lowercased, matched as substrings of the ASN organization
+    organizations: Vec<String>,
+}
+
+/// Tags sources as e.g. research scanners or cloud servers based on their PTR record and ASN.
+/// Categories are checked in order, the first match wins.
+pub struct HostingClassifier {
+    categories: Vec<Category>,
+}
+
+impl HostingClassifier {
+    pub fn parse(patterns: &str) -> Result<HostingClassifier> {
+        let config: PatternsConfig = toml::from_str(patterns)?;
+        let mut categories = Vec::new();
+        for category in config.categories {
+            categories.push(Category {
+                tag: category.tag,
+                ptr: category
+                    .ptr
+                    .iter()
+                    .map(|p| wildcard_to_regex(&p.to_ascii_lowercase()))
+                    .collect::<Result<Vec<Regex>>>()?,
+                asns: category.asns,
+                organizations: category
+                    .organizations
+                    .iter()
+                    .map(|o| o.to_ascii_lowercase())
+                    .collect(),
+            });
+        }
+        Ok(HostingClassifier { categories })
+    }
+
+    pub fn load(path: &Path) -> Result<HostingClassifier> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| eyre!("Unable to parse {}: {}", path.display(), e))
+    }
+
+    pub fn classify(&self, ptr: Option<&str>, geo: Option<&GeoInfo>) -> Option<&str> {
+        let ptr = ptr.map(|p| p.trim_end_matches('.').to_ascii_lowercase());
+        let asn = geo.and_then(|g| g.asn);
+        let organization = geo
+            .and_then(|g| g.organization.as_ref())
+            .map(|o| o.to_ascii_lowercase());
+
+        self.categories
+            .iter()
+            .find(|c| {
+                ptr.as_ref()
+                    .is_some_and(|ptr| c.ptr.iter().any(|p| p.is_match(ptr)))
+                    || asn.is_some_and(|asn| c.asns.contains(&asn))
+                    || organization
+                        .as_ref()
+                        .is_some_and(|org| c.organizations.iter().any(|o| org.contains(o)))
+            })
+            .map(|c| c.tag.as_str())
+    }
+}
+
+impl Default for HostingClassifier {
+    fn default() -> Self {
+        Self::parse(DEFAULT_PATTERNS).expect("The default hosting patterns are invalid")
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason};
+use reqwest::Method;
+use serde::Deserialize;
+
+use crate::delivery::{sink_name, Delivery, DeliveryConfig, OutgoingRequest};
+use crate::event::Event;
+use crate::report::Report;
+use crate::sink::{BufferedSender, EventSink};
+
+/// Posts the whole event as JSON if no body template is configured
+const DEFAULT_BODY: &str = "{{json this}}";
+const TEMPLATE_NAME: &str = "body";
+const REPORT_TEMPLATE_NAME: &str = "report";
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct HttpSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to http-1, http-2, ...
+    pub name: Option<String>,
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// A Handlebars template rendered with the event, see the README for its fields
+    pub body: Option<String>,
+    /// Read the body template from a file instead
+    pub body_file: Option<PathBuf>,
+    /// A Handlebars template rendered with scheduled reports, they are posted as JSON if not set
+    pub report_body: Option<String>,
+    /// Seconds between deliveries, events are buffered in between
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    /// Requests sent per delivery, a full batch is sent right away
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+}
+
+fn default_method() -> String {
+    String::from("POST")
+}
+
+fn default_flush_interval() -> u64 {
+    5
+}
+
+fn default_max_batch() -> usize {
+    100
+}
+
+/// Writes a value as JSON, unlike other expressions its output isn't escaped
+fn json(
+    helper: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = helper
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("json", 0))?;
+    out.write(&serde_json::to_string(value.value()).unwrap_or_default())?;
+    Ok(())
+}
+
+/// Escapes values for use inside a JSON string, as most templates build JSON bodies out of what clients sent
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Sends one request per event to any HTTP endpoint, e.g. Slack, Mattermost, Matrix, ntfy or a custom collector
+pub struct HttpSink {
+    templates: Handlebars<'static>,
+    buffer: BufferedSender<String>,
+}
+
+impl HttpSink {
+    pub fn new(mut config: HttpSinkConfig, delivery: DeliveryConfig) -> Result<HttpSink> {
+        let template = match (&config.body, &config.body_file) {
+            (Some(_), Some(_)) => return Err(eyre!("Only one of body and body_file can be set")),
+            (Some(body), None) => body.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|e| eyre!("Unable to read {}: {}", path.display(), e))?,
+            (None, None) => {
+                if !config.headers.keys().any(|h| h.eq_ignore_ascii_case("content-type")) {
+                    config
+                        .headers
+                        .insert(String::from("Content-Type"), String::from("application/json"));
+                }
+                DEFAULT_BODY.to_string()
+            }
+        };
+        let mut templates = Handlebars::new();
+        templates.register_escape_fn(escape_json);
+        templates.register_helper("json", Box::new(json));
+        templates
+            .register_template_string(TEMPLATE_NAME, template)
+            .map_err(|e| eyre!("Invalid body template: {}", e))?;
+        templates
+            .register_template_string(
+                REPORT_TEMPLATE_NAME,
+                config.report_body.as_deref().unwrap_or(DEFAULT_BODY),
+            )
+            .map_err(|e| eyre!("Invalid report body template: {}", e))?;
+
+        let method = Method::from_bytes(config.method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| eyre!("Invalid method \"{}\"", config.method))?;
+        let mut delivery = Delivery::new(sink_name("http", &config.url), delivery);
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<(String, String)>>();
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(config.flush_interval.max(1) as i64),
+            config.max_batch.max(1),
+            move |bodies: Vec<String>| {
+                if bodies.is_empty() {
+                    delivery.retry_spooled();
+                }
+                for body in bodies {
+                    delivery.deliver(OutgoingRequest {
+                        method: method.to_string(),
+                        url: config.url.clone(),
+                        headers: headers.clone(),
+                        body,
+                    });
+                }
+            },
+        );
+
+        Ok(HttpSink { templates, buffer })
+    }
+}
+
+impl EventSink for HttpSink {
+    fn send(&self, event: &Event) {
+        match self.templates.render(TEMPLATE_NAME, event) {
+            Ok(body) => self.buffer.add(body),
+            Err(e) => log::error!("Unable to render webhook body: {}", e),
+        }
+    }
+
+    fn report(&self, report: &Report) {
+        match self.templates.render(REPORT_TEMPLATE_NAME, report) {
+            Ok(body) => self.buffer.add(body),
+            Err(e) => log::error!("Unable to render report body: {}", e),
+        }
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn render(template: &str, data: &Value) -> String {
+        let mut templates = Handlebars::new();
+        templates.register_escape_fn(escape_json);
+        templates.register_helper("json", Box::new(json));
+        templates.render_template(template, data).unwrap()
+    }
+
+    #[test]
+    fn escapes_values_for_json_strings() {
+        let data = json!({"name": "a\"b\\c\n"});
+        let body = render(r#"{"content": "{{name}}"}"#, &data);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["content"], "a\"b\\c\n");
+    }
+
+    #[test]
+    fn leaves_json_and_triple_stash_unescaped() {
+        let data = json!({"name": "a\"b"});
+        assert_eq!(render("{{json name}}", &data), r#""a\"b""#);
+        assert_eq!(render("{{json this}}", &data), r#"{"name":"a\"b"}"#);
+        assert_eq!(render("{{{name}}}", &data), "a\"b");
+    }
+
+    #[test]
+    fn defaults_the_batch_size() {
+        let config: HttpSinkConfig = toml::from_str(r#"url = "https://ntfy.sh/my-honeypot""#).unwrap();
+        assert_eq!(config.max_batch, 100);
+        let config: HttpSinkConfig = toml::from_str("url = \"https://ntfy.sh/my-honeypot\"\nmax_batch = 5").unwrap();
+        assert_eq!(config.max_batch, 5);
+    }
+}
@@ -1,17 +1,67 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use color_eyre::Result;
 
+use crate::capture::CapturedSession;
+use crate::connections::ActiveConnections;
 use crate::server::HoneypotServer;
-use crate::types::Handler;
+use crate::types::{Filter, Handler, Reporter};
 
+pub mod access;
+pub mod admin;
+pub mod capture;
+pub mod collector;
 pub mod color;
+pub mod connections;
+pub mod dashboard;
+pub mod delivery;
+pub mod ecs;
+pub mod elasticsearch;
+pub mod event;
 pub mod favicon;
+pub mod file;
+pub mod fingerprint;
+pub mod gelf;
+pub mod geoip;
+pub mod hosting;
+pub mod http;
+pub mod limits;
+pub mod loki;
+pub mod mqtt;
+pub mod persona;
+pub mod pipeline;
+pub mod rdns;
+pub mod redis;
+pub mod report;
+pub mod reputation;
+pub mod routing;
+pub mod rules;
+pub mod sensor;
 mod server;
+pub mod session;
+pub mod syslog;
+pub mod sink;
+mod socket;
+pub mod store;
 pub mod types;
 pub mod utils;
 pub mod webhook;
 
-pub fn run_server(port: u16, handler: Handler) -> Result<()> {
-    let server = HoneypotServer::new(port, handler);
+pub fn run_server(
+    port: u16,
+    filter: Filter,
+    handler: Handler,
+    reporter: Reporter,
+    capture_dir: Option<PathBuf>,
+    connections: Arc<ActiveConnections>,
+) -> Result<()> {
+    let server = HoneypotServer::new(port, filter, handler, reporter, capture_dir, connections);
 
     server.start()
 }
+
+/// Feeds the client side of a captured session through the parser and handler again, returns what the honeypot answered
+pub fn replay(session: &CapturedSession, handler: Handler, reporter: Reporter) -> Result<Vec<u8>> {
+    HoneypotServer::replay(session, &handler, &reporter)
+}
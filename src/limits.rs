@@ -0,0 +1,239 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+/// Full buckets are only removed once there are this many, new keys are refused while none of them is full
+const MAX_BUCKETS: usize = 100_000;
+/// Minimum time between two sweeps of the buckets
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+/// The number of sources listed in a suppression report
+const REPORT_TOP_SOURCES: usize = 5;
+
+/// What happens to connections that exceed a limit
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Close the connection right away
+    #[default]
+    Drop,
+    /// Keep the connection open without answering
+    Tarpit,
+    /// Answer as usual, but don't log it or send it to webhooks
+    Silent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitReason {
+    Source,
+    Network,
+    Connections,
+}
+
+/// A limit of 0 disables it
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    /// Connections per minute from a single ip
+    pub per_source: u32,
+    /// Connections per minute from a single /24 (IPv4) or /64 (IPv6) network
+    pub per_network: u32,
+    /// Connections handled at the same time
+    pub max_connections: usize,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.per_source > 0 || self.per_network > 0 || self.max_connections > 0
+    }
+}
+
+/// Holds up to `capacity` tokens and refills them continuously over a minute
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, capacity: u32) {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity as f64 / 60.0).min(capacity as f64);
+        self.updated = Instant::now();
+    }
+}
+
+struct Buckets {
+    capacity: u32,
+    buckets: HashMap<IpAddr, Bucket>,
+    swept: Instant,
+}
+
+impl Buckets {
+    fn new(capacity: u32) -> Buckets {
+        Buckets {
+            capacity,
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, key: IpAddr) -> bool {
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&key) {
+            // Sweeping on every new key would make each connection scan all buckets
+            if self.swept.elapsed() >= SWEEP_INTERVAL {
+                let capacity = self.capacity;
+                self.buckets.retain(|_, b| {
+                    b.refill(capacity);
+                    b.tokens < capacity as f64
+                });
+                self.swept = Instant::now();
+            }
+            if self.buckets.len() >= MAX_BUCKETS {
+                // Letting untracked keys through would let anyone with enough addresses skip the limit
+                return false;
+            }
+        }
+        let bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: self.capacity as f64,
+            updated: Instant::now(),
+        });
+        bucket.refill(self.capacity);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The connections that exceeded a limit since the last report
+#[derive(Clone, Debug, Default)]
+pub struct SuppressionReport {
+    pub by_source: u64,
+    pub by_network: u64,
+    pub by_connections: u64,
+    /// The sources with the most suppressed connections, most first
+    pub top_sources: Vec<(IpAddr, u64)>,
+}
+
+impl SuppressionReport {
+    pub fn total(&self) -> u64 {
+        self.by_source + self.by_network + self.by_connections
+    }
+}
+
+#[derive(Default)]
+struct Suppressed {
+    by_source: u64,
+    by_network: u64,
+    by_connections: u64,
+    sources: HashMap<IpAddr, u64>,
+}
+
+/// Token bucket rate limits per source ip and network, plus a cap on concurrent connections
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    sources: Mutex<Buckets>,
+    networks: Mutex<Buckets>,
+    suppressed: Mutex<Suppressed>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            sources: Mutex::new(Buckets::new(config.per_source)),
+            networks: Mutex::new(Buckets::new(config.per_network)),
+            suppressed: Mutex::new(Suppressed::default()),
+            config,
+        }
+    }
+
+    /// Returns the limit a new connection exceeds, given the number of connections that are already being handled
+    pub fn check(&self, ip: IpAddr, active_connections: usize) -> Option<LimitReason> {
+        let reason = if self.config.max_connections > 0
+            && active_connections >= self.config.max_connections
+        {
+            Some(LimitReason::Connections)
+        } else if self.config.per_source > 0 && !self.sources.lock().unwrap().take(ip) {
+            Some(LimitReason::Source)
+        } else if self.config.per_network > 0
+            && !self.networks.lock().unwrap().take(network_of(ip))
+        {
+            Some(LimitReason::Network)
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            let mut suppressed = self.suppressed.lock().unwrap();
+            match reason {
+                LimitReason::Source => suppressed.by_source += 1,
+                LimitReason::Network => suppressed.by_network += 1,
+                LimitReason::Connections => suppressed.by_connections += 1,
+            }
+            *suppressed.sources.entry(ip).or_default() += 1;
+        }
+        reason
+    }
+
+    /// Returns what was suppressed since the last call and resets the counters
+    pub fn take_report(&self) -> SuppressionReport {
+        let suppressed = std::mem::take(&mut *self.suppressed.lock().unwrap());
+        let mut top_sources = suppressed.sources.into_iter().collect::<Vec<(IpAddr, u64)>>();
+        top_sources.sort_by_key(|(_, count)| Reverse(*count));
+        top_sources.truncate(REPORT_TOP_SOURCES);
+        SuppressionReport {
+            by_source: suppressed.by_source,
+            by_network: suppressed.by_network,
+            by_connections: suppressed.by_connections,
+            top_sources,
+        }
+    }
+}
+
+/// The /24 of an IPv4 or the /64 of an IPv6 address
+pub fn network_of(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(i: usize) -> IpAddr {
+        IpAddr::V6(Ipv6Addr::from(0x2001_0db8_u128 << 96 | i as u128))
+    }
+
+    #[test]
+    fn refuses_new_keys_while_all_buckets_are_in_use() {
+        let mut buckets = Buckets::new(60);
+        for i in 0..MAX_BUCKETS {
+            assert!(buckets.take(ip(i)));
+        }
+        assert!(!buckets.take(ip(MAX_BUCKETS)));
+        // Known keys keep their own bucket
+        assert!(buckets.take(ip(0)));
+
+        // Once the buckets refilled, the next sweep makes room again
+        for bucket in buckets.buckets.values_mut() {
+            bucket.updated -= Duration::from_secs(60);
+        }
+        buckets.swept -= SWEEP_INTERVAL;
+        assert!(buckets.take(ip(MAX_BUCKETS)));
+        assert_eq!(buckets.buckets.len(), 1);
+    }
+
+    #[test]
+    fn limits_each_key() {
+        let mut buckets = Buckets::new(2);
+        assert!(buckets.take(ip(0)));
+        assert!(buckets.take(ip(0)));
+        assert!(!buckets.take(ip(0)));
+        assert!(buckets.take(ip(1)));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::delivery::{sink_name, Delivery, DeliveryConfig, OutgoingRequest};
+use crate::event::{Event, EventKind};
+use crate::report::Report;
+use crate::sink::{BufferedSender, EventSink};
+
+const PUSH_PATH: &str = "/loki/api/v1/push";
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LokiSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to loki-1, loki-2, ...
+    pub name: Option<String>,
+    /// Base url of Loki, e.g. `http://localhost:3100`
+    pub url: String,
+    /// Static labels added to every stream, `job` defaults to `mc-honeypot`
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Sent as `X-Scope-OrgID` to multi-tenant setups
+    pub tenant_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Seconds between push requests, lines are buffered in between
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    /// Lines per push request, a full batch is sent right away
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+}
+
+fn default_flush_interval() -> u64 {
+    5
+}
+
+fn default_max_batch() -> usize {
+    500
+}
+
+type Labels = BTreeMap<String, String>;
+
+struct Line {
+    labels: Labels,
+    timestamp: DateTime<Utc>,
+    line: String,
+}
+
+/// Pushes events as JSON log lines to Grafana Loki, labeled with the event kind, listener port, country and sensor
+pub struct LokiSink {
+    labels: Labels,
+    buffer: BufferedSender<Line>,
+}
+
+impl LokiSink {
+    pub fn new(config: LokiSinkConfig, delivery: DeliveryConfig) -> Result<LokiSink> {
+        if let Some(label) = config.labels.keys().find(|l| !valid_label(l)) {
+            return Err(eyre!("Invalid label name \"{}\"", label));
+        }
+        let mut headers = vec![(String::from("Content-Type"), String::from("application/json"))];
+        if let Some(tenant_id) = &config.tenant_id {
+            headers.push((String::from("X-Scope-OrgID"), tenant_id.clone()));
+        }
+        if let Some(username) = &config.username {
+            let credentials = format!("{}:{}", username, config.password.as_deref().unwrap_or_default());
+            headers.push((
+                String::from("Authorization"),
+                format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+            ));
+        }
+        let mut labels = Labels::from([(String::from("job"), String::from("mc-honeypot"))]);
+        labels.extend(config.labels);
+
+        let base = config.url.trim_end_matches('/');
+        let url = if base.ends_with(PUSH_PATH) {
+            base.to_string()
+        } else {
+            format!("{}{}", base, PUSH_PATH)
+        };
+        let mut delivery = Delivery::new(sink_name("loki", &url), delivery);
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(config.flush_interval.max(1) as i64),
+            config.max_batch.max(1),
+            move |lines: Vec<Line>| {
+                if lines.is_empty() {
+                    delivery.retry_spooled();
+                    return;
+                }
+                delivery.deliver(OutgoingRequest {
+                    method: String::from("POST"),
+                    url: url.clone(),
+                    headers: headers.clone(),
+                    body: push_body(lines),
+                });
+            },
+        );
+
+        Ok(LokiSink { labels, buffer })
+    }
+
+    fn push(&self, extra: Labels, timestamp: DateTime<Utc>, line: String) {
+        let mut labels = self.labels.clone();
+        labels.extend(extra);
+        self.buffer.add(Line {
+            labels,
+            timestamp,
+            line,
+        });
+    }
+}
+
+impl EventSink for LokiSink {
+    fn send(&self, event: &Event) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Unable to serialize event: {}", e);
+                return;
+            }
+        };
+        let mut labels = Labels::from([(String::from("kind"), event.name().to_string())]);
+        if let EventKind::Connection(c) = &event.kind {
+            labels.insert(String::from("listener"), c.local_address.port().to_string());
+        }
+        if let Some(country) = event.geo.as_ref().and_then(|g| g.country.as_ref()) {
+            labels.insert(String::from("country"), country.clone());
+        }
+        if let Some(sensor) = &event.sensor {
+            labels.insert(String::from("sensor"), sensor.clone());
+        }
+        if let Some(alert) = &event.alert {
+            labels.insert(String::from("severity"), alert.severity.name().to_string());
+        }
+        self.push(labels, event.timestamp, line);
+    }
+
+    fn report(&self, report: &Report) {
+        match serde_json::to_string(report) {
+            Ok(line) => self.push(
+                Labels::from([(String::from("kind"), String::from("report"))]),
+                report.end,
+                line,
+            ),
+            Err(e) => log::error!("Unable to serialize report: {}", e),
+        }
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+/// Groups the lines into one stream per label set, Loki expects the values of a stream in order
+fn push_body(lines: Vec<Line>) -> String {
+    let mut streams: BTreeMap<Labels, Vec<(DateTime<Utc>, String)>> = BTreeMap::new();
+    for line in lines {
+        streams.entry(line.labels).or_default().push((line.timestamp, line.line));
+    }
+    let streams = streams
+        .into_iter()
+        .map(|(labels, mut values)| {
+            values.sort_by_key(|(timestamp, _)| *timestamp);
+            let values = values
+                .into_iter()
+                .map(|(timestamp, line)| {
+                    let nanos = timestamp.timestamp_nanos_opt().unwrap_or_default();
+                    json!([nanos.to_string(), line])
+                })
+                .collect::<Vec<_>>();
+            json!({ "stream": labels, "values": values })
+        })
+        .collect::<Vec<_>>();
+    json!({ "streams": streams }).to_string()
+}
+
+/// Label names must match `[a-zA-Z_][a-zA-Z0-9_]*`
+fn valid_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -1,20 +1,51 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::{command, Parser};
-use color_eyre::eyre::Result;
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result};
 use log::LevelFilter;
 use simple_logger::{set_up_color_terminal, SimpleLogger};
+use timer::Timer;
 
-use mc_honeypot::favicon::read_favicon_from_file;
+use mc_honeypot::access::{AccessLists, ListKind};
+use mc_honeypot::admin::{AdminApi, AdminListen};
+use mc_honeypot::capture::{CapturedSession, Direction};
+use mc_honeypot::collector::{run_collector, CollectorConfig, SensorTokens};
+use mc_honeypot::connections::ActiveConnections;
+use mc_honeypot::dashboard::{Dashboard, DashboardConfig};
+use mc_honeypot::delivery::{DeliveryConfig, DeliveryStats};
+use mc_honeypot::event::{Event, EventKind};
+use mc_honeypot::favicon::FaviconSet;
+use mc_honeypot::fingerprint::FingerprintRules;
+use mc_honeypot::geoip::GeoIp;
+use mc_honeypot::hosting::HostingClassifier;
+use mc_honeypot::limits::{LimitAction, RateLimitConfig, RateLimiter};
+use mc_honeypot::persona::{parse_players, Persona};
+use mc_honeypot::pipeline::{Enrichment, Pipeline};
+use mc_honeypot::routing::{JoinAction, Router};
+use mc_honeypot::rdns::{system_resolver, ReverseDns};
+use mc_honeypot::reputation::{
+    ProfileLookup, ReputationConfig, MOJANG_API_URL, MOJANG_SESSION_URL,
+};
+use mc_honeypot::report::{Report, ReportPeriod};
+use mc_honeypot::rules::AlertRules;
 use mc_honeypot::run_server;
+use mc_honeypot::sensor::{SensorConfig, SensorSink};
+use mc_honeypot::sink::{load_sinks, EventSink, NamedSink, SinkOptions};
+use mc_honeypot::store::EventStore;
 use mc_honeypot::types::{
-    Description, Handler, Players, Request, RequestType, SamplePlayer, ServerListPingResponse, Version
+    Admission, Connection, Filter, Handler, Reporter, Request, RequestType, Response, Tarpit,
 };
 use mc_honeypot::webhook::BufferedWebhookClient;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(
         short,
         long,
@@ -63,13 +94,324 @@ struct Args {
     #[arg(
         short,
         long,
-        help = "Path to png image which is displayed as the server icon. Needs to be 64x64 pixels in size"
+        help = "Path to png image which is displayed as the server icon. Needs to be 64x64 pixels in size. If this is a directory, its images are rotated per request"
     )]
     icon_file: Option<String>,
+    #[arg(
+        long,
+        help = "Resize the icon to 64x64 pixels and convert jpeg, gif and webp images to png instead of rejecting them"
+    )]
+    convert_icon: bool,
     #[arg(short, long, help = "URL of discord webhook to send logs to")]
     webhook_url: Option<String>,
+    #[arg(
+        long,
+        help = "Merge all events from the same source within one webhook message into a single embed with counts"
+    )]
+    webhook_digest: bool,
+    #[arg(
+        long,
+        help = "Path to a toml file with additional Discord webhooks, generic HTTP, syslog, Elasticsearch, Loki, GELF, MQTT, Redis and file sinks events are sent to"
+    )]
+    sinks_file: Option<String>,
+    #[arg(
+        long,
+        help = "Path to a toml file with alert rules deciding which sinks an event is sent to and with which severity. Without it, every event is sent to every sink"
+    )]
+    rules_file: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Send a summary report through the sinks every day or week (providable multiple times)",
+        value_name = "PERIOD"
+    )]
+    report: Vec<ReportPeriod>,
+    #[arg(
+        long,
+        help = "Time of day in UTC reports are sent at, weekly ones on Mondays",
+        value_name = "HH:MM",
+        default_value = "00:00"
+    )]
+    report_time: String,
+    #[arg(
+        long,
+        help = "Name of a sink reports are sent to (providable multiple times). Defaults to all sinks",
+        value_name = "NAME"
+    )]
+    report_sink: Vec<String>,
+    #[arg(
+        long,
+        help = "How often a failed webhook or sink request is retried with exponential backoff before it is spooled or dropped",
+        default_value = "5"
+    )]
+    delivery_retries: u32,
+    #[arg(
+        long,
+        help = "Directory requests that could not be delivered to a webhook or sink are stored in and retried from. They are dropped if not provided",
+        value_name = "DIR"
+    )]
+    spool_dir: Option<String>,
+    #[arg(
+        long,
+        help = "Maximum number of spooled requests per sink, the oldest ones are dropped beyond it",
+        default_value = "1000"
+    )]
+    spool_limit: usize,
+    #[arg(
+        long,
+        help = "Path to a toml file with additional personas and virtual-host routes chosen by the hostname clients connect with"
+    )]
+    vhost_file: Option<String>,
+    #[arg(
+        long,
+        help = "Path to a toml file with the signatures used to fingerprint scanners. Defaults to the built-in fingerprints.toml"
+    )]
+    fingerprint_rules: Option<String>,
+    #[arg(
+        long,
+        help = "Seconds a source has to be idle before its connections are summarized as a visit, e.g. 120. 0 disables visits",
+        default_value = "0"
+    )]
+    visit_window: u64,
+    #[arg(long, help = "Look up the usernames of join attempts through the profile api")]
+    lookup_profiles: bool,
+    #[arg(
+        long,
+        help = "Base URL of the Mojang compatible api used to resolve usernames",
+        default_value = MOJANG_API_URL
+    )]
+    profile_api_url: String,
+    #[arg(
+        long,
+        help = "Base URL of the Mojang compatible session server used to resolve skins",
+        default_value = MOJANG_SESSION_URL
+    )]
+    session_api_url: String,
+    #[arg(long, help = "File the profile lookups are cached in, it is saved every minute. They are only kept in memory if not provided. Holds up to 10000 names, the oldest are dropped beyond that")]
+    profile_cache: Option<String>,
+    #[arg(
+        long,
+        help = "Hours a profile lookup is cached for",
+        default_value = "24"
+    )]
+    profile_cache_ttl: i64,
+    #[arg(
+        long,
+        help = "The maximum number of profile lookups per minute, names that are not cached are skipped once it is reached",
+        default_value = "60"
+    )]
+    profile_lookups_per_minute: u32,
+    #[arg(long, help = "Path to a GeoLite2/GeoIP2 City database (.mmdb) used to locate sources")]
+    geoip_city_db: Option<String>,
+    #[arg(long, help = "Path to a GeoLite2/GeoIP2 ASN database (.mmdb) used to identify the network of sources")]
+    geoip_asn_db: Option<String>,
+    #[arg(long, help = "Look up the reverse DNS (PTR) record of every source")]
+    reverse_dns: bool,
+    #[arg(
+        long,
+        help = "Address of the DNS server used for reverse lookups. Defaults to the first nameserver in /etc/resolv.conf",
+        value_name = "IP:PORT"
+    )]
+    dns_resolver: Option<SocketAddr>,
+    #[arg(
+        long,
+        help = "Seconds a reverse DNS record is cached for",
+        default_value = "3600"
+    )]
+    reverse_dns_ttl: u64,
+    #[arg(
+        long,
+        help = "Path to a toml file with the PTR and ASN patterns used to tag research scanners, cloud servers and residential sources. Defaults to the built-in hosting.toml"
+    )]
+    hosting_patterns: Option<String>,
+    #[arg(
+        long,
+        help = "File with networks (one ip or CIDR per line) whose connections are answered but never logged or sent to webhooks (providable multiple times)",
+        value_name = "FILE"
+    )]
+    ignore_list: Vec<String>,
+    #[arg(
+        long,
+        help = "File with networks (one ip or CIDR per line) that are tagged as known benign (providable multiple times)",
+        value_name = "FILE"
+    )]
+    allow_list: Vec<String>,
+    #[arg(
+        long,
+        help = "File with networks (one ip or CIDR per line) whose connections are refused before anything is read (providable multiple times)",
+        value_name = "FILE"
+    )]
+    block_list: Vec<String>,
+    #[arg(
+        long,
+        help = "Connections per minute allowed from a single ip. 0 disables the limit",
+        default_value = "0"
+    )]
+    rate_limit: u32,
+    #[arg(
+        long,
+        help = "Connections per minute allowed from a single /24 (IPv4) or /64 (IPv6) network. 0 disables the limit",
+        default_value = "0"
+    )]
+    network_rate_limit: u32,
+    #[arg(
+        long,
+        help = "The maximum number of connections handled at the same time. 0 disables the limit",
+        default_value = "0"
+    )]
+    max_connections: usize,
+    #[arg(
+        long,
+        help = "What happens to connections exceeding a limit",
+        value_enum,
+        default_value_t = LimitAction::Drop
+    )]
+    limit_action: LimitAction,
+    #[arg(
+        long,
+        help = "Seconds tarpitted join attempts and connections exceeding a limit are held open",
+        default_value = "60"
+    )]
+    tarpit_duration: u64,
+    #[arg(
+        long,
+        help = "File with networks (one ip or CIDR per line) whose connections are answered as slowly as possible (providable multiple times)",
+        value_name = "FILE"
+    )]
+    tarpit_list: Vec<String>,
+    #[arg(
+        long,
+        help = "Fingerprint label whose sources are tarpitted for an hour after they were seen (providable multiple times)",
+        value_name = "LABEL"
+    )]
+    tarpit_fingerprint: Vec<String>,
+    #[arg(
+        long,
+        help = "Milliseconds between the bytes of a tarpitted status response. 0 sends it at once",
+        default_value = "500"
+    )]
+    tarpit_byte_interval: u64,
+    #[arg(
+        long,
+        help = "Seconds a tarpitted ping is delayed",
+        default_value = "20"
+    )]
+    tarpit_pong_delay: u64,
+    #[arg(
+        long,
+        help = "Seconds between the plugin requests that keep tarpitted logins from timing out",
+        default_value = "10"
+    )]
+    tarpit_keep_alive: u64,
+    #[arg(
+        long,
+        help = "Minutes between log lines summarizing access list matches and connections suppressed by limits. 0 disables them",
+        default_value = "10"
+    )]
+    stats_interval: u64,
+    #[arg(
+        long,
+        help = "Directory the raw traffic of every connection is recorded into, one file per connection. Stops at 10000 files",
+        value_name = "DIR"
+    )]
+    capture_dir: Option<String>,
+    #[arg(
+        long,
+        help = "Base URL of a collector every event is forwarded to, e.g. https://collector.example.com:8443. Events are buffered while it is unreachable",
+        value_name = "URL"
+    )]
+    collector_url: Option<String>,
+    #[arg(
+        long,
+        help = "Token this sensor authenticates to the collector with",
+        value_name = "TOKEN",
+        env = "MC_HONEYPOT_COLLECTOR_TOKEN",
+        hide_env_values = true
+    )]
+    collector_token: Option<String>,
+    #[arg(
+        long,
+        help = "PEM file with the certificate of the collector or the CA that issued it, for self-signed certificates",
+        value_name = "FILE"
+    )]
+    collector_ca: Option<String>,
+    #[arg(long, help = "Id this sensor reports its events with. Defaults to the hostname", value_name = "ID")]
+    sensor_id: Option<String>,
+    #[arg(
+        long,
+        help = "Number of events kept while the collector is unreachable, the oldest ones are dropped beyond it",
+        default_value = "10000"
+    )]
+    sensor_buffer: usize,
+    #[arg(
+        long,
+        help = "Address the web dashboard with live events and statistics listens on, e.g. 127.0.0.1:8080. Disabled if not provided",
+        value_name = "IP:PORT"
+    )]
+    dashboard_listen: Option<SocketAddr>,
+    #[arg(
+        long,
+        help = "Password the dashboard asks for with HTTP basic auth, any username is accepted",
+        env = "MC_HONEYPOT_DASHBOARD_PASSWORD",
+        hide_env_values = true
+    )]
+    dashboard_password: Option<String>,
+    #[arg(
+        long,
+        help = "Number of recent events the dashboard keeps in memory",
+        default_value = "10000"
+    )]
+    dashboard_history: usize,
+    #[arg(
+        long,
+        help = "JSON lines file written by a file sink the dashboard loads its history from at startup",
+        value_name = "FILE"
+    )]
+    dashboard_events_file: Option<String>,
+    #[arg(
+        long,
+        help = "Bearer token of the admin api for inspecting and changing the running honeypot. The api is disabled if not provided",
+        value_name = "TOKEN",
+        env = "MC_HONEYPOT_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    admin_token: Option<String>,
+    #[arg(
+        long,
+        help = "Address the admin api listens on, or unix:<path> for a unix socket",
+        value_name = "IP:PORT|unix:PATH",
+        default_value = "127.0.0.1:8081"
+    )]
+    admin_listen: String,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Feeds a captured connection through the parser and handler again and prints the resulting event
+    Replay {
+        /// A file from the capture directory
+        file: String,
+    },
+    /// Receives the events of sensors started with --collector-url and sends them through the sinks
+    Collector {
+        /// Address the collector listens on
+        #[arg(long, default_value = "0.0.0.0:8443")]
+        listen: SocketAddr,
+        /// Path to a toml file with the id and token of every sensor
+        #[arg(long)]
+        tokens_file: String,
+        /// PEM file with the certificate chain, the collector speaks plain HTTP without it
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<String>,
+        /// PEM file with the private key of the certificate
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<String>,
+    },
+}
+
+/// How alert rules refer to the sink created for --webhook-url
+const WEBHOOK_SINK_NAME: &str = "webhook";
+
 fn main() -> Result<()> {
     set_up_color_terminal();
     SimpleLogger::new()
@@ -79,86 +421,529 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    run_server(args.port, get_handler(args.clone()))?;
+    if let Some(Command::Replay { file }) = &args.command {
+        return replay(&args, Path::new(file));
+    }
+    if let Some(Command::Collector { .. }) = &args.command {
+        return collector(&args);
+    }
+
+    let capture_dir = args.capture_dir.as_ref().map(PathBuf::from);
+    if let Some(dir) = &capture_dir {
+        fs::create_dir_all(dir)?;
+        log::info!("Capturing connections into {}", dir.display());
+    }
+
+    let router = Arc::new(get_router(&args)?);
+    let rdns = Arc::new(get_reverse_dns(&args)?);
+    let access = Arc::new(get_access_lists(&args)?);
+    let limits = RateLimitConfig {
+        per_source: args.rate_limit,
+        per_network: args.network_rate_limit,
+        max_connections: args.max_connections,
+    };
+    let limiter = Arc::new(limits.is_enabled().then(|| RateLimiter::new(limits)));
+
+    let delivery = DeliveryConfig {
+        retries: args.delivery_retries,
+        spool_dir: args.spool_dir.as_ref().map(PathBuf::from),
+        spool_limit: args.spool_limit,
+        ..DeliveryConfig::default()
+    };
+    let has_sinks = args.webhook_url.is_some() || args.sinks_file.is_some() || args.collector_url.is_some();
+
+    let enrichment = get_enrichment(&args, router.clone(), rdns.clone(), access.clone())?;
+    let store = start_dashboard(&args, router.clone())?;
+    let pipeline = Arc::new(get_pipeline(&args, enrichment, store, delivery.clone())?);
+
+    let connections = Arc::new(ActiveConnections::new());
+    if let Some(token) = &args.admin_token {
+        AdminApi {
+            token: token.clone(),
+            connections: connections.clone(),
+            router: router.clone(),
+            access: access.clone(),
+            pipeline: pipeline.clone(),
+            delivery: delivery.stats.clone(),
+        }
+        .start(&args.admin_listen.parse::<AdminListen>()?)?;
+    }
+
+    let timer = Timer::new();
+    let _stats_guard = (args.stats_interval > 0 && (!access.is_empty() || limiter.is_some() || has_sinks)).then(|| {
+        let access = access.clone();
+        let limiter = limiter.clone();
+        let delivery = has_sinks.then(|| delivery.stats.clone());
+        let pipeline = pipeline.clone();
+        timer.schedule_repeating(
+            chrono::Duration::minutes(args.stats_interval as i64),
+            move || log_stats(&access, limiter.as_ref().as_ref(), delivery.as_deref(), &pipeline),
+        )
+    });
+
+    run_server(
+        args.port,
+        get_filter(&args, access.clone(), limiter),
+        get_handler(args.clone(), router, rdns, access)?,
+        Arc::new(move |connection: Connection| pipeline.publish(connection)),
+        capture_dir,
+        connections,
+    )?;
 
     Ok(())
 }
 
-fn get_handler(args: Args) -> Handler {
-    let favicon = args.icon_file.map(|s| match read_favicon_from_file(&s) {
-        Ok(s) => s,
-        Err(e) => panic!("{}", e),
+fn replay(args: &Args, path: &Path) -> Result<()> {
+    let session = CapturedSession::load(path)?;
+    let router = Arc::new(get_router(args)?);
+    let access = Arc::new(get_access_lists(args)?);
+    let fingerprints = get_fingerprints(args)?;
+
+    let handler = get_handler(args.clone(), router.clone(), Arc::new(None), access.clone())?;
+    let enrichment = Enrichment::new(router, access, fingerprints);
+    let reporter: Reporter = Arc::new(move |connection: Connection| {
+        let event = enrichment.connection(connection);
+        log_event(&event);
+        match serde_json::to_string_pretty(&event) {
+            Ok(json) => println!("{}", json),
+            Err(e) => log::error!("Unable to serialize event: {}", e),
+        }
     });
-    let sample = match &args.players {
-        Some(s) => {
-            let mut players: Vec<SamplePlayer> = Vec::new();
-            for p in s.iter() {
-                match p.split_once(':') {
-                    Some(sp) => players.push(SamplePlayer {
-                        name: sp.0.to_string(),
-                        id: sp.1.to_string(),
-                    }),
-                    None => log::warn!(
-                        "Unable to split \"{}\", check you are using a \":\" to split the name & UUID",
-                        p
-                    )
-                }
-            }
-            players
+
+    let recorded = session.bytes(Direction::Outbound);
+    let replayed = mc_honeypot::replay(&session, handler, reporter)?;
+    if replayed == recorded {
+        log::info!("The replayed response matches the recorded one ({} bytes)", recorded.len());
+    } else {
+        log::warn!(
+            "The replayed response differs from the recorded one ({} bytes replayed, {} bytes recorded)",
+            replayed.len(),
+            recorded.len()
+        );
+    }
+    Ok(())
+}
+
+fn collector(args: &Args) -> Result<()> {
+    let Some(Command::Collector {
+        listen,
+        tokens_file,
+        tls_cert,
+        tls_key,
+    }) = &args.command
+    else {
+        unreachable!()
+    };
+    let tokens = SensorTokens::load(Path::new(tokens_file))?;
+    log::info!("Loaded the tokens of {} sensor(s)", tokens.len());
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some((fs::read(cert)?, fs::read(key)?)),
+        _ => None,
+    };
+
+    // Sensors send enriched events, only the rules and sinks of the collector apply to them
+    let router = Arc::new(get_router(args)?);
+    let enrichment = Enrichment::new(router.clone(), Arc::new(get_access_lists(args)?), FingerprintRules::default());
+    let delivery = DeliveryConfig {
+        retries: args.delivery_retries,
+        spool_dir: args.spool_dir.as_ref().map(PathBuf::from),
+        spool_limit: args.spool_limit,
+        ..DeliveryConfig::default()
+    };
+    let store = start_dashboard(args, router)?;
+    let pipeline = Arc::new(get_pipeline(args, enrichment, store, delivery.clone())?);
+
+    let timer = Timer::new();
+    let _stats_guard = (args.stats_interval > 0).then(|| {
+        let pipeline = pipeline.clone();
+        let access = AccessLists::new();
+        timer.schedule_repeating(chrono::Duration::minutes(args.stats_interval as i64), move || {
+            log_stats(&access, None, Some(&delivery.stats), &pipeline)
+        })
+    });
+
+    run_collector(
+        CollectorConfig {
+            listen: *listen,
+            tokens,
+            tls,
         },
-        None => vec![],
+        pipeline,
+    )
+}
+
+fn get_router(args: &Args) -> Result<Router> {
+    let default_persona = Persona {
+        version_string: args.version_string.clone(),
+        protocol_version: args.protocol_version,
+        max_players: args.max_players,
+        online_players: args.online_players,
+        players: parse_players(args.players.as_deref().unwrap_or_default()),
+        motd: args.motd.clone(),
     };
+    match &args.vhost_file {
+        Some(path) => Router::load(default_persona, Path::new(path)),
+        None => Ok(Router::new(default_persona)),
+    }
+}
 
-    let client = args.webhook_url.map(BufferedWebhookClient::new);
-    Arc::new(move |request: Request| {
-        if let Some(client) = &client {
-            client.send(&request.remote_address, &request.request_type);
+fn get_reverse_dns(args: &Args) -> Result<Option<ReverseDns>> {
+    if !args.reverse_dns {
+        return Ok(None);
+    }
+    let resolver = args
+        .dns_resolver
+        .or_else(system_resolver)
+        .ok_or_else(|| eyre!("No DNS resolver found, use --dns-resolver to set one"))?;
+    log::info!("Using {} for reverse DNS lookups", resolver);
+    Ok(Some(ReverseDns::new(
+        resolver,
+        Duration::from_secs(args.reverse_dns_ttl),
+    )))
+}
+
+fn get_access_lists(args: &Args) -> Result<AccessLists> {
+    let mut access = AccessLists::new();
+    for (kind, paths) in [
+        (ListKind::Ignore, &args.ignore_list),
+        (ListKind::Allow, &args.allow_list),
+        (ListKind::Block, &args.block_list),
+        (ListKind::Tarpit, &args.tarpit_list),
+    ] {
+        for path in paths {
+            access.add(kind, PathBuf::from(path))?;
         }
-        match request.request_type {
-            RequestType::Join(req) => {
-                log::info!(
-                    "[{}] {} ({}) tried joining the server",
-                    request.remote_address,
-                    req.name, req.id
-                );
-            }
-            RequestType::LegacyPing(req) => {
-                log::info!(
-                    "[{}] Received Legacy Ping Request [{:?}]",
-                    request.remote_address,
-                    req
-                )
-            }
-            RequestType::ModernPing(req) => {
-                log::info!(
-                    "[{}] Received Ping Request [{:?}]",
-                    request.remote_address,
-                    req
-                )
-            }
-        };
-        ServerListPingResponse {
-            version: Version {
-                name: args.version_string.clone(),
-                protocol: args.protocol_version,
-            },
-            players: Players {
-                sample: sample.clone(),
-                max: args.max_players,
-                online: match args.online_players {
-                    Some(v) => v,
-                    None => { match &args.players {
-                        Some(s) => s.len() as i32,
-                        None => 0,
-                    }},
-                },
+    }
+    Ok(access)
+}
+
+fn get_filter(args: &Args, access: Arc<AccessLists>, limiter: Arc<Option<RateLimiter>>) -> Filter {
+    let limited = match args.limit_action {
+        LimitAction::Drop => Admission::Refuse,
+        LimitAction::Tarpit => Admission::Tarpit(Duration::from_secs(args.tarpit_duration)),
+        LimitAction::Silent => Admission::Silent,
+    };
+    Arc::new(move |address, active_connections| {
+        if access.check(address.ip()) == Some(ListKind::Block) {
+            return Admission::Refuse;
+        }
+        match limiter.as_ref() {
+            Some(limiter) if limiter.check(address.ip(), active_connections).is_some() => limited,
+            _ => Admission::Accept,
+        }
+    })
+}
+
+fn get_handler(
+    args: Args,
+    router: Arc<Router>,
+    rdns: Arc<Option<ReverseDns>>,
+    access: Arc<AccessLists>,
+) -> Result<Handler> {
+    let favicons = match &args.icon_file {
+        Some(path) => {
+            let favicons = FaviconSet::load(Path::new(path), args.convert_icon)?;
+            log::info!("Loaded {} server icon(s) from {}", favicons.len(), path);
+            Some(favicons)
+        }
+        None => None,
+    };
+
+    let slowdown = Tarpit {
+        byte_interval: Duration::from_millis(args.tarpit_byte_interval),
+        pong_delay: Duration::from_secs(args.tarpit_pong_delay),
+        hold: Duration::from_secs(args.tarpit_duration),
+        keep_alive_interval: Duration::from_secs(args.tarpit_keep_alive),
+    };
+
+    Ok(Arc::new(move |request: &Request| {
+        if let Some(rdns) = rdns.as_ref() {
+            rdns.prefetch(request.remote_address.ip());
+        }
+        let route = router.route(request.request_type.server_address());
+        let response = match request.request_type {
+            RequestType::Join(_) => match route.join_action {
+                JoinAction::Kick => Response::Kick(route.kick_message.clone()),
+                JoinAction::Drop => Response::Drop,
             },
-            description: Description {
-                text: args.motd.clone(),
+            _ => Response::Status(
+                router
+                    .persona(&route.persona)
+                    .response(&route.persona, favicons.as_ref()),
+            ),
+        };
+        if access.matching(request.remote_address.ip()) == Some(ListKind::Tarpit) {
+            Response::Tarpit(Box::new(response), slowdown)
+        } else {
+            response
+        }
+    }))
+}
+
+fn get_fingerprints(args: &Args) -> Result<FingerprintRules> {
+    let fingerprints = match &args.fingerprint_rules {
+        Some(path) => FingerprintRules::load(Path::new(path))?,
+        None => FingerprintRules::default(),
+    };
+    log::info!("Loaded {} fingerprint signature(s)", fingerprints.len());
+    Ok(fingerprints)
+}
+
+fn get_enrichment(
+    args: &Args,
+    router: Arc<Router>,
+    rdns: Arc<Option<ReverseDns>>,
+    access: Arc<AccessLists>,
+) -> Result<Enrichment> {
+    let profiles = if args.lookup_profiles {
+        Some(ProfileLookup::new(ReputationConfig {
+            api_url: args.profile_api_url.clone(),
+            session_url: args.session_api_url.clone(),
+            cache_file: args.profile_cache.as_ref().map(PathBuf::from),
+            cache_ttl: chrono::Duration::hours(args.profile_cache_ttl),
+            lookups_per_minute: args.profile_lookups_per_minute,
+        })?)
+    } else {
+        None
+    };
+
+    let hosting = match &args.hosting_patterns {
+        Some(path) => HostingClassifier::load(Path::new(path))?,
+        None => HostingClassifier::default(),
+    };
+
+    let enrichment = Enrichment {
+        tarpit_fingerprints: args.tarpit_fingerprint.clone(),
+        profiles,
+        geoip: GeoIp::new(
+            args.geoip_city_db.as_ref().map(PathBuf::from),
+            args.geoip_asn_db.as_ref().map(PathBuf::from),
+        ),
+        rdns,
+        hosting,
+        sensor: args.collector_url.as_ref().map(|_| sensor_id(args)),
+        ..Enrichment::new(router, access, get_fingerprints(args)?)
+    };
+    Ok(enrichment)
+}
+
+fn start_dashboard(args: &Args, router: Arc<Router>) -> Result<Option<Arc<EventStore>>> {
+    let Some(listen) = args.dashboard_listen else {
+        return Ok(None);
+    };
+    let store = Arc::new(EventStore::new(args.dashboard_history));
+    if let Some(path) = &args.dashboard_events_file {
+        match store.load(Path::new(path)) {
+            Ok(loaded) => log::info!("Loaded {} event(s) from {} into the dashboard", loaded, path),
+            Err(e) => log::warn!("Unable to load events from {}: {}", path, e),
+        }
+    }
+    Dashboard::start(
+        DashboardConfig {
+            listen,
+            password: args.dashboard_password.clone(),
+        },
+        store.clone(),
+        router,
+    )?;
+    Ok(Some(store))
+}
+
+fn get_pipeline(
+    args: &Args,
+    enrichment: Enrichment,
+    store: Option<Arc<EventStore>>,
+    delivery: DeliveryConfig,
+) -> Result<Pipeline> {
+    let mut builder = Pipeline::builder(enrichment).observer(Box::new(EventLog));
+    if let Some(store) = store {
+        builder = builder.observer(Box::new(store));
+    }
+    if let Some(url) = &args.collector_url {
+        let token = args
+            .collector_token
+            .clone()
+            .ok_or_else(|| eyre!("--collector-url requires --collector-token"))?;
+        let id = sensor_id(args);
+        log::info!("Forwarding events to the collector at {} as sensor \"{}\"", url, id);
+        let sensor = SensorSink::new(
+            SensorConfig {
+                collector_url: url.clone(),
+                id,
+                token,
+                ca_file: args.collector_ca.as_ref().map(PathBuf::from),
+                buffer: args.sensor_buffer,
             },
-            favicon: favicon.clone(),
-            enforces_secure_chat: true,
-            previews_chat: true,
+            delivery.stats.clone(),
+        )?;
+        builder = builder.observer(Box::new(sensor));
+    }
+    if let Some(path) = &args.sinks_file {
+        builder = builder.sinks(load_sinks(Path::new(path), &delivery)?);
+    }
+    if let Some(url) = args.webhook_url.clone() {
+        let sink = BufferedWebhookClient::new(url, args.webhook_digest, delivery);
+        builder = builder.sink(NamedSink::new(WEBHOOK_SINK_NAME.to_string(), sink, SinkOptions::default()));
+    }
+    if let Some(path) = &args.rules_file {
+        let rules = AlertRules::load(Path::new(path), &builder.sink_names())?;
+        log::info!("Loaded {} alert rule(s)", rules.len());
+        builder = builder.rules(rules);
+    }
+    if args.visit_window > 0 {
+        builder = builder.visits(chrono::Duration::seconds(args.visit_window as i64));
+    }
+    if !args.report.is_empty() {
+        let at = chrono::NaiveTime::parse_from_str(&args.report_time, "%H:%M")
+            .map_err(|e| eyre!("Invalid report time \"{}\": {}", args.report_time, e))?;
+        builder = builder.reports(&args.report, at, &args.report_sink);
+    }
+    builder.build()
+}
+
+fn sensor_id(args: &Args) -> String {
+    args.sensor_id
+        .clone()
+        .or_else(|| {
+            fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()
+                .map(|h| h.trim().to_string())
+        })
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| String::from("mc-honeypot"))
+}
+
+/// Logs every event and report
+struct EventLog;
+
+impl EventSink for EventLog {
+    fn send(&self, event: &Event) {
+        log_event(event);
+    }
+
+    fn report(&self, report: &Report) {
+        log::info!("{} report: {}", report.period, report.summary());
+    }
+}
+
+fn log_event(event: &Event) {
+    let connection = match &event.kind {
+        EventKind::Connection(connection) => connection,
+        EventKind::Visit(visit) => {
+            log::info!(
+                "[{}] Visit ended after {}s: {} connection(s), usernames {:?}, protocol versions {:?}{}{}{}",
+                visit.source,
+                visit.duration().num_seconds(),
+                visit.connections,
+                visit.usernames,
+                visit.protocol_versions,
+                event.geo_suffix(),
+                event.tag_suffix(),
+                event.sensor_suffix()
+            );
+            return;
         }
-    })
+    };
+    match &connection.request {
+        RequestType::Join(req) => {
+            let account = match &connection.reputation {
+                Some(r) if r.profile.exists => format!(
+                    ", premium account {}{}",
+                    r.profile.canonical_name.as_deref().unwrap_or_default(),
+                    if r.uuid_matches { "" } else { " with another uuid" }
+                ),
+                Some(_) => String::from(", no premium account"),
+                None => String::new(),
+            };
+            log::info!(
+                "[{}] {} ({}, {:?} uuid{}{}) tried joining the server{}{}{}{}",
+                connection.remote_address,
+                req.player.name,
+                req.player.id,
+                req.analysis.uuid_kind,
+                if req.analysis.valid_username { "" } else { ", invalid username" },
+                account,
+                event.fingerprint_suffix(),
+                event.geo_suffix(),
+                event.tag_suffix(),
+                event.sensor_suffix()
+            );
+        }
+        RequestType::LegacyPing(req) => {
+            log::info!(
+                "[{}] Received Legacy Ping Request [{:?}]{}{}{}{}",
+                connection.remote_address,
+                req,
+                event.fingerprint_suffix(),
+                event.geo_suffix(),
+                event.tag_suffix(),
+                event.sensor_suffix()
+            )
+        }
+        RequestType::ModernPing(req) => {
+            log::info!(
+                "[{}] Received Ping Request [{:?}]{}{}{}{}",
+                connection.remote_address,
+                req,
+                event.fingerprint_suffix(),
+                event.geo_suffix(),
+                event.tag_suffix(),
+                event.sensor_suffix()
+            )
+        }
+    };
+}
+
+fn log_stats(
+    access: &AccessLists,
+    limiter: Option<&RateLimiter>,
+    delivery: Option<&DeliveryStats>,
+    pipeline: &Pipeline,
+) {
+    if !access.is_empty() {
+        let hits = access
+            .hits()
+            .iter()
+            .map(|(kind, path, hits)| format!("{} {} ({})", hits, kind.name(), path.display()))
+            .chain(
+                access
+                    .runtime_hits()
+                    .iter()
+                    .filter(|(_, inserted, marked)| inserted + marked > 0)
+                    .map(|(kind, inserted, marked)| {
+                        format!("{} {} (inserted), {} {} (marked)", inserted, kind.name(), marked, kind.name())
+                    }),
+            )
+            .collect::<Vec<String>>();
+        log::info!("Access list hits: {}", hits.join(", "));
+    }
+    if let Some(limiter) = limiter {
+        let report = limiter.take_report();
+        if report.total() > 0 {
+            let top_sources = report
+                .top_sources
+                .iter()
+                .map(|(ip, count)| format!("{} ({})", ip, count))
+                .collect::<Vec<String>>();
+            log::warn!(
+                "Suppressed {} connection(s) exceeding limits ({} by ip, {} by network, {} by connection cap), top sources: {}",
+                report.total(),
+                report.by_source,
+                report.by_network,
+                report.by_connections,
+                top_sources.join(", ")
+            );
+        }
+    }
+    if let Some(delivery) = delivery {
+        log::info!("Sink deliveries: {}", delivery.summary());
+    }
+    let overflows = pipeline.take_overflows();
+    if !overflows.is_empty() {
+        let overflows = overflows
+            .iter()
+            .map(|(name, count)| format!("{} ({})", name, count))
+            .collect::<Vec<String>>();
+        log::warn!("Dropped events for sinks with a full queue: {}", overflows.join(", "));
+    }
 }
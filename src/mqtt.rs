@@ -0,0 +1,189 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use rumqttc::{Client, ConnectionError, MqttOptions, Outgoing, Packet, QoS};
+use serde::Deserialize;
+
+use crate::delivery::{DeliveryConfig, DeliveryStats};
+use crate::event::Event;
+use crate::report::Report;
+use crate::sink::EventSink;
+
+/// Publishes waiting for the broker before further ones are dropped
+const QUEUE_CAPACITY: usize = 1000;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct MqttSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to mqtt-1, mqtt-2, ...
+    pub name: Option<String>,
+    /// `mqtt://host:1883`, or `mqtts://host:8883` for TLS with the system's root certificates
+    pub url: String,
+    /// `{kind}` is replaced with the kind of the event, e.g. `join`, or `report`
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    /// 0 (at most once), 1 (at least once) or 2 (exactly once)
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    /// Defaults to `mc-honeypot-` followed by the process id
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn default_topic() -> String {
+    String::from("mc-honeypot/{kind}")
+}
+
+/// Publishes events and reports as JSON to an MQTT broker, reconnecting in the background
+pub struct MqttSink {
+    client: Client,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    stats: Arc<DeliveryStats>,
+}
+
+impl MqttSink {
+    pub fn new(config: MqttSinkConfig, delivery: DeliveryConfig) -> Result<MqttSink> {
+        let qos = parse_qos(config.qos)?;
+        let client_id = config
+            .client_id
+            .unwrap_or_else(|| format!("mc-honeypot-{}", std::process::id()));
+        let separator = if config.url.contains('?') { '&' } else { '?' };
+        let mut options = MqttOptions::parse_url(format!("{}{}client_id={}", config.url, separator, client_id))
+            .map_err(|e| eyre!("Invalid url \"{}\": {}", config.url, e))?;
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = config.username {
+            options.set_credentials(username, config.password.unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+        let url = config.url.clone();
+        let stats = delivery.stats.clone();
+        std::thread::spawn(move || {
+            let mut failing = false;
+            // Iterating drives the connection and reconnects after errors, it ends once the client is dropped
+            for notification in connection.iter() {
+                match notification {
+                    Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker {}", url);
+                        failing = false;
+                    }
+                    Ok(notification) if confirms_publish(qos, &notification) => {
+                        stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(_) => {}
+                    Err(ConnectionError::RequestsDone) => break,
+                    Err(e) => {
+                        // Only the first of a series of failed attempts is logged
+                        if !failing {
+                            log::warn!("Unable to reach MQTT broker {}: {}, reconnecting", url, e);
+                            failing = true;
+                        }
+                        std::thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            topic: config.topic,
+            qos,
+            retain: config.retain,
+            stats: delivery.stats,
+        })
+    }
+
+    fn publish(&self, kind: &str, payload: serde_json::Result<String>) {
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Unable to serialize {}: {}", kind, e);
+                return;
+            }
+        };
+        let topic = topic(&self.topic, kind);
+        if let Err(e) = self.client.try_publish(topic, self.qos, self.retain, payload) {
+            log::error!("Unable to publish to MQTT: {}", e);
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn parse_qos(qos: u8) -> Result<QoS> {
+    match qos {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        other => Err(eyre!("Invalid qos {}, expected 0, 1 or 2", other)),
+    }
+}
+
+/// Publishes with QoS 0 are delivered once they are sent, others once the broker acknowledged them
+fn confirms_publish(qos: QoS, notification: &rumqttc::Event) -> bool {
+    matches!(
+        (qos, notification),
+        (QoS::AtMostOnce, rumqttc::Event::Outgoing(Outgoing::Publish(_)))
+            | (QoS::AtLeastOnce, rumqttc::Event::Incoming(Packet::PubAck(_)))
+            | (QoS::ExactlyOnce, rumqttc::Event::Incoming(Packet::PubComp(_)))
+    )
+}
+
+fn topic(template: &str, kind: &str) -> String {
+    template.replace("{kind}", kind)
+}
+
+impl EventSink for MqttSink {
+    fn send(&self, event: &Event) {
+        self.publish(event.name(), serde_json::to_string(event));
+    }
+
+    fn report(&self, report: &Report) {
+        self.publish("report", serde_json::to_string(report));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_qos_levels() {
+        assert_eq!(parse_qos(0).unwrap(), QoS::AtMostOnce);
+        assert_eq!(parse_qos(1).unwrap(), QoS::AtLeastOnce);
+        assert_eq!(parse_qos(2).unwrap(), QoS::ExactlyOnce);
+        assert!(parse_qos(3).is_err());
+    }
+
+    #[test]
+    fn substitutes_the_kind_in_topics() {
+        assert_eq!(topic(&default_topic(), "join"), "mc-honeypot/join");
+        assert_eq!(topic("honeypot/{kind}/{kind}", "report"), "honeypot/report/report");
+        assert_eq!(topic("honeypot/events", "ping"), "honeypot/events");
+    }
+
+    #[test]
+    fn counts_publishes_once_the_broker_confirmed_them() {
+        use rumqttc::{PubAck, PubComp, PubRec};
+
+        let sent = rumqttc::Event::Outgoing(Outgoing::Publish(1));
+        let acknowledged = rumqttc::Event::Incoming(Packet::PubAck(PubAck::new(1)));
+        let received = rumqttc::Event::Incoming(Packet::PubRec(PubRec::new(1)));
+        let completed = rumqttc::Event::Incoming(Packet::PubComp(PubComp::new(1)));
+
+        assert!(confirms_publish(QoS::AtMostOnce, &sent));
+        assert!(!confirms_publish(QoS::AtLeastOnce, &sent));
+        assert!(confirms_publish(QoS::AtLeastOnce, &acknowledged));
+        assert!(!confirms_publish(QoS::ExactlyOnce, &sent));
+        assert!(!confirms_publish(QoS::ExactlyOnce, &received));
+        assert!(confirms_publish(QoS::ExactlyOnce, &completed));
+    }
+}
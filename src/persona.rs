@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+use crate::favicon::FaviconSet;
+use crate::types::{Description, Players, SamplePlayer, ServerListPingResponse, Version};
+
+/// The way the honeypot presents itself in the server list
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Persona {
+    pub version_string: String,
+    pub protocol_version: i32,
+    pub max_players: i32,
+    /// Defaults to the number of sample players if not provided
+    pub online_players: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_players")]
+    pub players: Vec<SamplePlayer>,
+    pub motd: String,
+}
+
+impl Persona {
+    /// Builds the status response, picking the icon named after `name` from `favicons` if there is one
+    pub fn response(&self, name: &str, favicons: Option<&FaviconSet>) -> ServerListPingResponse {
+        ServerListPingResponse {
+            version: Version {
+                name: self.version_string.clone(),
+                protocol: self.protocol_version,
+            },
+            players: Players {
+                sample: self.players.clone(),
+                max: self.max_players,
+                online: self
+                    .online_players
+                    .unwrap_or(self.players.len() as i32),
+            },
+            description: Description {
+                text: self.motd.clone(),
+            },
+            favicon: favicons.map(|f| f.get(Some(name))),
+            enforces_secure_chat: true,
+            previews_chat: true,
+        }
+    }
+}
+
+/// Parses players given as "NAME:UUID", skipping (and logging) malformed entries
+pub fn parse_players(players: &[String]) -> Vec<SamplePlayer> {
+    let mut parsed: Vec<SamplePlayer> = Vec::new();
+    for p in players.iter() {
+        match p.split_once(':') {
+            Some(sp) => parsed.push(SamplePlayer {
+                name: sp.0.to_string(),
+                id: sp.1.to_string(),
+            }),
+            None => log::warn!(
+                "Unable to split \"{}\", check you are using a \":\" to split the name & UUID",
+                p
+            ),
+        }
+    }
+    parsed
+}
+
+fn deserialize_players<'de, D>(deserializer: D) -> Result<Vec<SamplePlayer>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let players: Vec<String> = Vec::deserialize(deserializer)?;
+    Ok(parse_players(&players))
+}
@@ -0,0 +1,670 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::access::{AccessLists, ListKind};
+use crate::event::{Event, EventKind};
+use crate::fingerprint::FingerprintRules;
+use crate::geoip::GeoIp;
+use crate::hosting::HostingClassifier;
+use crate::rdns::ReverseDns;
+use crate::report::{Report, ReportPeriod, ReportScheduler};
+use crate::reputation::ProfileLookup;
+use crate::routing::{JoinAction, Router};
+use crate::rules::AlertRules;
+use crate::session::SessionTracker;
+use crate::sink::{Backpressure, EventSink, NamedSink, SinkOptions};
+use crate::types::{Connection, RequestType};
+
+pub const KNOWN_BENIGN_TAG: &str = "known benign";
+pub const TARPIT_TAG: &str = "tarpit";
+/// How long sources are tarpitted after matching one of the tarpit fingerprints
+pub const TARPIT_MARK_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Everything used to turn a connection into an event
+pub struct Enrichment {
+    pub router: Arc<Router>,
+    pub access: Arc<AccessLists>,
+    pub fingerprints: FingerprintRules,
+    /// Sources are tarpitted once one of their connections matches a fingerprint with one of these labels
+    pub tarpit_fingerprints: Vec<String>,
+    /// Only looked up for join attempts
+    pub profiles: Option<ProfileLookup>,
+    pub geoip: GeoIp,
+    pub rdns: Arc<Option<ReverseDns>>,
+    pub hosting: HostingClassifier,
+    /// Set as the sensor of every event in sensor mode
+    pub sensor: Option<String>,
+}
+
+impl Enrichment {
+    /// Enriches events with the route and fingerprint only, the other lookups are off
+    pub fn new(router: Arc<Router>, access: Arc<AccessLists>, fingerprints: FingerprintRules) -> Enrichment {
+        Enrichment {
+            router,
+            access,
+            fingerprints,
+            tarpit_fingerprints: vec![],
+            profiles: None,
+            geoip: GeoIp::new(None, None),
+            rdns: Arc::new(None),
+            hosting: HostingClassifier::default(),
+            sensor: None,
+        }
+    }
+
+    /// Builds the event of a connection with the persona, action, fingerprint and reputation
+    pub fn connection(&self, connection: Connection) -> Event {
+        let route = self.router.route(connection.request.request_type.server_address());
+        let source = connection.request.remote_address.ip();
+        let tarpitted = self.access.matching(source) == Some(ListKind::Tarpit);
+        let fingerprint = self.fingerprints.classify(&connection.request, &connection.trace);
+        if self.tarpit_fingerprints.contains(&fingerprint.label) {
+            self.access.mark(source, ListKind::Tarpit, TARPIT_MARK_DURATION);
+        }
+        let mut event = Event::connection(connection);
+        if let EventKind::Connection(c) = &mut event.kind {
+            c.persona = route.persona.clone();
+            c.action = action(&c.request, &route.join_action, tarpitted).to_string();
+            c.fingerprint = Some(fingerprint);
+            if let (Some(profiles), RequestType::Join(req)) = (&self.profiles, &c.request) {
+                c.reputation = profiles.lookup(&req.player.name, &req.player.id);
+            }
+        }
+        event.tags = route.tags.clone();
+        if tarpitted {
+            event.tags.push(TARPIT_TAG.to_string());
+        }
+        event
+    }
+
+    /// Adds what is known about the source of an event: its location, PTR record and the tags derived from them
+    pub fn source(&self, event: &mut Event) {
+        event.sensor.clone_from(&self.sensor);
+        if self.access.matching(event.source) == Some(ListKind::Allow) {
+            event.tags.push(KNOWN_BENIGN_TAG.to_string());
+        }
+        event.geo = self.geoip.lookup(event.source);
+        event.ptr = self.rdns.as_ref().as_ref().and_then(|rdns| rdns.get(event.source));
+        if let Some(tag) = self.hosting.classify(event.ptr.as_deref(), event.geo.as_ref()) {
+            if !event.tags.iter().any(|t| t == tag) {
+                event.tags.push(tag.to_string());
+            }
+        }
+    }
+}
+
+/// What the handler did with a request
+fn action(request: &RequestType, join_action: &JoinAction, tarpitted: bool) -> &'static str {
+    match (request, join_action) {
+        _ if tarpitted => "tarpit",
+        (RequestType::Join(_), JoinAction::Kick) => "kick",
+        (RequestType::Join(_), JoinAction::Drop) => "drop",
+        _ => "status",
+    }
+}
+
+enum Item {
+    Event(Box<Event>),
+    Report(Box<Report>),
+    /// Asks the sink to send what it buffered once the events before it were handed over
+    Flush,
+}
+
+/// The bounded queue in front of a sink, drained by a worker thread of its own
+struct SinkQueue {
+    name: String,
+    sink: Arc<dyn EventSink>,
+    options: SinkOptions,
+    transmitter: SyncSender<Item>,
+    overflowed: AtomicU64,
+    /// The part of `overflowed` that was already logged
+    logged_overflows: AtomicU64,
+}
+
+impl SinkQueue {
+    fn new(named: NamedSink) -> SinkQueue {
+        let sink: Arc<dyn EventSink> = Arc::from(named.sink);
+        let (tx, rx) = sync_channel(named.options.queue_size.max(1));
+        let worker = sink.clone();
+        let batch_size = named.options.batch_size.max(1);
+        std::thread::spawn(move || drain(worker.as_ref(), rx, batch_size));
+
+        SinkQueue {
+            name: named.name,
+            sink,
+            options: named.options,
+            transmitter: tx,
+            overflowed: AtomicU64::new(0),
+            logged_overflows: AtomicU64::new(0),
+        }
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        self.options.wants(event) && self.sink.accepts(event)
+    }
+
+    /// Reports and flushes are rare enough to always wait for room in the queue
+    fn push(&self, item: Item) {
+        let result = if self.options.backpressure == Backpressure::Block || !matches!(item, Item::Event(_)) {
+            self.transmitter.send(item).map_err(|e| TrySendError::Disconnected(e.0))
+        } else {
+            self.transmitter.try_send(item)
+        };
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("The worker of sink {} stopped", self.name);
+            }
+        }
+    }
+}
+
+/// Hands queued events to the sink in batches of up to `batch_size`, reports in between keep their place
+fn drain(sink: &dyn EventSink, receiver: Receiver<Item>, batch_size: usize) {
+    let mut events = Vec::new();
+    while let Ok(item) = receiver.recv() {
+        let mut next = Some(item);
+        while let Some(item) = next.take() {
+            match item {
+                Item::Event(event) => events.push(*event),
+                Item::Report(report) => {
+                    if !events.is_empty() {
+                        sink.send_batch(&std::mem::take(&mut events));
+                    }
+                    sink.report(&report);
+                }
+                Item::Flush => {
+                    if !events.is_empty() {
+                        sink.send_batch(&std::mem::take(&mut events));
+                    }
+                    sink.flush();
+                }
+            }
+            if events.len() < batch_size {
+                next = receiver.try_recv().ok();
+            }
+        }
+        if !events.is_empty() {
+            sink.send_batch(&std::mem::take(&mut events));
+        }
+    }
+}
+
+/// The part of the pipeline shared with the visit tracker and the report scheduler
+struct Dispatch {
+    enrichment: Arc<Enrichment>,
+    queues: Arc<Vec<SinkQueue>>,
+    observers: Arc<Vec<Box<dyn EventSink>>>,
+    rules: Option<AlertRules>,
+    reports: Option<ReportScheduler>,
+}
+
+impl Dispatch {
+    fn publish(&self, mut event: Event) {
+        self.enrichment.source(&mut event);
+        self.deliver(event);
+    }
+
+    fn deliver(&self, event: Event) {
+        if let Some(reports) = &self.reports {
+            reports.record(&event);
+        }
+        match &self.rules {
+            Some(rules) => {
+                for (index, alert) in rules.route(&event) {
+                    let queue = &self.queues[index];
+                    if queue.wants(&event) {
+                        let mut event = event.clone();
+                        event.alert = Some(alert);
+                        queue.push(Item::Event(Box::new(event)));
+                    }
+                }
+            }
+            None => {
+                for queue in self.queues.iter().filter(|q| q.wants(&event)) {
+                    queue.push(Item::Event(Box::new(event.clone())));
+                }
+            }
+        }
+        for observer in self.observers.iter() {
+            observer.send(&event);
+        }
+    }
+}
+
+/// Turns connections into events and fans them out to sinks.
+/// Every sink is fed through a bounded queue by a worker thread of its own, so a slow sink never holds up the others.
+pub struct Pipeline {
+    enrichment: Arc<Enrichment>,
+    sessions: Option<SessionTracker>,
+    dispatch: Arc<Dispatch>,
+    paused: AtomicBool,
+    published: AtomicU64,
+    /// Events dropped because the pipeline was paused
+    skipped: AtomicU64,
+}
+
+/// What the pipeline did since it was started
+#[derive(Serialize)]
+pub struct PipelineCounters {
+    pub published: u64,
+    pub skipped_while_paused: u64,
+    pub paused: bool,
+    /// Events each sink missed because its queue was full
+    pub overflows: Vec<(String, u64)>,
+}
+
+impl Pipeline {
+    pub fn builder(enrichment: Enrichment) -> PipelineBuilder {
+        PipelineBuilder {
+            enrichment,
+            sinks: vec![],
+            observers: vec![],
+            rules: None,
+            visit_window: None,
+            reports: None,
+        }
+    }
+
+    /// Enriches a connection and hands its event to the sinks, meant to be called by the `Reporter`
+    pub fn publish(&self, connection: Connection) {
+        if self.enrichment.access.matching(connection.request.remote_address.ip()) == Some(ListKind::Ignore) {
+            return;
+        }
+        if self.skip() {
+            return;
+        }
+        let event = self.enrichment.connection(connection);
+        if let Some(sessions) = &self.sessions {
+            sessions.track(&event);
+        }
+        self.dispatch.publish(event);
+    }
+
+    /// Hands an event that was enriched elsewhere to the sinks, e.g. one a collector received from a sensor
+    pub fn forward(&self, event: Event) {
+        if self.skip() {
+            return;
+        }
+        self.dispatch.deliver(event);
+    }
+
+    /// Counts the event and returns whether it is dropped because the pipeline is paused
+    fn skip(&self) -> bool {
+        if self.paused.load(Ordering::Relaxed) {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        self.published.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// While paused, connections are still answered but their events are neither logged nor sent anywhere
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::Relaxed) != paused {
+            log::info!("Event logging {}", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    /// Asks every sink to send what it buffered, after the events already queued for it
+    pub fn flush(&self) {
+        for queue in self.dispatch.queues.iter() {
+            queue.push(Item::Flush);
+        }
+        for observer in self.dispatch.observers.iter() {
+            observer.flush();
+        }
+    }
+
+    pub fn counters(&self) -> PipelineCounters {
+        PipelineCounters {
+            published: self.published.load(Ordering::Relaxed),
+            skipped_while_paused: self.skipped.load(Ordering::Relaxed),
+            paused: self.paused.load(Ordering::Relaxed),
+            overflows: self
+                .dispatch
+                .queues
+                .iter()
+                .map(|q| (q.name.clone(), q.overflowed.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    /// How many events each sink missed because its queue was full, since the last call
+    pub fn take_overflows(&self) -> Vec<(String, u64)> {
+        self.dispatch
+            .queues
+            .iter()
+            .map(|q| {
+                let total = q.overflowed.load(Ordering::Relaxed);
+                (q.name.clone(), total - q.logged_overflows.swap(total, Ordering::Relaxed))
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+pub struct PipelineBuilder {
+    enrichment: Enrichment,
+    sinks: Vec<NamedSink>,
+    observers: Vec<Box<dyn EventSink>>,
+    rules: Option<AlertRules>,
+    visit_window: Option<chrono::Duration>,
+    reports: Option<(Vec<ReportPeriod>, NaiveTime, Vec<String>)>,
+}
+
+impl PipelineBuilder {
+    pub fn sink(mut self, sink: NamedSink) -> PipelineBuilder {
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn sinks(mut self, sinks: impl IntoIterator<Item = NamedSink>) -> PipelineBuilder {
+        self.sinks.extend(sinks);
+        self
+    }
+
+    /// The names of the sinks added so far, alert rules refer to sinks by their position in it
+    pub fn sink_names(&self) -> Vec<String> {
+        self.sinks.iter().map(|s| s.name.clone()).collect()
+    }
+
+    /// Observers get every event and report right away on the publishing thread, regardless of alert rules,
+    /// e.g. to log them
+    pub fn observer(mut self, observer: Box<dyn EventSink>) -> PipelineBuilder {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Sends events only to the sinks the rules route them to, see [`PipelineBuilder::sink_names`]
+    pub fn rules(mut self, rules: AlertRules) -> PipelineBuilder {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Publishes a visit event once a source has been idle for `window`
+    pub fn visits(mut self, window: chrono::Duration) -> PipelineBuilder {
+        self.visit_window = Some(window);
+        self
+    }
+
+    /// Sends reports at `at` UTC to the named sinks, or to all of them if `sinks` is empty
+    pub fn reports(mut self, periods: &[ReportPeriod], at: NaiveTime, sinks: &[String]) -> PipelineBuilder {
+        self.reports = (!periods.is_empty()).then(|| (periods.to_vec(), at, sinks.to_vec()));
+        self
+    }
+
+    pub fn build(self) -> Result<Pipeline> {
+        let queues = Arc::new(self.sinks.into_iter().map(SinkQueue::new).collect::<Vec<SinkQueue>>());
+        let observers = Arc::new(self.observers);
+
+        let reports = match self.reports {
+            Some((periods, at, names)) => {
+                let targets = if names.is_empty() {
+                    (0..queues.len()).collect::<Vec<usize>>()
+                } else {
+                    names
+                        .iter()
+                        .map(|name| {
+                            queues
+                                .iter()
+                                .position(|q| &q.name == name)
+                                .ok_or_else(|| eyre!("Unknown report sink \"{}\"", name))
+                        })
+                        .collect::<Result<Vec<usize>>>()?
+                };
+                let queues = queues.clone();
+                let observers = observers.clone();
+                Some(ReportScheduler::new(&periods, at, move |report| {
+                    for observer in observers.iter() {
+                        observer.report(report);
+                    }
+                    for index in &targets {
+                        queues[*index].push(Item::Report(Box::new(report.clone())));
+                    }
+                }))
+            }
+            None => None,
+        };
+
+        let enrichment = Arc::new(self.enrichment);
+        let dispatch = Arc::new(Dispatch {
+            enrichment: enrichment.clone(),
+            queues,
+            observers,
+            rules: self.rules,
+            reports,
+        });
+        let sessions = self.visit_window.map(|window| {
+            let dispatch = dispatch.clone();
+            SessionTracker::new(window, move |visit| dispatch.publish(Event::visit(visit)))
+        });
+
+        Ok(Pipeline {
+            enrichment,
+            sessions,
+            dispatch,
+            paused: AtomicBool::new(false),
+            published: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::Mutex;
+
+    use chrono::Utc;
+
+    use crate::types::{ConnectionTrace, Request, ServerListPingRequest};
+
+    use super::*;
+
+    /// Writes down what it was handed, batches wait for a token while it is gated
+    #[derive(Default)]
+    struct Recorder {
+        log: Arc<Mutex<Vec<String>>>,
+        gate: Option<Mutex<Receiver<()>>>,
+        flushed: Option<Mutex<Sender<()>>>,
+    }
+
+    impl EventSink for Recorder {
+        fn send(&self, event: &Event) {
+            self.send_batch(std::slice::from_ref(event));
+        }
+
+        fn send_batch(&self, events: &[Event]) {
+            if let Some(gate) = &self.gate {
+                let _ = gate.lock().unwrap().recv();
+            }
+            let names = events.iter().map(|e| e.name()).collect::<Vec<&str>>();
+            self.log.lock().unwrap().push(names.join(","));
+        }
+
+        fn report(&self, report: &Report) {
+            self.log.lock().unwrap().push(format!("report {}", report.connections));
+        }
+
+        fn flush(&self) {
+            self.log.lock().unwrap().push(String::from("flush"));
+            if let Some(flushed) = &self.flushed {
+                let _ = flushed.lock().unwrap().send(());
+            }
+        }
+    }
+
+    fn event(request_type: fn(ServerListPingRequest) -> RequestType) -> Event {
+        Event::connection(Connection {
+            request: Request {
+                request_type: request_type(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        })
+    }
+
+    fn report(connections: usize) -> Report {
+        Report {
+            period: "daily",
+            start: Utc::now(),
+            end: Utc::now(),
+            connections,
+            pings: 0,
+            legacy_pings: 0,
+            joins: 0,
+            visits: 0,
+            unique_sources: 0,
+            top_asns: vec![],
+            top_countries: vec![],
+            top_usernames: vec![],
+            new_fingerprints: vec![],
+            protocol_versions: vec![],
+        }
+    }
+
+    /// Drains everything queued up front, so the batches don't depend on timing
+    fn drained(items: Vec<Item>, batch_size: usize) -> Vec<String> {
+        let recorder = Recorder::default();
+        let (tx, rx) = sync_channel(items.len());
+        for item in items {
+            tx.send(item).unwrap();
+        }
+        drop(tx);
+        drain(&recorder, rx, batch_size);
+        let log = recorder.log.lock().unwrap().clone();
+        log
+    }
+
+    fn queue_of(options: SinkOptions, recorder: Recorder) -> SinkQueue {
+        SinkQueue::new(NamedSink::new(String::from("test"), recorder, options))
+    }
+
+    #[test]
+    fn only_queues_the_wanted_kinds() {
+        let options = SinkOptions {
+            kinds: vec![String::from("legacy_ping")],
+            ..SinkOptions::default()
+        };
+        let queue = queue_of(options, Recorder::default());
+        assert!(queue.wants(&event(RequestType::LegacyPing)));
+        assert!(!queue.wants(&event(RequestType::ModernPing)));
+        assert!(queue_of(SinkOptions::default(), Recorder::default()).wants(&event(RequestType::ModernPing)));
+    }
+
+    #[test]
+    fn hands_over_batches_of_up_to_batch_size() {
+        let items = (0..5).map(|_| Item::Event(Box::new(event(RequestType::ModernPing)))).collect();
+        assert_eq!(drained(items, 2), vec!["ping,ping", "ping,ping", "ping"]);
+    }
+
+    #[test]
+    fn keeps_reports_and_flushes_in_order() {
+        let ping = || Item::Event(Box::new(event(RequestType::ModernPing)));
+        let items = vec![
+            ping(),
+            ping(),
+            Item::Report(Box::new(report(2))),
+            ping(),
+            Item::Flush,
+            Item::Event(Box::new(event(RequestType::LegacyPing))),
+        ];
+        assert_eq!(
+            drained(items, 10),
+            vec!["ping,ping", "report 2", "ping", "flush", "legacy_ping"]
+        );
+    }
+
+    /// A queue of one in front of a sink whose batches wait for `release`
+    struct Gated {
+        queue: Arc<SinkQueue>,
+        release: Sender<()>,
+        flushes: Receiver<()>,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Gated {
+        fn new(backpressure: Backpressure) -> Gated {
+            let (release, gate) = channel();
+            let (flushed, flushes) = channel();
+            let recorder = Recorder {
+                log: Arc::default(),
+                gate: Some(Mutex::new(gate)),
+                flushed: Some(Mutex::new(flushed)),
+            };
+            let log = recorder.log.clone();
+            let options = SinkOptions {
+                queue_size: 1,
+                backpressure,
+                batch_size: 1,
+                ..SinkOptions::default()
+            };
+            Gated {
+                queue: Arc::new(queue_of(options, recorder)),
+                release,
+                flushes,
+                log,
+            }
+        }
+
+        /// Lets the sink take `batches` batches and waits until it took everything queued
+        fn finish(&self, batches: usize) {
+            for _ in 0..batches {
+                self.release.send(()).unwrap();
+            }
+            self.queue.push(Item::Flush);
+            self.flushes.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    #[test]
+    fn drops_events_while_the_queue_is_full() {
+        let gated = Gated::new(Backpressure::Drop);
+        for _ in 0..4 {
+            gated.queue.push(Item::Event(Box::new(event(RequestType::ModernPing))));
+        }
+        // At most one event is held by the sink and one waits in the queue
+        let overflowed = gated.queue.overflowed.load(Ordering::Relaxed);
+        assert!(overflowed >= 2);
+        gated.finish(4);
+        let delivered = gated.log.lock().unwrap().iter().filter(|l| *l == "ping").count() as u64;
+        assert_eq!(delivered + overflowed, 4);
+    }
+
+    #[test]
+    fn waits_for_room_in_the_queue_when_blocking() {
+        let gated = Gated::new(Backpressure::Block);
+        let (pushed, done) = channel();
+        let queue = gated.queue.clone();
+        std::thread::spawn(move || {
+            for _ in 0..4 {
+                queue.push(Item::Event(Box::new(event(RequestType::ModernPing))));
+            }
+            pushed.send(()).unwrap();
+        });
+        assert!(done.recv_timeout(Duration::from_millis(200)).is_err());
+        for _ in 0..4 {
+            gated.release.send(()).unwrap();
+        }
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+        gated.finish(0);
+        assert_eq!(gated.queue.overflowed.load(Ordering::Relaxed), 0);
+        assert_eq!(gated.log.lock().unwrap().as_slice(), ["ping", "ping", "ping", "ping", "flush"]);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+
+use crate::utils::{read_bytes, read_int, read_unsigned_short};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Lookups running at the same time
+const WORKERS: usize = 8;
+/// Lookups waiting for a worker, sources beyond that are not looked up
+const MAX_QUEUED: usize = 256;
+/// Expired entries are only removed once the cache grows this large
+const MAX_CACHE_ENTRIES: usize = 100_000;
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+
+/// Returns the first nameserver from /etc/resolv.conf
+pub fn system_resolver() -> Option<SocketAddr> {
+    fs::read_to_string("/etc/resolv.conf")
+        .ok()?
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+}
+
+/// Builds the name used for PTR lookups, e.g. `4.3.2.1.in-addr.arpa`
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Sends a single PTR query over UDP and returns the first name in the answer
+pub fn query_ptr(resolver: SocketAddr, ip: IpAddr, timeout: Duration) -> Result<Option<String>> {
+    let id = (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+        ^ std::process::id()) as u16;
+    let mut query: Vec<u8> = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in reverse_name(ip).split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let bind_address = match resolver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_address)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send_to(&query, resolver)?;
+
+    let mut buf = [0; 1500];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        if from == resolver && len >= 2 && buf[0..2] == id.to_be_bytes() {
+            return parse_ptr_response(&buf[..len]);
+        }
+    }
+}
+
+fn parse_ptr_response(message: &[u8]) -> Result<Option<String>> {
+    let mut reader = Cursor::new(message);
+    let _id = read_unsigned_short(&mut reader)?;
+    let flags = read_unsigned_short(&mut reader)?;
+    let questions = read_unsigned_short(&mut reader)?;
+    let answers = read_unsigned_short(&mut reader)?;
+    let _authority = read_unsigned_short(&mut reader)?;
+    let _additional = read_unsigned_short(&mut reader)?;
+
+    match flags & 0xf {
+        0 => {}
+        // NXDOMAIN, there is no PTR record
+        3 => return Ok(None),
+        rcode => bail!("Resolver responded with rcode {}", rcode),
+    }
+
+    for _ in 0..questions {
+        read_name(&mut reader, message)?;
+        let _type_and_class = read_int(&mut reader)?;
+    }
+    for _ in 0..answers {
+        read_name(&mut reader, message)?;
+        let record_type = read_unsigned_short(&mut reader)?;
+        let _class = read_unsigned_short(&mut reader)?;
+        let _ttl = read_int(&mut reader)?;
+        let len = read_unsigned_short(&mut reader)?;
+        if record_type == TYPE_PTR {
+            return Ok(Some(read_name(&mut reader, message)?));
+        }
+        read_bytes(&mut reader, len as usize)?;
+    }
+    Ok(None)
+}
+
+/// Reads a possibly compressed domain name
+fn read_name(reader: &mut Cursor<&[u8]>, message: &[u8]) -> Result<String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut position = reader.position() as usize;
+    let mut jumped = false;
+    // Guards against pointer loops
+    for _ in 0..128 {
+        let len = *message
+            .get(position)
+            .ok_or_else(|| eyre!("Truncated name"))? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *message
+                .get(position + 1)
+                .ok_or_else(|| eyre!("Truncated name"))? as usize;
+            if !jumped {
+                reader.set_position(position as u64 + 2);
+            }
+            jumped = true;
+            position = ((len & 0x3f) << 8) | low;
+        } else if len == 0 {
+            if !jumped {
+                reader.set_position(position as u64 + 1);
+            }
+            return Ok(labels.join("."));
+        } else {
+            let label = message
+                .get(position + 1..position + 1 + len)
+                .ok_or_else(|| eyre!("Truncated name"))?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            position += 1 + len;
+        }
+    }
+    bail!("Name contains too many labels")
+}
+
+enum Entry {
+    Pending,
+    Resolved(Option<String>, Instant),
+}
+
+struct Cache {
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+    resolved: Condvar,
+}
+
+/// Resolves PTR records in the background and caches them.
+/// Lookups are started with [`ReverseDns::prefetch`] as soon as a source shows up, so they are usually done once the event is published.
+pub struct ReverseDns {
+    cache: Arc<Cache>,
+    ttl: Duration,
+    transmitter: SyncSender<IpAddr>,
+}
+
+impl ReverseDns {
+    pub fn new(resolver: SocketAddr, ttl: Duration) -> ReverseDns {
+        let cache = Arc::new(Cache {
+            entries: Mutex::new(HashMap::new()),
+            resolved: Condvar::new(),
+        });
+        let (tx, rx) = sync_channel::<IpAddr>(MAX_QUEUED);
+        let rx = Arc::new(Mutex::new(rx));
+
+        // Lookups can take a while, so a slow resolver must not hold up the others
+        for _ in 0..WORKERS {
+            let rx = rx.clone();
+            let cache = cache.clone();
+            std::thread::spawn(move || loop {
+                let Ok(ip) = rx.lock().unwrap().recv() else {
+                    return;
+                };
+                let ptr = match query_ptr(resolver, ip, QUERY_TIMEOUT) {
+                    Ok(ptr) => ptr,
+                    Err(e) => {
+                        log::debug!("Reverse DNS lookup of {} failed: {}", ip, e);
+                        None
+                    }
+                };
+                let mut entries = cache.entries.lock().unwrap();
+                entries.insert(ip, Entry::Resolved(ptr, Instant::now()));
+                cache.resolved.notify_all();
+            });
+        }
+
+        ReverseDns {
+            cache,
+            ttl,
+            transmitter: tx,
+        }
+    }
+
+    /// Starts a lookup unless the address is already cached or being looked up
+    pub fn prefetch(&self, ip: IpAddr) {
+        let mut entries = self.cache.entries.lock().unwrap();
+        match entries.get(&ip) {
+            Some(Entry::Pending) => return,
+            Some(Entry::Resolved(_, at)) if at.elapsed() < self.ttl => return,
+            _ => {}
+        }
+        entries.insert(ip, Entry::Pending);
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, e| !matches!(e, Entry::Resolved(_, at) if at.elapsed() >= ttl));
+        }
+        match self.transmitter.try_send(ip) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::debug!("Skipping reverse DNS lookup of {}, too many lookups are pending", ip);
+                entries.remove(&ip);
+            }
+            Err(e) => {
+                log::error!("Error sending message to Reverse DNS Thread {}", e);
+                entries.remove(&ip);
+            }
+        }
+    }
+
+    /// Returns the PTR record of `ip`, waiting for a pending lookup to finish
+    pub fn get(&self, ip: IpAddr) -> Option<String> {
+        self.prefetch(ip);
+        let entries = self.cache.entries.lock().unwrap();
+        let (entries, _) = self
+            .cache
+            .resolved
+            .wait_timeout_while(entries, QUERY_TIMEOUT, |e| {
+                matches!(e.get(&ip), Some(Entry::Pending))
+            })
+            .unwrap();
+        match entries.get(&ip) {
+            Some(Entry::Resolved(ptr, _)) => ptr.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to the PTR query of 1.2.0.192.in-addr.arpa, the answer points back at the question's name
+    fn response(rcode: u8, answer: Option<&str>) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x81, 0x80 | rcode, 0, 1, 0, answer.is_some() as u8, 0, 0, 0, 0];
+        for label in reverse_name("192.0.2.1".parse().unwrap()).split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        message.extend_from_slice(&[0, 0, 12, 0, 1]);
+        if let Some(name) = answer {
+            let mut rdata = vec![];
+            for label in name.split('.') {
+                rdata.push(label.len() as u8);
+                rdata.extend_from_slice(label.as_bytes());
+            }
+            rdata.push(0);
+            // Compressed pointer to the name at offset 12
+            message.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1, 0, 0, 0x0e, 0x10]);
+            message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            message.extend_from_slice(&rdata);
+        }
+        message
+    }
+
+    #[test]
+    fn builds_reverse_names() {
+        assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192.in-addr.arpa");
+        assert!(reverse_name("2001:db8::1".parse().unwrap()).starts_with("1.0.0.0."));
+        assert!(reverse_name("2001:db8::1".parse().unwrap()).ends_with(".8.b.d.0.1.0.0.2.ip6.arpa"));
+    }
+
+    #[test]
+    fn parses_ptr_records() {
+        let ptr = parse_ptr_response(&response(0, Some("Scanner.Example.com"))).unwrap();
+        assert_eq!(ptr.as_deref(), Some("scanner.example.com"));
+    }
+
+    #[test]
+    fn handles_missing_records() {
+        assert_eq!(parse_ptr_response(&response(0, None)).unwrap(), None);
+        assert_eq!(parse_ptr_response(&response(3, None)).unwrap(), None);
+        assert!(parse_ptr_response(&response(2, None)).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_and_looping_names() {
+        let message = response(0, Some("scanner.example.com"));
+        assert!(parse_ptr_response(&message[..message.len() - 5]).is_err());
+        let mut looping = message.clone();
+        // Points the question's name at itself
+        looping[12] = 0xc0;
+        looping[13] = 12;
+        assert!(parse_ptr_response(&looping).is_err());
+    }
+}
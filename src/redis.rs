@@ -0,0 +1,316 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use redis::{Client, Cmd, Connection, RedisResult, Value};
+use serde::Deserialize;
+
+use crate::delivery::{DeliveryConfig, DeliveryStats};
+use crate::event::Event;
+use crate::report::Report;
+use crate::sink::{BufferedSender, EventSink};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long messages are dropped without trying after the server couldn't be reached, doubling while it stays away
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RedisSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to redis-1, redis-2, ...
+    pub name: Option<String>,
+    /// `redis://[:password@]host:6379[/db]`
+    pub url: String,
+    /// Events are sent with `PUBLISH` to this channel, `{kind}` is replaced with the kind of the event
+    pub channel: Option<String>,
+    /// Events are appended with `XADD` to this stream, `{kind}` is replaced with the kind of the event
+    pub stream: Option<String>,
+    /// Trims the stream to about this many entries
+    pub max_len: Option<usize>,
+}
+
+enum Target {
+    Channel(String),
+    Stream { key: String, max_len: Option<usize> },
+}
+
+/// Publishes events and reports as JSON to a Redis channel or appends them to a stream
+pub struct RedisSink {
+    buffer: BufferedSender<(String, String)>,
+}
+
+impl RedisSink {
+    pub fn new(config: RedisSinkConfig, delivery: DeliveryConfig) -> Result<RedisSink> {
+        let target = match (config.channel, config.stream) {
+            (Some(channel), None) => Target::Channel(channel),
+            (None, Some(key)) => Target::Stream {
+                key,
+                max_len: config.max_len,
+            },
+            _ => return Err(eyre!("Exactly one of channel and stream has to be set")),
+        };
+        let client = Client::open(config.url.as_str()).map_err(|e| eyre!("Invalid url \"{}\": {}", config.url, e))?;
+
+        let mut publisher = Publisher::new(client, delivery.stats);
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(5),
+            1,
+            move |messages: Vec<(String, String)>| {
+                for (kind, payload) in messages {
+                    publisher.deliver(&command(&target, &kind, &payload));
+                }
+            },
+        );
+
+        Ok(RedisSink { buffer })
+    }
+}
+
+fn command(target: &Target, kind: &str, payload: &str) -> Cmd {
+    match target {
+        Target::Channel(channel) => {
+            let mut command = redis::cmd("PUBLISH");
+            command.arg(channel.replace("{kind}", kind)).arg(payload);
+            command
+        }
+        Target::Stream { key, max_len } => {
+            let mut command = redis::cmd("XADD");
+            command.arg(key.replace("{kind}", kind));
+            if let Some(max_len) = max_len {
+                command.arg("MAXLEN").arg("~").arg(*max_len);
+            }
+            command.arg("*").arg("kind").arg(kind).arg("event").arg(payload);
+            command
+        }
+    }
+}
+
+fn connect(client: &Client) -> RedisResult<Connection> {
+    let connection = client.get_connection_with_timeout(TIMEOUT)?;
+    connection.set_read_timeout(Some(TIMEOUT))?;
+    connection.set_write_timeout(Some(TIMEOUT))?;
+    Ok(connection)
+}
+
+/// Sends commands over a single connection, which is opened again once the server closed it
+struct Publisher {
+    client: Client,
+    connection: Option<Connection>,
+    stats: Arc<DeliveryStats>,
+    /// Set once the server couldn't be reached, until then messages are dropped without trying them
+    failing_until: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Publisher {
+    fn new(client: Client, stats: Arc<DeliveryStats>) -> Publisher {
+        Publisher {
+            client,
+            connection: None,
+            stats,
+            failing_until: None,
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    fn deliver(&mut self, command: &Cmd) {
+        if self.failing_until.is_some_and(|until| Instant::now() < until) {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // One retry on a fresh connection covers connections the server closed in the meantime
+        let mut result = Err(redis::RedisError::from((redis::ErrorKind::IoError, "Not connected")));
+        for _ in 0..2 {
+            if self.connection.is_none() {
+                match connect(&self.client) {
+                    Ok(c) => self.connection = Some(c),
+                    Err(e) => {
+                        result = Err(e);
+                        continue;
+                    }
+                }
+            }
+            if let Some(c) = self.connection.as_mut() {
+                result = command.query::<Value>(c);
+            }
+            match &result {
+                Err(e) if is_unreachable(e) => self.connection = None,
+                _ => break,
+            }
+        }
+        match result {
+            Ok(_) => {
+                self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+                if self.failing_until.take().is_some() {
+                    log::info!("Redis sink works again");
+                }
+                self.backoff = INITIAL_BACKOFF;
+            }
+            Err(e) if is_unreachable(&e) => {
+                log::warn!(
+                    "Unable to reach Redis: {}, dropping messages without trying for {}s",
+                    e,
+                    self.backoff.as_secs()
+                );
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                self.failing_until = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => {
+                log::error!("Unable to send to Redis: {}", e);
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn is_unreachable(error: &redis::RedisError) -> bool {
+    error.is_connection_dropped() || error.is_io_error() || error.is_timeout() || error.is_connection_refusal()
+}
+
+impl EventSink for RedisSink {
+    fn send(&self, event: &Event) {
+        match serde_json::to_string(event) {
+            Ok(json) => self.buffer.add((event.name().to_string(), json)),
+            Err(e) => log::error!("Unable to serialize event: {}", e),
+        }
+    }
+
+    fn report(&self, report: &Report) {
+        match serde_json::to_string(report) {
+            Ok(json) => self.buffer.add((String::from("report"), json)),
+            Err(e) => log::error!("Unable to serialize report: {}", e),
+        }
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    use redis::Arg;
+
+    use super::*;
+
+    /// Reads a command as sent by the client, an array of bulk strings
+    fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|read| *read > 0)?;
+        let count = line.trim_end().strip_prefix('*')?.parse::<usize>().ok()?;
+        (0..count)
+            .map(|_| {
+                let mut length = String::new();
+                reader.read_line(&mut length).ok()?;
+                let mut value = String::new();
+                reader.read_line(&mut value).ok()?;
+                Some(value.trim_end().to_string())
+            })
+            .collect()
+    }
+
+    /// Answers one `PUBLISH` per connection and closes it afterwards, returns the url and the published payloads
+    fn closing_server() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let received = published.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while let Some(command) = read_command(&mut reader) {
+                    if command[0] == "PUBLISH" {
+                        received.lock().unwrap().push(command[2].clone());
+                        stream.write_all(b":1\r\n").unwrap();
+                        break;
+                    }
+                    // CLIENT SETINFO when connecting
+                    stream.write_all(b"+OK\r\n").unwrap();
+                }
+            }
+        });
+        (url, published)
+    }
+
+    fn args(command: &Cmd) -> Vec<String> {
+        command
+            .args_iter()
+            .map(|arg| match arg {
+                Arg::Simple(bytes) => String::from_utf8_lossy(bytes).to_string(),
+                Arg::Cursor => String::from("<cursor>"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn publishes_to_the_channel_of_the_kind() {
+        let command = command(&Target::Channel(String::from("honeypot:{kind}")), "join", "{}");
+        assert_eq!(args(&command), vec!["PUBLISH", "honeypot:join", "{}"]);
+    }
+
+    #[test]
+    fn appends_to_streams() {
+        let stream = Target::Stream {
+            key: String::from("honeypot:{kind}"),
+            max_len: None,
+        };
+        assert_eq!(
+            args(&command(&stream, "ping", "{}")),
+            vec!["XADD", "honeypot:ping", "*", "kind", "ping", "event", "{}"]
+        );
+    }
+
+    #[test]
+    fn trims_streams_approximately() {
+        let stream = Target::Stream {
+            key: String::from("honeypot"),
+            max_len: Some(10000),
+        };
+        assert_eq!(
+            args(&command(&stream, "report", "{}")),
+            vec!["XADD", "honeypot", "MAXLEN", "~", "10000", "*", "kind", "report", "event", "{}"]
+        );
+    }
+
+    #[test]
+    fn reconnects_after_the_server_closed_the_connection() {
+        let (url, published) = closing_server();
+        let stats = Arc::new(DeliveryStats::default());
+        let mut publisher = Publisher::new(Client::open(url).unwrap(), stats.clone());
+        let channel = Target::Channel(String::from("honeypot"));
+        publisher.deliver(&command(&channel, "ping", "first"));
+        publisher.deliver(&command(&channel, "ping", "second"));
+
+        assert_eq!(*published.lock().unwrap(), ["first", "second"]);
+        assert_eq!(stats.delivered.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+        assert!(publisher.failing_until.is_none());
+    }
+
+    #[test]
+    fn backs_off_while_the_server_is_unreachable() {
+        // Nothing listens on the port once the listener is dropped
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stats = Arc::new(DeliveryStats::default());
+        let mut publisher = Publisher::new(Client::open(format!("redis://{}", address)).unwrap(), stats.clone());
+        let channel = Target::Channel(String::from("honeypot"));
+        publisher.deliver(&command(&channel, "ping", "first"));
+        assert!(publisher.failing_until.is_some());
+        assert_eq!(publisher.backoff, INITIAL_BACKOFF * 2);
+
+        // Dropped without connecting, which would double the backoff again
+        publisher.deliver(&command(&channel, "ping", "second"));
+        assert_eq!(publisher.backoff, INITIAL_BACKOFF * 2);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.delivered.load(Ordering::Relaxed), 0);
+    }
+}
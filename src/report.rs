@@ -0,0 +1,327 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use clap::ValueEnum;
+use serde::Serialize;
+use timer::{Guard, Timer};
+
+use crate::event::{Event, EventKind};
+use crate::types::RequestType;
+
+/// How many entries the top lists of a report have
+const TOP_ENTRIES: usize = 10;
+/// Upper bound for distinct values counted per period, e.g. usernames
+const MAX_DISTINCT: usize = 100_000;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportPeriod {
+    Daily,
+    /// Sent on Mondays
+    Weekly,
+}
+
+impl ReportPeriod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportPeriod::Daily => "daily",
+            ReportPeriod::Weekly => "weekly",
+        }
+    }
+
+    fn length(&self) -> Duration {
+        match self {
+            ReportPeriod::Daily => Duration::days(1),
+            ReportPeriod::Weekly => Duration::weeks(1),
+        }
+    }
+
+    /// The first time after `now` a report is due
+    fn next(&self, now: DateTime<Utc>, at: NaiveTime) -> DateTime<Utc> {
+        let mut next = now.date_naive().and_time(at).and_utc();
+        if next <= now {
+            next += Duration::days(1);
+        }
+        if *self == ReportPeriod::Weekly {
+            while next.weekday() != Weekday::Mon {
+                next += Duration::days(1);
+            }
+        }
+        next
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Count {
+    pub value: String,
+    pub count: usize,
+}
+
+/// What happened on the honeypot during one period
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename = "report")]
+pub struct Report {
+    pub period: &'static str,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub connections: usize,
+    pub pings: usize,
+    pub legacy_pings: usize,
+    pub joins: usize,
+    pub visits: usize,
+    pub unique_sources: usize,
+    pub top_asns: Vec<Count>,
+    pub top_countries: Vec<Count>,
+    pub top_usernames: Vec<Count>,
+    /// Fingerprints first seen since the honeypot started, as `label #hash`
+    pub new_fingerprints: Vec<String>,
+    /// The most common protocol versions
+    pub protocol_versions: Vec<Count>,
+}
+
+impl Report {
+    /// Formats the numbers for log lines
+    pub fn summary(&self) -> String {
+        format!(
+            "{} connection(s) from {} source(s): {} ping(s), {} legacy ping(s), {} join(s), {} new fingerprint(s)",
+            self.connections,
+            self.unique_sources,
+            self.pings,
+            self.legacy_pings,
+            self.joins,
+            self.new_fingerprints.len()
+        )
+    }
+}
+
+struct Statistics {
+    start: DateTime<Utc>,
+    connections: usize,
+    pings: usize,
+    legacy_pings: usize,
+    joins: usize,
+    visits: usize,
+    sources: HashSet<IpAddr>,
+    asns: HashMap<String, usize>,
+    countries: HashMap<String, usize>,
+    usernames: HashMap<String, usize>,
+    protocol_versions: HashMap<i32, usize>,
+    new_fingerprints: Vec<String>,
+}
+
+impl Statistics {
+    fn new(start: DateTime<Utc>) -> Statistics {
+        Statistics {
+            start,
+            connections: 0,
+            pings: 0,
+            legacy_pings: 0,
+            joins: 0,
+            visits: 0,
+            sources: HashSet::new(),
+            asns: HashMap::new(),
+            countries: HashMap::new(),
+            usernames: HashMap::new(),
+            protocol_versions: HashMap::new(),
+            new_fingerprints: vec![],
+        }
+    }
+
+    fn report(self, period: ReportPeriod, end: DateTime<Utc>) -> Report {
+        Report {
+            period: period.name(),
+            start: self.start,
+            end,
+            connections: self.connections,
+            pings: self.pings,
+            legacy_pings: self.legacy_pings,
+            joins: self.joins,
+            visits: self.visits,
+            unique_sources: self.sources.len(),
+            top_asns: top(self.asns, TOP_ENTRIES),
+            top_countries: top(self.countries, TOP_ENTRIES),
+            top_usernames: top(self.usernames, TOP_ENTRIES),
+            new_fingerprints: self.new_fingerprints,
+            protocol_versions: top(self.protocol_versions, TOP_ENTRIES),
+        }
+    }
+}
+
+fn increment<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: K) {
+    if counts.len() < MAX_DISTINCT || counts.contains_key(&key) {
+        *counts.entry(key).or_default() += 1;
+    }
+}
+
+/// The most common values, ties broken by value
+fn top<K: ToString>(counts: HashMap<K, usize>, limit: usize) -> Vec<Count> {
+    let mut counts = counts
+        .into_iter()
+        .map(|(value, count)| Count {
+            value: value.to_string(),
+            count,
+        })
+        .collect::<Vec<Count>>();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts.truncate(limit);
+    counts
+}
+
+/// Collects statistics about all events and hands a report to `deliver` at the end of every period
+#[allow(unused)]
+pub struct ReportScheduler {
+    timer: Timer,
+    guards: Vec<Guard>,
+    periods: Vec<Arc<Mutex<Statistics>>>,
+    known_fingerprints: Mutex<HashSet<String>>,
+}
+
+impl ReportScheduler {
+    /// Reports are sent at `at` UTC, weekly ones on Mondays
+    pub fn new<F>(periods: &[ReportPeriod], at: NaiveTime, deliver: F) -> ReportScheduler
+    where
+        F: Fn(&Report) + Send + Sync + 'static,
+    {
+        let timer = Timer::new();
+        let deliver = Arc::new(deliver);
+        let now = Utc::now();
+        let mut guards = Vec::new();
+        let mut statistics = Vec::new();
+        for period in periods {
+            let period = *period;
+            let stats = Arc::new(Mutex::new(Statistics::new(now)));
+            let next = period.next(now, at);
+            log::info!("Sending the first {} report at {}", period.name(), next);
+
+            let scheduled = stats.clone();
+            let deliver = deliver.clone();
+            guards.push(timer.schedule(next, Some(period.length()), move || {
+                let end = Utc::now();
+                let finished = std::mem::replace(&mut *scheduled.lock().unwrap(), Statistics::new(end));
+                deliver(&finished.report(period, end));
+            }));
+            statistics.push(stats);
+        }
+
+        ReportScheduler {
+            timer,
+            guards,
+            periods: statistics,
+            known_fingerprints: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn record(&self, event: &Event) {
+        let new_fingerprint = match &event.kind {
+            EventKind::Connection(connection) => connection.fingerprint.as_ref().and_then(|f| {
+                let mut known = self.known_fingerprints.lock().unwrap();
+                (known.len() < MAX_DISTINCT && known.insert(f.hash.clone()))
+                    .then(|| format!("{} #{}", f.label, f.hash))
+            }),
+            EventKind::Visit(_) => None,
+        };
+
+        for stats in &self.periods {
+            let mut stats = stats.lock().unwrap();
+            let connection = match &event.kind {
+                EventKind::Connection(connection) => connection,
+                EventKind::Visit(_) => {
+                    stats.visits += 1;
+                    continue;
+                }
+            };
+            stats.connections += 1;
+            if stats.sources.len() < MAX_DISTINCT {
+                stats.sources.insert(event.source);
+            }
+            let protocol_version = match &connection.request {
+                RequestType::Join(req) => {
+                    stats.joins += 1;
+                    increment(&mut stats.usernames, req.player.name.clone());
+                    req.handshake.protocol_version
+                }
+                RequestType::ModernPing(req) => {
+                    stats.pings += 1;
+                    req.protocol_version
+                }
+                RequestType::LegacyPing(req) => {
+                    stats.legacy_pings += 1;
+                    req.protocol_version
+                }
+            };
+            increment(&mut stats.protocol_versions, protocol_version);
+            if let Some(geo) = &event.geo {
+                match (geo.asn, &geo.organization) {
+                    (Some(asn), Some(org)) => increment(&mut stats.asns, format!("AS{} {}", asn, org)),
+                    (Some(asn), None) => increment(&mut stats.asns, format!("AS{}", asn)),
+                    _ => {}
+                }
+                if let Some(country) = &geo.country {
+                    increment(&mut stats.countries, country.clone());
+                }
+            }
+            if let Some(fingerprint) = &new_fingerprint {
+                stats.new_fingerprints.push(fingerprint.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn time(day: u32, hour: u32) -> DateTime<Utc> {
+        // January 1st 2024 was a Monday
+        Utc.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap()
+    }
+
+    fn at(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn schedules_daily_reports() {
+        assert_eq!(ReportPeriod::Daily.next(time(3, 10), at(12)), time(3, 12));
+        assert_eq!(ReportPeriod::Daily.next(time(3, 10), at(9)), time(4, 9));
+        // A report that is due right now was just sent
+        assert_eq!(ReportPeriod::Daily.next(time(3, 10), at(10)), time(4, 10));
+        assert_eq!(ReportPeriod::Daily.next(time(31, 23), at(0)), Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn schedules_weekly_reports_on_mondays() {
+        assert_eq!(ReportPeriod::Weekly.next(time(1, 10), at(12)), time(1, 12));
+        assert_eq!(ReportPeriod::Weekly.next(time(1, 10), at(9)), time(8, 9));
+        assert_eq!(ReportPeriod::Weekly.next(time(1, 10), at(10)), time(8, 10));
+        assert_eq!(ReportPeriod::Weekly.next(time(3, 10), at(12)), time(8, 12));
+        assert_eq!(ReportPeriod::Weekly.next(time(7, 23), at(0)), time(8, 0));
+    }
+
+    #[test]
+    fn lists_the_most_common_values() {
+        let counts = HashMap::from([("b", 2), ("a", 2), ("c", 5), ("d", 1)]);
+        let top = top(counts, 3)
+            .into_iter()
+            .map(|c| (c.value, c.count))
+            .collect::<Vec<(String, usize)>>();
+        assert_eq!(top, [(String::from("c"), 5), (String::from("a"), 2), (String::from("b"), 2)]);
+    }
+
+    #[test]
+    fn caps_protocol_versions() {
+        let mut stats = Statistics::new(time(1, 0));
+        for version in 0..TOP_ENTRIES as i32 + 5 {
+            increment(&mut stats.protocol_versions, version);
+        }
+        increment(&mut stats.protocol_versions, 767);
+        increment(&mut stats.protocol_versions, 767);
+        let report = stats.report(ReportPeriod::Daily, time(2, 0));
+        assert_eq!(report.protocol_versions.len(), TOP_ENTRIES);
+        assert_eq!(report.protocol_versions[0].value, "767");
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use timer::{Guard, Timer};
+
+use crate::utils::{format_uuid, is_valid_username};
+
+pub const MOJANG_API_URL: &str = "https://api.mojang.com";
+pub const MOJANG_SESSION_URL: &str = "https://sessionserver.mojang.com";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a connection waits for a lookup before it is reported without the profile
+const LOOKUP_WAIT: Duration = Duration::from_millis(500);
+const SAVE_INTERVAL: chrono::Duration = chrono::Duration::seconds(60);
+/// Clients choose the names that are looked up, so the cache drops the oldest profiles beyond this
+const MAX_PROFILES: usize = 10_000;
+
+/// What the profile API knows about a username
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Profile {
+    /// Whether a premium account with this name exists
+    pub exists: bool,
+    pub canonical_name: Option<String>,
+    pub uuid: Option<String>,
+    pub skin_hash: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reputation {
+    #[serde(flatten)]
+    pub profile: Profile,
+    /// Whether the UUID the client sent belongs to the account
+    pub uuid_matches: bool,
+}
+
+#[derive(Deserialize)]
+struct NameResponse {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SessionResponse {
+    #[serde(default)]
+    properties: Vec<SessionProperty>,
+}
+
+#[derive(Deserialize)]
+struct SessionProperty {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Textures {
+    textures: HashMap<String, Texture>,
+}
+
+#[derive(Deserialize)]
+struct Texture {
+    url: String,
+}
+
+pub struct ReputationConfig {
+    /// Base url of a Mojang compatible api, used to resolve names
+    pub api_url: String,
+    /// Base url of a Mojang compatible session server, used to resolve skins
+    pub session_url: String,
+    pub cache_file: Option<PathBuf>,
+    pub cache_ttl: chrono::Duration,
+    pub lookups_per_minute: u32,
+}
+
+struct Cache {
+    profiles: HashMap<String, Profile>,
+    lookups: Vec<Instant>,
+    /// Names the worker is looking up right now
+    pending: HashSet<String>,
+    /// Set when the profiles changed since they were last saved
+    dirty: bool,
+}
+
+struct Shared {
+    config: ReputationConfig,
+    client: reqwest::blocking::Client,
+    cache: Mutex<Cache>,
+    fetched: Condvar,
+}
+
+/// Resolves usernames through the profile api in the background, caching the results on disk.
+/// Once the rate limit is reached, names that are not cached are skipped instead of waiting.
+#[allow(unused)]
+pub struct ProfileLookup {
+    shared: Arc<Shared>,
+    transmitter: Sender<(String, String)>,
+    timer: Timer,
+    guard: Option<Guard>,
+}
+
+impl ProfileLookup {
+    pub fn new(config: ReputationConfig) -> Result<ProfileLookup> {
+        let profiles = match &config.cache_file {
+            Some(path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)?,
+            _ => HashMap::new(),
+        };
+        let mut cache = Cache {
+            profiles,
+            lookups: vec![],
+            pending: HashSet::new(),
+            dirty: false,
+        };
+        cache.evict(config.cache_ttl);
+        let shared = Arc::new(Shared {
+            client: reqwest::blocking::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            config,
+            cache: Mutex::new(cache),
+            fetched: Condvar::new(),
+        });
+        let (tx, rx) = channel::<(String, String)>();
+
+        let worker = shared.clone();
+        std::thread::spawn(move || {
+            for (key, name) in rx {
+                let result = worker.fetch(&name);
+                let mut cache = worker.cache.lock().unwrap();
+                cache.pending.remove(&key);
+                match result {
+                    Ok(profile) => {
+                        cache.profiles.insert(key, profile);
+                        cache.dirty = true;
+                        if cache.profiles.len() > MAX_PROFILES {
+                            cache.evict(worker.config.cache_ttl);
+                        }
+                    }
+                    Err(e) => log::error!("Unable to look up profile of {}: {}", name, e),
+                }
+                worker.fetched.notify_all();
+            }
+        });
+
+        // Writing the whole cache on every lookup would be slow once it grows, so it is saved periodically
+        let timer = Timer::new();
+        let guard = shared.config.cache_file.is_some().then(|| {
+            let saver = shared.clone();
+            timer.schedule_repeating(SAVE_INTERVAL, move || saver.save())
+        });
+
+        Ok(ProfileLookup {
+            shared,
+            transmitter: tx,
+            timer,
+            guard,
+        })
+    }
+
+    /// Returns the cached profile of `name` or waits briefly for it to be fetched.
+    /// A lookup that takes longer is finished in the background, so later joins with the name have it.
+    pub fn lookup(&self, name: &str, uuid: &str) -> Option<Reputation> {
+        if !is_valid_username(name) {
+            return None;
+        }
+        let key = name.to_ascii_lowercase();
+        let ttl = self.shared.config.cache_ttl;
+        let mut cache = self.shared.cache.lock().unwrap();
+        if cache.fresh(&key, ttl).is_none() && !cache.pending.contains(&key) {
+            if !cache.try_acquire(self.shared.config.lookups_per_minute) {
+                log::debug!("Skipping profile lookup of {}, rate limit reached", name);
+                return None;
+            }
+            cache.pending.insert(key.clone());
+            if let Err(e) = self.transmitter.send((key.clone(), name.to_string())) {
+                log::error!("Error sending message to Profile Lookup Thread {}", e);
+                return None;
+            }
+        }
+
+        let (cache, _) = self
+            .shared
+            .fetched
+            .wait_timeout_while(cache, LOOKUP_WAIT, |c| c.pending.contains(&key))
+            .unwrap();
+        let Some(profile) = cache.fresh(&key, ttl) else {
+            if cache.pending.contains(&key) {
+                log::debug!("Profile lookup of {} is still pending", name);
+            }
+            return None;
+        };
+
+        Some(Reputation {
+            uuid_matches: profile
+                .uuid
+                .as_ref()
+                .is_some_and(|id| id.eq_ignore_ascii_case(uuid)),
+            profile,
+        })
+    }
+}
+
+impl Shared {
+    fn fetch(&self, name: &str) -> Result<Profile> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/users/profiles/minecraft/{}",
+                self.config.api_url.trim_end_matches('/'),
+                name
+            ))
+            .send()?;
+        match response.status() {
+            StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => {
+                return Ok(Profile {
+                    exists: false,
+                    canonical_name: None,
+                    uuid: None,
+                    skin_hash: None,
+                    fetched_at: Utc::now(),
+                })
+            }
+            status if !status.is_success() => bail!("Profile api responded with {}", status),
+            _ => {}
+        }
+
+        let account: NameResponse = response.json()?;
+        let uuid = u128::from_str_radix(&account.id.replace('-', ""), 16)
+            .map(format_uuid)
+            .unwrap_or(account.id.clone());
+        let skin_hash = match self.fetch_skin_hash(&account.id) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log::warn!("Unable to look up skin of {}: {}", account.name, e);
+                None
+            }
+        };
+
+        Ok(Profile {
+            exists: true,
+            canonical_name: Some(account.name),
+            uuid: Some(uuid),
+            skin_hash,
+            fetched_at: Utc::now(),
+        })
+    }
+
+    /// The skin hash is the last path segment of the texture url
+    fn fetch_skin_hash(&self, id: &str) -> Result<Option<String>> {
+        let session: SessionResponse = self
+            .client
+            .get(format!(
+                "{}/session/minecraft/profile/{}",
+                self.config.session_url.trim_end_matches('/'),
+                id.replace('-', "")
+            ))
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let Some(property) = session.properties.iter().find(|p| p.name == "textures") else {
+            return Ok(None);
+        };
+        let textures: Textures = serde_json::from_slice(&BASE64_STANDARD.decode(&property.value)?)?;
+        Ok(textures
+            .textures
+            .get("SKIN")
+            .and_then(|skin| skin.url.rsplit('/').next().map(String::from)))
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.config.cache_file else {
+            return;
+        };
+        let json = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.evict(self.config.cache_ttl);
+            if !cache.dirty {
+                return;
+            }
+            cache.dirty = false;
+            serde_json::to_string(&cache.profiles)
+        };
+        let saved = json
+            .map_err(color_eyre::Report::from)
+            .and_then(|json| Ok(fs::write(path, json)?));
+        if let Err(e) = saved {
+            log::error!("Unable to save profile cache to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Cache {
+    fn fresh(&self, key: &str, ttl: chrono::Duration) -> Option<Profile> {
+        self.profiles
+            .get(key)
+            .filter(|profile| Utc::now() - profile.fetched_at < ttl)
+            .cloned()
+    }
+
+    /// Drops expired profiles, and the oldest ones while there are more than `MAX_PROFILES`
+    fn evict(&mut self, ttl: chrono::Duration) {
+        let count = self.profiles.len();
+        let now = Utc::now();
+        self.profiles.retain(|_, profile| now - profile.fetched_at < ttl);
+        if self.profiles.len() > MAX_PROFILES {
+            let mut fetched = self.profiles.values().map(|p| p.fetched_at).collect::<Vec<DateTime<Utc>>>();
+            fetched.sort_unstable();
+            let oldest_kept = fetched[fetched.len() - MAX_PROFILES];
+            self.profiles.retain(|_, profile| profile.fetched_at >= oldest_kept);
+        }
+        if self.profiles.len() != count {
+            self.dirty = true;
+        }
+    }
+
+    fn try_acquire(&mut self, per_minute: u32) -> bool {
+        let now = Instant::now();
+        self.lookups
+            .retain(|t| now.duration_since(*t) < Duration::from_secs(60));
+        if self.lookups.len() >= per_minute as usize {
+            return false;
+        }
+        self.lookups.push(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+
+    const JEB_ID: &str = "853c80ef3c3749fdaa49938b674adae6";
+
+    /// Answers like the profile api and session server, knowing only jeb_. Counts the requests it got.
+    fn stub() -> (String, Arc<AtomicUsize>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let textures = BASE64_STANDARD.encode(
+                    r#"{"textures":{"SKIN":{"url":"http://textures.minecraft.net/texture/1a4af718455d4aab528e7a61f86fa25e6a369d1768dcb13f7df319a713eb810b"}}}"#,
+                );
+                let response = match request.url().to_ascii_lowercase().as_str() {
+                    "/users/profiles/minecraft/jeb_" => {
+                        Response::from_string(format!(r#"{{"id":"{}","name":"jeb_"}}"#, JEB_ID))
+                    }
+                    url if url == format!("/session/minecraft/profile/{}", JEB_ID) => Response::from_string(format!(
+                        r#"{{"id":"{}","name":"jeb_","properties":[{{"name":"textures","value":"{}"}}]}}"#,
+                        JEB_ID, textures
+                    )),
+                    url if url.starts_with("/users/profiles/minecraft/") => {
+                        Response::from_string("").with_status_code(204)
+                    }
+                    _ => Response::from_string("").with_status_code(500),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        (url, requests)
+    }
+
+    fn lookup(url: &str, lookups_per_minute: u32) -> ProfileLookup {
+        ProfileLookup::new(ReputationConfig {
+            api_url: url.to_string(),
+            session_url: url.to_string(),
+            cache_file: None,
+            cache_ttl: chrono::Duration::hours(1),
+            lookups_per_minute,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_existing_accounts() {
+        let (url, requests) = stub();
+        let lookup = lookup(&url, 10);
+        let reputation = lookup.lookup("Jeb_", "853c80ef-3c37-49fd-aa49-938b674adae6").unwrap();
+        assert!(reputation.uuid_matches);
+        assert!(reputation.profile.exists);
+        assert_eq!(reputation.profile.canonical_name.as_deref(), Some("jeb_"));
+        assert_eq!(
+            reputation.profile.skin_hash.as_deref(),
+            Some("1a4af718455d4aab528e7a61f86fa25e6a369d1768dcb13f7df319a713eb810b")
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Cached, also for other spellings of the name
+        let reputation = lookup.lookup("jeb_", "").unwrap();
+        assert!(!reputation.uuid_matches);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reports_unknown_names() {
+        let (url, _) = stub();
+        let reputation = lookup(&url, 10).lookup("nobody_here", "").unwrap();
+        assert!(!reputation.profile.exists);
+        assert!(!reputation.uuid_matches);
+        assert_eq!(reputation.profile.uuid, None);
+    }
+
+    #[test]
+    fn notices_uuids_of_other_accounts() {
+        let (url, _) = stub();
+        let reputation = lookup(&url, 10).lookup("jeb_", "069a79f4-44e9-4726-a5be-fca90e38aaf5").unwrap();
+        assert!(reputation.profile.exists);
+        assert!(!reputation.uuid_matches);
+    }
+
+    #[test]
+    fn skips_lookups_beyond_the_rate_limit() {
+        let (url, requests) = stub();
+        let lookup = lookup(&url, 1);
+        assert!(lookup.lookup("nobody_here", "").is_some());
+        assert!(lookup.lookup("jeb_", "").is_none());
+        assert!(lookup.lookup("not a name!", "").is_none());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // Cached names don't count
+        assert!(lookup.lookup("nobody_here", "").is_some());
+    }
+
+    #[test]
+    fn evicts_expired_and_the_oldest_profiles() {
+        let profile = |age: i64| Profile {
+            exists: false,
+            canonical_name: None,
+            uuid: None,
+            skin_hash: None,
+            fetched_at: Utc::now() - chrono::Duration::seconds(age),
+        };
+        let mut cache = Cache {
+            profiles: (0..MAX_PROFILES + 10)
+                .map(|i| (format!("name{}", i), profile(i as i64)))
+                .collect(),
+            lookups: vec![],
+            pending: HashSet::new(),
+            dirty: false,
+        };
+        cache.profiles.insert(String::from("expired"), profile(2 * 86400));
+        cache.evict(chrono::Duration::days(1));
+        assert!(cache.dirty);
+        assert_eq!(cache.profiles.len(), MAX_PROFILES);
+        assert!(cache.profiles.contains_key("name0"));
+        assert!(!cache.profiles.contains_key(&format!("name{}", MAX_PROFILES)));
+        assert!(!cache.profiles.contains_key("expired"));
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::RwLock;
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::persona::Persona;
+
+pub const DEFAULT_PERSONA: &str = "default";
+
+/// What happens when a client tries to log in
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinAction {
+    /// Send a disconnect message
+    Kick,
+    /// Close the connection without answering
+    #[default]
+    Drop,
+}
+
+pub enum HostPattern {
+    Exact(String),
+    /// `*` matches any sequence of characters, e.g. `*.example.net`
+    Wildcard(Regex),
+    Regex(Regex),
+    /// Matches hostnames that are a raw IPv4 or IPv6 address
+    IpLiteral,
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => exact == host,
+            HostPattern::Wildcard(regex) | HostPattern::Regex(regex) => regex.is_match(host),
+            HostPattern::IpLiteral => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_ok(),
+        }
+    }
+}
+
+pub struct Route {
+    pub pattern: HostPattern,
+    pub persona: String,
+    pub join_action: JoinAction,
+    pub kick_message: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RouteConfig {
+    exact: Option<String>,
+    wildcard: Option<String>,
+    regex: Option<String>,
+    #[serde(default)]
+    ip_literal: bool,
+    persona: Option<String>,
+    #[serde(default)]
+    join: JoinAction,
+    kick_message: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RouterConfig {
+    #[serde(default)]
+    personas: HashMap<String, Persona>,
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+}
+
+/// Chooses a persona and join behaviour based on the hostname sent in the handshake.
+/// Routes are checked in order, the first match wins. Personas can be changed while running, routes can't.
+pub struct Router {
+    personas: RwLock<HashMap<String, Persona>>,
+    routes: Vec<Route>,
+    fallback: Route,
+}
+
+impl Router {
+    pub fn new(default_persona: Persona) -> Router {
+        let mut personas = HashMap::new();
+        personas.insert(DEFAULT_PERSONA.to_string(), default_persona);
+        Router {
+            personas: RwLock::new(personas),
+            routes: vec![],
+            fallback: Route {
+                pattern: HostPattern::Regex(Regex::new("").unwrap()),
+                persona: DEFAULT_PERSONA.to_string(),
+                join_action: JoinAction::Drop,
+                kick_message: String::new(),
+                tags: vec![],
+            },
+        }
+    }
+
+    /// Loads additional personas and routes from a toml file
+    pub fn load(default_persona: Persona, path: &Path) -> Result<Router> {
+        let mut router = Router::new(default_persona);
+        let config: RouterConfig = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| eyre!("Unable to parse {}: {}", path.display(), e))?;
+
+        router.personas.get_mut().unwrap().extend(config.personas);
+        for (i, route) in config.routes.into_iter().enumerate() {
+            let pattern = match (route.exact, route.wildcard, route.regex, route.ip_literal) {
+                (Some(exact), None, None, false) => HostPattern::Exact(normalize_host(&exact)),
+                (None, Some(wildcard), None, false) => {
+                    HostPattern::Wildcard(wildcard_to_regex(&normalize_host(&wildcard))?)
+                }
+                (None, None, Some(regex), false) => HostPattern::Regex(Regex::new(&regex)?),
+                (None, None, None, true) => HostPattern::IpLiteral,
+                _ => bail!(
+                    "Route #{} needs exactly one of exact, wildcard, regex or ip_literal",
+                    i + 1
+                ),
+            };
+            let persona = route.persona.unwrap_or(DEFAULT_PERSONA.to_string());
+            if !router.personas.get_mut().unwrap().contains_key(&persona) {
+                bail!("Route #{} uses unknown persona \"{}\"", i + 1, persona);
+            }
+            router.routes.push(Route {
+                pattern,
+                persona,
+                join_action: route.join,
+                kick_message: route.kick_message.unwrap_or_default(),
+                tags: route.tags,
+            });
+        }
+
+        Ok(router)
+    }
+
+    pub fn route(&self, server_address: &str) -> &Route {
+        let host = normalize_host(server_address);
+        self.routes
+            .iter()
+            .find(|r| r.pattern.matches(&host))
+            .unwrap_or(&self.fallback)
+    }
+
+    /// All personas sorted by name
+    pub fn personas(&self) -> Vec<(String, Persona)> {
+        let mut personas = self
+            .personas
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, persona)| (name.clone(), persona.clone()))
+            .collect::<Vec<(String, Persona)>>();
+        personas.sort_by(|a, b| a.0.cmp(&b.0));
+        personas
+    }
+
+    pub fn persona(&self, name: &str) -> Persona {
+        let personas = self.personas.read().unwrap();
+        personas
+            .get(name)
+            .unwrap_or_else(|| &personas[DEFAULT_PERSONA])
+            .clone()
+    }
+
+    /// Changes a persona in place, new connections are answered with the result
+    pub fn update_persona(&self, name: &str, update: impl FnOnce(&mut Persona)) -> Result<Persona> {
+        let mut personas = self.personas.write().unwrap();
+        let persona = personas
+            .get_mut(name)
+            .ok_or_else(|| eyre!("Unknown persona \"{}\"", name))?;
+        update(persona);
+        Ok(persona.clone())
+    }
+}
+
+/// Lowercases the hostname and strips a port, the trailing dot and anything mods (e.g. Forge) append after a null byte
+pub fn normalize_host(server_address: &str) -> String {
+    let host = server_address.split('\0').next().unwrap_or_default();
+    // Some clients and proxies send `host:port`, while a bare IPv6 address has colons of its own
+    let host = match host.rsplit_once(':') {
+        Some((name, port))
+            if !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (name.ends_with(']') || !name.contains(':')) =>
+        {
+            name
+        }
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Turns a pattern like `*.example.net` into an anchored regex where `*` matches any sequence of characters
+pub(crate) fn wildcard_to_regex(wildcard: &str) -> Result<Regex> {
+    let pattern = wildcard
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join(".*");
+    Ok(Regex::new(&format!("^{}$", pattern))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_hostnames() {
+        assert_eq!(normalize_host("Play.Example.COM"), "play.example.com");
+        assert_eq!(normalize_host("play.example.com."), "play.example.com");
+        assert_eq!(normalize_host("play.example.com:25565"), "play.example.com");
+        assert_eq!(normalize_host("play.example.com.:25565"), "play.example.com");
+        assert_eq!(normalize_host("[2001:db8::1]:25565"), "[2001:db8::1]");
+        assert_eq!(normalize_host("2001:db8::1"), "2001:db8::1");
+        assert_eq!(normalize_host("[2001:db8::1]"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn strips_what_forge_appends() {
+        // Forge for 1.7 to 1.12, 1.13 to 1.17 and 1.18 onwards
+        assert_eq!(normalize_host("play.example.com\0FML\0"), "play.example.com");
+        assert_eq!(normalize_host("play.example.com\0FML2\0"), "play.example.com");
+        assert_eq!(normalize_host("Play.Example.com.\0FML3\0"), "play.example.com");
+        assert_eq!(normalize_host("192.0.2.1\0FML\0"), "192.0.2.1");
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let pattern = HostPattern::Wildcard(wildcard_to_regex("*.example.net").unwrap());
+        assert!(pattern.matches("mc.example.net"));
+        assert!(pattern.matches("a.b.example.net"));
+        assert!(!pattern.matches("example.net"));
+        assert!(!pattern.matches("mc.example.net.evil.com"));
+        // Dots in the pattern are literal
+        assert!(!pattern.matches("mc.examplexnet"));
+    }
+
+    #[test]
+    fn matches_regexes_and_exact_names() {
+        let pattern = HostPattern::Regex(Regex::new("^(mc|play)\\.example\\.org$").unwrap());
+        assert!(pattern.matches("play.example.org"));
+        assert!(!pattern.matches("www.example.org"));
+
+        let pattern = HostPattern::Exact(normalize_host("Play.Example.org."));
+        assert!(pattern.matches(&normalize_host("play.example.org:25565")));
+        assert!(!pattern.matches("mc.play.example.org"));
+    }
+
+    #[test]
+    fn matches_ip_literals() {
+        assert!(HostPattern::IpLiteral.matches("192.0.2.1"));
+        assert!(HostPattern::IpLiteral.matches("2001:db8::1"));
+        assert!(HostPattern::IpLiteral.matches("[2001:db8::1]"));
+        assert!(HostPattern::IpLiteral.matches(&normalize_host("[2001:db8::1]:25565")));
+        assert!(!HostPattern::IpLiteral.matches("play.example.com"));
+        assert!(!HostPattern::IpLiteral.matches("192.0.2.1.nip.io"));
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::event::Event;
+
+/// Upper bound for the values each rule remembers for counts and first seen predicates
+const MAX_TRACKED: usize = 100_000;
+/// Minimum time between two sweeps of the counted keys
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Notice,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Notice => "notice",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// The rule an event matched, handed to the sinks it is routed to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Alert {
+    pub rule: String,
+    pub severity: Severity,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CountConfig {
+    at_least: usize,
+    /// Seconds
+    window: u64,
+    /// The field events are counted by
+    #[serde(default = "default_count_by")]
+    by: String,
+}
+
+fn default_count_by() -> String {
+    String::from("source")
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: String,
+    #[serde(default)]
+    severity: Severity,
+    #[serde(default)]
+    when: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    unless: BTreeMap<String, toml::Value>,
+    count: Option<CountConfig>,
+    first_seen: Option<String>,
+    /// All sinks if not set
+    sinks: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesConfig {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+enum Matcher {
+    Regex(Regex),
+    Equals(Value),
+    Range(Option<f64>, Option<f64>),
+}
+
+impl Matcher {
+    fn parse(value: &toml::Value) -> Result<Matcher> {
+        match value {
+            toml::Value::String(pattern) => Ok(Matcher::Regex(
+                Regex::new(pattern).map_err(|e| eyre!("Invalid pattern \"{}\": {}", pattern, e))?,
+            )),
+            toml::Value::Integer(i) => Ok(Matcher::Equals(Value::from(*i))),
+            toml::Value::Float(f) => Ok(Matcher::Equals(Value::from(*f))),
+            toml::Value::Boolean(b) => Ok(Matcher::Equals(Value::from(*b))),
+            toml::Value::Table(table) => {
+                let bound = |key: &str| -> Result<Option<f64>> {
+                    match table.get(key) {
+                        None => Ok(None),
+                        Some(toml::Value::Integer(i)) => Ok(Some(*i as f64)),
+                        Some(toml::Value::Float(f)) => Ok(Some(*f)),
+                        Some(_) => Err(eyre!("{} has to be a number", key)),
+                    }
+                };
+                if let Some(key) = table.keys().find(|k| *k != "at_least" && *k != "at_most") {
+                    return Err(eyre!("Unknown condition \"{}\", expected at_least or at_most", key));
+                }
+                Ok(Matcher::Range(bound("at_least")?, bound("at_most")?))
+            }
+            _ => Err(eyre!("Conditions have to be a pattern, number, boolean or range")),
+        }
+    }
+
+    /// Arrays match if any of their elements does
+    fn matches(&self, value: &Value) -> bool {
+        if let Value::Array(values) = value {
+            return values.iter().any(|v| self.matches(v));
+        }
+        match self {
+            Matcher::Regex(regex) => match value {
+                Value::String(s) => regex.is_match(s),
+                Value::Number(_) | Value::Bool(_) => regex.is_match(&value.to_string()),
+                _ => false,
+            },
+            Matcher::Equals(expected) => match (expected.as_f64(), value.as_f64()) {
+                (Some(expected), Some(actual)) => expected == actual,
+                _ => expected == value,
+            },
+            Matcher::Range(at_least, at_most) => value.as_f64().is_some_and(|v| {
+                at_least.is_none_or(|min| v >= min) && at_most.is_none_or(|max| v <= max)
+            }),
+        }
+    }
+}
+
+/// Remembers values in insertion order, forgetting the oldest ones beyond `MAX_TRACKED`
+#[derive(Default)]
+struct SeenValues {
+    values: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenValues {
+    /// Returns whether the value is new
+    fn insert(&mut self, value: String) -> bool {
+        if self.values.contains(&value) {
+            return false;
+        }
+        if self.order.len() >= MAX_TRACKED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+        self.values.insert(value.clone());
+        self.order.push_back(value);
+        true
+    }
+}
+
+#[derive(Default)]
+struct Hits {
+    keys: HashMap<String, VecDeque<Instant>>,
+    swept: Option<Instant>,
+}
+
+struct Counter {
+    at_least: usize,
+    window: Duration,
+    by: String,
+    hits: Mutex<Hits>,
+}
+
+impl Counter {
+    /// Returns true for every `at_least`-th hit of the key within the window
+    fn hit(&self, key: String) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        if hits.keys.len() >= MAX_TRACKED && !hits.keys.contains_key(&key) {
+            // Sweeping on every new key would make each event scan all keys
+            if hits.swept.is_none_or(|at| now.duration_since(at) >= SWEEP_INTERVAL) {
+                hits.keys
+                    .retain(|_, h| h.back().is_some_and(|last| now.duration_since(*last) < self.window));
+                hits.swept = Some(now);
+            }
+            if hits.keys.len() >= MAX_TRACKED {
+                return false;
+            }
+        }
+        let times = hits.keys.entry(key).or_default();
+        while times.front().is_some_and(|t| now.duration_since(*t) >= self.window) {
+            times.pop_front();
+        }
+        times.push_back(now);
+        if times.len() >= self.at_least {
+            times.clear();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    severity: Severity,
+    when: Vec<(String, Matcher)>,
+    unless: Vec<(String, Matcher)>,
+    count: Option<Counter>,
+    first_seen: Option<(String, Mutex<SeenValues>)>,
+    /// Indices into the sinks given on load
+    sinks: Vec<usize>,
+}
+
+impl Rule {
+    fn matches(&self, event: &Value) -> bool {
+        let conditions = self
+            .when
+            .iter()
+            .all(|(path, m)| lookup(event, path).is_some_and(|v| m.matches(v)))
+            && !self
+                .unless
+                .iter()
+                .any(|(path, m)| lookup(event, path).is_some_and(|v| m.matches(v)));
+        if !conditions {
+            return false;
+        }
+
+        // Both predicates keep track of every event passing the conditions, so they are always evaluated
+        let first_seen = match &self.first_seen {
+            Some((path, seen)) => match lookup(event, path).and_then(key_of) {
+                Some(key) => seen.lock().unwrap().insert(key),
+                None => false,
+            },
+            None => true,
+        };
+        let count = match &self.count {
+            Some(counter) => match lookup(event, &counter.by).and_then(key_of) {
+                Some(key) => counter.hit(key),
+                None => false,
+            },
+            None => true,
+        };
+        first_seen && count
+    }
+}
+
+/// Decides which sinks an event is delivered to and with which severity.
+/// Every matching rule routes the event to its sinks, a sink gets it once with the highest severity.
+pub struct AlertRules {
+    rules: Vec<Rule>,
+}
+
+impl AlertRules {
+    /// `sinks` are the names of all sinks, which rules refer to
+    pub fn parse(rules: &str, sinks: &[String]) -> Result<AlertRules> {
+        let config: RulesConfig = toml::from_str(rules)?;
+        let mut parsed = Vec::new();
+        for rule in config.rule {
+            let name = rule.name.clone();
+            parsed.push(Self::parse_rule(rule, sinks).map_err(|e| eyre!("Rule \"{}\": {}", name, e))?);
+        }
+        Ok(AlertRules { rules: parsed })
+    }
+
+    fn parse_rule(rule: RuleConfig, sinks: &[String]) -> Result<Rule> {
+        let matchers = |conditions: &BTreeMap<String, toml::Value>| {
+            conditions
+                .iter()
+                .map(|(path, value)| {
+                    Matcher::parse(value)
+                        .map(|m| (path.clone(), m))
+                        .map_err(|e| eyre!("{}: {}", path, e))
+                })
+                .collect::<Result<Vec<(String, Matcher)>>>()
+        };
+        let sinks = match &rule.sinks {
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    sinks
+                        .iter()
+                        .position(|s| s == name)
+                        .ok_or_else(|| eyre!("Unknown sink \"{}\", configured are: {}", name, sinks.join(", ")))
+                })
+                .collect::<Result<Vec<usize>>>()?,
+            None => (0..sinks.len()).collect(),
+        };
+        if let Some(count) = &rule.count {
+            if count.at_least == 0 || count.window == 0 {
+                return Err(eyre!("count needs at_least and window to be above 0"));
+            }
+        }
+
+        Ok(Rule {
+            when: matchers(&rule.when)?,
+            unless: matchers(&rule.unless)?,
+            count: rule.count.map(|c| Counter {
+                at_least: c.at_least,
+                window: Duration::from_secs(c.window),
+                by: c.by,
+                hits: Mutex::new(Hits::default()),
+            }),
+            first_seen: rule.first_seen.map(|path| (path, Mutex::new(SeenValues::default()))),
+            name: rule.name,
+            severity: rule.severity,
+            sinks,
+        })
+    }
+
+    pub fn load(path: &Path, sinks: &[String]) -> Result<AlertRules> {
+        Self::parse(&fs::read_to_string(path)?, sinks)
+            .map_err(|e| eyre!("Unable to parse {}: {}", path.display(), e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the index of every sink the event goes to, with the alert it is delivered with
+    pub fn route(&self, event: &Event) -> Vec<(usize, Alert)> {
+        let value = match serde_json::to_value(event) {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("Unable to evaluate alert rules: {}", e);
+                return vec![];
+            }
+        };
+
+        let mut routes: Vec<(usize, Alert)> = Vec::new();
+        for rule in self.rules.iter().filter(|r| r.matches(&value)) {
+            for sink in &rule.sinks {
+                let alert = Alert {
+                    rule: rule.name.clone(),
+                    severity: rule.severity,
+                };
+                match routes.iter_mut().find(|(s, _)| s == sink) {
+                    Some((_, existing)) if existing.severity < rule.severity => *existing = alert,
+                    Some(_) => {}
+                    None => routes.push((*sink, alert)),
+                }
+            }
+        }
+        routes
+    }
+}
+
+/// Follows a dotted path like `request.player.name` through the JSON form of an event
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| match value {
+        Value::Object(map) => map.get(key),
+        Value::Array(values) => key.parse::<usize>().ok().and_then(|i| values.get(i)),
+        _ => None,
+    })
+}
+
+fn key_of(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn matcher(toml: &str) -> Matcher {
+        Matcher::parse(&toml::from_str::<toml::Table>(&format!("value = {}", toml)).unwrap()["value"]).unwrap()
+    }
+
+    fn sinks() -> Vec<String> {
+        vec![String::from("discord"), String::from("siem")]
+    }
+
+    #[test]
+    fn matches_patterns_numbers_and_ranges() {
+        assert!(matcher("'^bot_'").matches(&json!("bot_123")));
+        assert!(!matcher("'^bot_'").matches(&json!("player")));
+        assert!(matcher("'^76'").matches(&json!(765)));
+        assert!(!matcher("'^bot_'").matches(&Value::Null));
+
+        assert!(matcher("765").matches(&json!(765)));
+        assert!(matcher("765").matches(&json!(765.0)));
+        assert!(!matcher("765").matches(&json!("765")));
+        assert!(matcher("true").matches(&json!(true)));
+
+        let range = matcher("{ at_least = 5, at_most = 10 }");
+        assert!(range.matches(&json!(5)) && range.matches(&json!(10)));
+        assert!(!range.matches(&json!(4.9)) && !range.matches(&json!(11)));
+        assert!(matcher("{ at_least = 5 }").matches(&json!(1000)));
+    }
+
+    #[test]
+    fn matches_any_element_of_arrays() {
+        assert!(matcher("'^tarpit$'").matches(&json!(["known benign", "tarpit"])));
+        assert!(!matcher("'^tarpit$'").matches(&json!([])));
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        let parse = |toml: &str| Matcher::parse(&toml::from_str::<toml::Table>(toml).unwrap()["value"]);
+        assert!(parse("value = '('").is_err());
+        assert!(parse("value = { at_least = 'five' }").is_err());
+        assert!(parse("value = { above = 5 }").is_err());
+        assert!(parse("value = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn looks_up_dotted_paths() {
+        let event = json!({"request": {"player": {"name": "jeb_"}}, "tags": ["a", "b"]});
+        assert_eq!(lookup(&event, "request.player.name"), Some(&json!("jeb_")));
+        assert_eq!(lookup(&event, "tags.1"), Some(&json!("b")));
+        assert_eq!(lookup(&event, "tags.2"), None);
+        assert_eq!(lookup(&event, "request.player.name.first"), None);
+        assert_eq!(lookup(&event, "geo"), None);
+    }
+
+    #[test]
+    fn evaluates_when_and_unless() {
+        let rules = AlertRules::parse(
+            r#"
+            [[rule]]
+            name = "joins"
+            when = { kind = "^join$" }
+            unless = { tags = "^known benign$" }
+            "#,
+            &sinks(),
+        )
+        .unwrap();
+        let rule = &rules.rules[0];
+        assert!(rule.matches(&json!({"kind": "join", "tags": []})));
+        assert!(!rule.matches(&json!({"kind": "join", "tags": ["known benign"]})));
+        assert!(!rule.matches(&json!({"kind": "ping", "tags": []})));
+        assert_eq!(rule.sinks, vec![0, 1]);
+    }
+
+    #[test]
+    fn tracks_first_seen_values_and_counts() {
+        let rules = AlertRules::parse(
+            r#"
+            [[rule]]
+            name = "new name"
+            first_seen = "name"
+
+            [[rule]]
+            name = "scan"
+            count = { at_least = 3, window = 60 }
+            "#,
+            &sinks(),
+        )
+        .unwrap();
+        let first_seen = &rules.rules[0];
+        assert!(first_seen.matches(&json!({"name": "jeb_"})));
+        assert!(!first_seen.matches(&json!({"name": "jeb_"})));
+        assert!(first_seen.matches(&json!({"name": "Notch"})));
+        assert!(!first_seen.matches(&json!({})));
+
+        let count = &rules.rules[1];
+        let event = json!({"source": "192.0.2.1"});
+        let hits = (0..6).map(|_| count.matches(&event)).collect::<Vec<bool>>();
+        assert_eq!(hits, vec![false, false, true, false, false, true]);
+        assert!(!count.matches(&json!({"source": "198.51.100.1"})));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let error = AlertRules::parse("[[rule]]\nname = \"a\"\nsinks = [\"mail\"]", &sinks()).err().unwrap();
+        assert!(error.to_string().contains("Unknown sink \"mail\""));
+        assert!(AlertRules::parse("[[rule]]\nname = \"a\"\ncount = { at_least = 0, window = 60 }", &sinks()).is_err());
+        assert!(AlertRules::parse("[[rule]]\nname = \"a\"\ncolor = \"red\"", &sinks()).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use color_eyre::Result;
+use reqwest::blocking::Client;
+use reqwest::header::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+
+use crate::delivery::DeliveryStats;
+use crate::event::Event;
+use crate::sink::{BufferedSender, EventSink};
+
+/// Path of the collector endpoint sensors post their events to
+pub const EVENTS_PATH: &str = "/api/v1/events";
+/// Events per request to the collector
+const MAX_BATCH: usize = 500;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// An event together with an id that is unique per sensor, so the collector can drop events sent twice
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Envelope {
+    pub id: String,
+    pub event: Event,
+}
+
+/// The body of a request to the collector
+#[derive(Serialize, Deserialize)]
+pub struct EventBatch {
+    pub sensor: String,
+    pub events: Vec<Envelope>,
+}
+
+pub struct SensorConfig {
+    /// Base url of the collector, e.g. `https://collector.example.com:8443`
+    pub collector_url: String,
+    pub id: String,
+    pub token: String,
+    /// PEM file with the certificate of the collector or its CA, for self-signed certificates
+    pub ca_file: Option<PathBuf>,
+    /// Events kept while the collector is unreachable, the oldest are dropped beyond that
+    pub buffer: usize,
+}
+
+/// Forwards every event to a central collector.
+/// Events are kept in memory while the collector is unreachable and sent once it is back.
+pub struct SensorSink {
+    /// Identifies the current run, so ids stay unique across restarts
+    run: i64,
+    sequence: AtomicU64,
+    buffer: BufferedSender<Envelope>,
+    stats: Arc<DeliveryStats>,
+}
+
+impl SensorSink {
+    pub fn new(config: SensorConfig, stats: Arc<DeliveryStats>) -> Result<SensorSink> {
+        let mut client = Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(path) = &config.ca_file {
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&fs::read(path)?)?);
+        }
+        let client = client.build()?;
+        let url = format!("{}{}", config.collector_url.trim_end_matches('/'), EVENTS_PATH);
+
+        let mut forwarder = Forwarder {
+            client,
+            url,
+            sensor: config.id,
+            token: config.token,
+            limit: config.buffer.max(1),
+            backlog: VecDeque::new(),
+            retry_at: None,
+            backoff: MIN_BACKOFF,
+            stats: stats.clone(),
+        };
+        let buffer = BufferedSender::new(chrono::Duration::seconds(2), MAX_BATCH, move |events: Vec<Envelope>| {
+            forwarder.forward(events)
+        });
+
+        Ok(SensorSink {
+            run: Utc::now().timestamp_millis(),
+            sequence: AtomicU64::new(0),
+            buffer,
+            stats,
+        })
+    }
+}
+
+impl EventSink for SensorSink {
+    /// Runs on the connection thread as an observer, so events are dropped rather than waiting for a slow collector
+    fn send(&self, event: &Event) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let added = self.buffer.try_add(Envelope {
+            id: format!("{}-{}", self.run, sequence),
+            event: event.clone(),
+        });
+        if !added {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+struct Forwarder {
+    client: Client,
+    url: String,
+    sensor: String,
+    token: String,
+    limit: usize,
+    backlog: VecDeque<Envelope>,
+    /// Set while the collector is unreachable
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    stats: Arc<DeliveryStats>,
+}
+
+enum Outcome {
+    Delivered,
+    /// The collector will never accept these events
+    Rejected(String),
+    Failed(String),
+}
+
+impl Forwarder {
+    fn forward(&mut self, events: Vec<Envelope>) {
+        self.backlog.extend(events);
+        let overflow = self.backlog.len().saturating_sub(self.limit);
+        if overflow > 0 {
+            self.backlog.drain(..overflow);
+            self.stats.dropped.fetch_add(overflow as u64, Ordering::Relaxed);
+        }
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+
+        while !self.backlog.is_empty() {
+            let count = self.backlog.len().min(MAX_BATCH);
+            match self.send(count) {
+                Outcome::Delivered => {
+                    self.backlog.drain(..count);
+                    self.stats.delivered.fetch_add(count as u64, Ordering::Relaxed);
+                    if self.retry_at.take().is_some() {
+                        log::info!("Collector {} is reachable again", self.url);
+                    }
+                    self.backoff = MIN_BACKOFF;
+                }
+                Outcome::Rejected(reason) => {
+                    log::error!("Collector {} rejected {} event(s): {}", self.url, count, reason);
+                    self.backlog.drain(..count);
+                    self.stats.dropped.fetch_add(count as u64, Ordering::Relaxed);
+                }
+                Outcome::Failed(reason) => {
+                    if self.retry_at.is_none() {
+                        log::warn!("Collector {} is unreachable, buffering events: {}", self.url, reason);
+                    }
+                    self.stats.retried.fetch_add(1, Ordering::Relaxed);
+                    self.retry_at = Some(Instant::now() + self.backoff);
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send(&self, count: usize) -> Outcome {
+        let batch = EventBatch {
+            sensor: self.sensor.clone(),
+            events: self.backlog.iter().take(count).cloned().collect(),
+        };
+        let response = self
+            .client
+            .post(&self.url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.token))
+            .json(&batch)
+            .send();
+        match response {
+            Ok(response) if response.status().is_success() => Outcome::Delivered,
+            // A token the collector doesn't know yet may be added later, so the events are kept
+            Ok(response) if response.status().is_server_error() || matches!(response.status().as_u16(), 401 | 403) => {
+                Outcome::Failed(format!("HTTP {}", response.status()))
+            }
+            Ok(response) => Outcome::Rejected(format!("HTTP {}", response.status())),
+            Err(e) => Outcome::Failed(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Connection, ConnectionTrace, Request, RequestType, ServerListPingRequest};
+
+    use super::*;
+
+    fn ping() -> Event {
+        Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        })
+    }
+
+    fn envelopes(ids: std::ops::Range<usize>) -> Vec<Envelope> {
+        ids.map(|id| Envelope {
+            id: id.to_string(),
+            event: ping(),
+        })
+        .collect()
+    }
+
+    /// A forwarder that is waiting to retry, so it only buffers
+    fn backing_off(limit: usize) -> Forwarder {
+        Forwarder {
+            client: Client::new(),
+            url: format!("http://127.0.0.1:9{}", EVENTS_PATH),
+            sensor: String::from("test"),
+            token: String::from("token"),
+            limit,
+            backlog: VecDeque::new(),
+            retry_at: Some(Instant::now() + MAX_BACKOFF),
+            backoff: MAX_BACKOFF,
+            stats: Arc::new(DeliveryStats::default()),
+        }
+    }
+
+    #[test]
+    fn buffers_while_backing_off() {
+        let mut forwarder = backing_off(10);
+        forwarder.forward(envelopes(0..4));
+        forwarder.forward(envelopes(4..6));
+        assert_eq!(forwarder.backlog.len(), 6);
+        assert_eq!(forwarder.stats.dropped.load(Ordering::Relaxed), 0);
+        assert_eq!(forwarder.stats.delivered.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn drops_the_oldest_events_beyond_the_limit() {
+        let mut forwarder = backing_off(3);
+        forwarder.forward(envelopes(0..2));
+        forwarder.forward(envelopes(2..7));
+        assert_eq!(forwarder.stats.dropped.load(Ordering::Relaxed), 4);
+        let ids = forwarder.backlog.iter().map(|e| e.id.as_str()).collect::<Vec<&str>>();
+        assert_eq!(ids, vec!["4", "5", "6"]);
+    }
+
+    #[test]
+    fn backs_off_once_the_collector_is_unreachable() {
+        let mut forwarder = backing_off(10);
+        forwarder.retry_at = None;
+        forwarder.backoff = MIN_BACKOFF;
+        forwarder.forward(envelopes(0..2));
+        assert_eq!(forwarder.backlog.len(), 2);
+        assert_eq!(forwarder.stats.retried.load(Ordering::Relaxed), 1);
+        assert!(forwarder.retry_at.is_some());
+        assert_eq!(forwarder.backoff, MIN_BACKOFF * 2);
+    }
+}
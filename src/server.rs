@@ -1,28 +1,129 @@
-use std::intrinsics::write_bytes;
-use std::io::Read;
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream};
+use std::io::Cursor;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::Result;
-use color_eyre::Report;
+use color_eyre::eyre::{bail, Result};
 
+use crate::capture::{CaptureDir, CapturedSession, Direction};
+use crate::connections::ActiveConnections;
 use crate::server::legacy::handle_legacy_ping;
-use crate::types::{Handler, Request, RequestType, SamplePlayer, ServerListPingRequest};
+use crate::server::tarpit::{Step, TarpitScheduler};
+use crate::server::transport::{Capturing, Replayed, Transport};
+use crate::types::{
+    Admission, Connection, ConnectionTrace, Description, Filter, Handler, JoinRequest, PacketKind, PacketRecord, Reporter, Request, RequestType, Response, SamplePlayer, ServerListPingRequest
+};
 use crate::utils::{
-    format_uuid, read_int128, read_long, read_unsigned_short, read_utf8_string, read_varint, write_bytes_to_stream, write_utf8_string, write_varint, write_varint_to_stream
+    analyze_player, format_uuid, read_byte, read_bytes, read_int128, read_long, read_unsigned_short, read_utf8_string, read_varint, write_bytes_to_stream, write_utf8_string, write_varint
 };
 
 pub mod legacy;
+mod tarpit;
+mod transport;
+
+/// Large enough for the signature data sent by 1.19.1 clients
+const MAX_LOGIN_START_LENGTH: i32 = 8192;
+
+/// How long we wait for the ping after sending the status response, this includes a full round trip
+const PING_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Login plugin requests were added in 1.13
+const MIN_LOGIN_PLUGIN_PROTOCOL: i32 = 393;
+
+/// The pause after a failed accept, e.g. while we are out of file descriptors until some connections are closed
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Keeps track of what a client sent, so it can be reported once the connection is closed
+pub(crate) struct ConnectionState {
+    started: Instant,
+    pub trace: ConnectionTrace,
+    pub request: Option<Request>,
+}
+
+impl ConnectionState {
+    fn new() -> ConnectionState {
+        ConnectionState {
+            started: Instant::now(),
+            trace: ConnectionTrace::default(),
+            request: None,
+        }
+    }
+
+    pub fn record(&mut self, kind: PacketKind) {
+        self.trace.packets.push(PacketRecord {
+            kind,
+            offset_ms: self.started.elapsed().as_millis() as u64,
+        });
+    }
+
+    /// Hands the request to the handler and keeps it around for the report
+    pub fn handle(&mut self, handler: &Handler, request: Request) -> Response {
+        let response = handler(&request);
+        self.request = Some(request);
+        response
+    }
+
+    fn finish(mut self, reporter: &Reporter) {
+        self.trace.duration_ms = self.started.elapsed().as_millis() as u64;
+        if let Some(request) = self.request {
+            reporter(Connection {
+                request,
+                trace: self.trace,
+            });
+        }
+    }
+}
 
 pub struct HoneypotServer {
     port: u16,
+    filter: Filter,
     handler: Handler,
+    reporter: Reporter,
+    /// Directory the raw traffic of every reported connection is recorded into
+    capture_dir: Option<Arc<CaptureDir>>,
+    active: Arc<ActiveConnections>,
 }
 
 impl HoneypotServer {
-    pub fn new(port: u16, handler: Handler) -> Self {
-        Self { port, handler }
+    pub fn new(
+        port: u16,
+        filter: Filter,
+        handler: Handler,
+        reporter: Reporter,
+        capture_dir: Option<PathBuf>,
+        active: Arc<ActiveConnections>,
+    ) -> Self {
+        Self {
+            port,
+            filter,
+            handler,
+            reporter,
+            capture_dir: capture_dir.map(|path| Arc::new(CaptureDir::new(path))),
+            active,
+        }
+    }
+
+    /// Feeds the client side of a captured session through the parser and handler again and returns what we answered
+    pub fn replay(session: &CapturedSession, handler: &Handler, reporter: &Reporter) -> Result<Vec<u8>> {
+        // Fails if an error was created before, which already installed the default hook
+        let _ = color_eyre::install();
+
+        let mut stream = Replayed::new(
+            session.bytes(Direction::Inbound),
+            session.header.remote_address,
+            session
+                .header
+                .local_address
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))),
+        );
+        let mut state = ConnectionState::new();
+        let result =
+            Self::handle_server_list_ping(&mut stream, handler, &TarpitScheduler::new(), &mut state);
+        state.finish(reporter);
+        result?;
+        Ok(stream.outbound)
     }
 
     pub fn start(self) -> Result<()> {
@@ -35,29 +136,85 @@ impl HoneypotServer {
 
         log::info!("Started Server on port {}", self.port);
 
+        let tarpit = TarpitScheduler::new();
         for stream in listener.incoming() {
-            Self::handle_connection(stream?, &self.handler);
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Unable to accept connection: {}", e);
+                    std::thread::sleep(ACCEPT_ERROR_DELAY);
+                    continue;
+                }
+            };
+            let Ok(address) = stream.peer_addr() else {
+                continue;
+            };
+            match (self.filter)(&address, self.active.len()) {
+                Admission::Accept => self.handle_connection(stream, address, false, &tarpit),
+                Admission::Silent => self.handle_connection(stream, address, true, &tarpit),
+                Admission::Tarpit(duration) => tarpit.hold(stream, duration),
+                // Dropping the stream closes it
+                Admission::Refuse => {}
+            }
         }
 
         Ok(())
     }
 
-    fn handle_connection(mut stream: TcpStream, handler: &Handler) {
-        let cloned = handler.clone();
+    /// Silent connections are handled as usual, but neither reported, logged nor captured
+    fn handle_connection(&self, mut stream: TcpStream, address: SocketAddr, silent: bool, tarpit: &TarpitScheduler) {
+        let handler = self.handler.clone();
+        let reporter = self.reporter.clone();
+        let tarpit = tarpit.clone();
+        let capture_dir = self.capture_dir.clone().filter(|_| !silent);
+        let active = self.active.register(address, stream.local_addr().ok(), silent);
         std::thread::spawn(move || {
-            if let Err(report) = Self::handle_server_list_ping(&mut stream, cloned) {
-                log::error!("{}", report)
+            let _active = active;
+            let mut state = ConnectionState::new();
+            let capture = capture_dir.and_then(|dir| {
+                stream
+                    .peer_addr()
+                    .and_then(|remote| Ok((remote, stream.local_addr()?)))
+                    .map_err(|e| e.into())
+                    .and_then(|(remote, local)| dir.capture(remote, local))
+                    .inspect_err(|e| log::warn!("Unable to capture connection: {}", e))
+                    .ok()
+                    .flatten()
+            });
+            let result = match capture {
+                Some(capture) => Self::handle_server_list_ping(
+                    &mut Capturing::new(stream, capture),
+                    &handler,
+                    &tarpit,
+                    &mut state,
+                ),
+                None => Self::handle_server_list_ping(&mut stream, &handler, &tarpit, &mut state),
+            };
+            if let Err(report) = result {
+                if silent {
+                    log::debug!("{}", report)
+                } else {
+                    log::error!("{}", report)
+                }
+            }
+            if !silent {
+                state.finish(&reporter);
             }
         });
     }
 
-    fn handle_server_list_ping(stream: &mut TcpStream, handler: Handler) -> Result<()> {
+    fn handle_server_list_ping<T: Transport>(
+        stream: &mut T,
+        handler: &Handler,
+        tarpit: &TarpitScheduler,
+        state: &mut ConnectionState,
+    ) -> Result<()> {
         stream.set_read_timeout(Some(Duration::from_millis(200)))?;
 
         let mut buf: [u8; 1] = [0];
         stream.peek(&mut buf)?;
         if buf[0] == 0xFE {
-            handle_legacy_ping(stream, handler)?;
+            handle_legacy_ping(stream, handler, tarpit, state)?;
             return Ok(());
         }
 
@@ -68,43 +225,89 @@ impl HoneypotServer {
         let server_address = read_utf8_string(stream)?;
         let server_port = read_unsigned_short(stream).expect("Expected Server Port");
         let next_state = read_varint(stream)?;
+        state.record(PacketKind::Handshake);
+        state.trace.next_state = Some(next_state);
+
+        let handshake = ServerListPingRequest {
+            protocol_version,
+            server_address,
+            server_port,
+        };
 
         if next_state == 2 {
-            let _len = read_varint(stream)?;
-            let _packet_id = read_varint(stream)?;
-            let username = read_utf8_string(stream)?;
-            let uuid = read_int128(stream)?;
-
-            stream
-                .shutdown(Shutdown::Both)
-                .expect("Error shutting down stream");
-
-            handler(Request {
-                request_type: RequestType::Join(SamplePlayer {
-                    name: username,
-                    id: format_uuid(uuid),
+            // Serverbound Login Start
+            let len = read_varint(stream)?;
+            if !(0..=MAX_LOGIN_START_LENGTH).contains(&len) {
+                bail!("Login Start packet has an invalid length of {} bytes", len);
+            }
+            let mut packet = Cursor::new(read_bytes(stream, len as usize)?);
+            let _packet_id = read_varint(&mut packet)?;
+            let username = read_utf8_string(&mut packet)?;
+            let uuid = read_login_uuid(&mut packet, protocol_version);
+            state.record(PacketKind::LoginStart);
+
+            let response = state.handle(handler, Request {
+                request_type: RequestType::Join(JoinRequest {
+                    handshake,
+                    analysis: analyze_player(&username, uuid),
+                    player: SamplePlayer {
+                        name: username,
+                        id: uuid.map(format_uuid).unwrap_or_default(),
+                    },
                 }),
-                remote_address: stream.peer_addr().unwrap(),
+                remote_address: stream.peer_addr()?,
+                local_address: stream.local_addr()?,
             });
+
+            match response {
+                Response::Kick(reason) => write_bytes_to_stream(stream, login_disconnect(reason)?),
+                Response::Tarpit(response, slowdown) => {
+                    let keep_alive = (protocol_version >= MIN_LOGIN_PLUGIN_PROTOCOL)
+                        .then_some(slowdown.keep_alive_interval);
+                    let mut steps = vec![Step::Hold(slowdown.hold, keep_alive)];
+                    if let Response::Kick(reason) = *response {
+                        steps.push(Step::Write(login_disconnect(reason)?));
+                    }
+                    // The tarpit works on the socket itself, so a capture of the connection ends here
+                    tarpit.run(stream.try_clone()?, steps);
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            // The client may already have hung up after reading the disconnect message
+            let _ = stream.shutdown(Shutdown::Both);
             return Ok(());
         }
 
         let request = Request {
-            remote_address: stream.peer_addr().unwrap(),
-            request_type: RequestType::ModernPing(ServerListPingRequest {
-                protocol_version,
-                server_address,
-                server_port,
-            }),
+            remote_address: stream.peer_addr()?,
+            local_address: stream.local_addr()?,
+            request_type: RequestType::ModernPing(handshake),
         };
 
-        let response = handler(request);
+        let (response, slowdown) = match state.handle(handler, request) {
+            Response::Status(response) => (response, None),
+            Response::Tarpit(response, slowdown) => match *response {
+                Response::Status(response) => (response, Some(slowdown)),
+                _ => {
+                    tarpit.hold(stream.try_clone()?, slowdown.hold);
+                    return Ok(());
+                }
+            },
+            Response::Kick(_) | Response::Drop => {
+                stream.shutdown(Shutdown::Both)?;
+                return Ok(());
+            }
+        };
         let response_json = serde_json::to_string(&response)?;
 
-        // Serverbound Status Request OR Serverbound Ping Request
+        // Serverbound Status Request
         let _len = read_varint(stream);
-        let _packet_id = read_varint(stream);
-        let payload = read_long(stream).unwrap_or(0);
+        let packet_id = read_varint(stream);
+        if let Ok(0) = packet_id {
+            state.record(PacketKind::StatusRequest);
+        }
 
         // Clientbound Status Response
         let mut resp_buf: Vec<u8> = Vec::new();
@@ -114,17 +317,209 @@ impl HoneypotServer {
         let mut status_buffer: Vec<u8> = Vec::new();
         write_varint(&mut status_buffer, resp_buf.len() as i32);
         status_buffer.append(&mut resp_buf);
+
+        if let Some(slowdown) = slowdown {
+            let mut steps = vec![if slowdown.byte_interval.is_zero() {
+                Step::Write(status_buffer)
+            } else {
+                Step::Dribble(status_buffer, slowdown.byte_interval)
+            }];
+            if let Ok(1) = packet_id {
+                // The ping came first, so only its payload is left to read
+                let payload = read_long(stream)?;
+                state.record(PacketKind::PingRequest);
+                state.trace.ping_payload = Some(payload);
+                steps.push(Step::Pause(slowdown.pong_delay));
+                steps.push(Step::Write(pong(payload)));
+            } else {
+                steps.push(Step::Pong(slowdown.pong_delay));
+            }
+            tarpit.run(stream.try_clone()?, steps);
+            return Ok(());
+        }
         write_bytes_to_stream(stream, status_buffer);
 
-        //Clientbound Ping Response
-        let mut resp_buf: Vec<u8> = Vec::new();
-        write_varint(&mut resp_buf, 1);
-        resp_buf.append(&mut payload.to_be_bytes().to_vec());
-        write_varint_to_stream(stream, resp_buf.len() as i32);
-        write_bytes_to_stream(stream, resp_buf);
+        // Serverbound Ping Request, some clients send it without asking for the status first
+        let payload = if let Ok(1) = packet_id {
+            read_long(stream)
+        } else {
+            stream.set_read_timeout(Some(PING_TIMEOUT))?;
+            let _len = read_varint(stream);
+            read_varint(stream).and_then(|_packet_id| read_long(stream))
+        };
+
+        if let Ok(payload) = payload {
+            state.record(PacketKind::PingRequest);
+            state.trace.ping_payload = Some(payload);
+
+            write_bytes_to_stream(stream, pong(payload));
+        }
 
         stream.shutdown(Shutdown::Both)?;
 
         Ok(())
     }
 }
+
+/// Clientbound Login Disconnect
+fn login_disconnect(reason: String) -> Result<Vec<u8>> {
+    let mut resp_buf: Vec<u8> = Vec::new();
+    write_varint(&mut resp_buf, 0);
+    write_utf8_string(&mut resp_buf, serde_json::to_string(&Description { text: reason })?);
+
+    let mut disconnect_buffer: Vec<u8> = Vec::new();
+    write_varint(&mut disconnect_buffer, resp_buf.len() as i32);
+    disconnect_buffer.append(&mut resp_buf);
+    Ok(disconnect_buffer)
+}
+
+/// Clientbound Ping Response
+fn pong(payload: i64) -> Vec<u8> {
+    let mut resp_buf: Vec<u8> = Vec::new();
+    write_varint(&mut resp_buf, 1);
+    resp_buf.append(&mut payload.to_be_bytes().to_vec());
+
+    let mut pong_buffer: Vec<u8> = Vec::new();
+    write_varint(&mut pong_buffer, resp_buf.len() as i32);
+    pong_buffer.append(&mut resp_buf);
+    pong_buffer
+}
+
+/// Reads the UUID from the rest of a Login Start packet.
+/// Its layout changed a few times, clients before 1.19 don't send one at all.
+fn read_login_uuid(packet: &mut Cursor<Vec<u8>>, protocol_version: i32) -> Option<u128> {
+    let remaining = packet.get_ref().len() - packet.position() as usize;
+    match remaining {
+        // 1.20.2+
+        16 => read_int128(packet).ok(),
+        // 1.19.3 - 1.20.1, prefixed by a "has UUID" boolean
+        17 => match read_byte(packet) {
+            Ok(1) => read_int128(packet).ok(),
+            _ => None,
+        },
+        // 1.19.1 - 1.19.2, the signature data comes before the optional UUID
+        _ if protocol_version == 760 && remaining > 17 => {
+            if read_byte(packet).ok()? == 1 {
+                let _expires_at = read_long(packet).ok()?;
+                let key_len = read_varint(packet).ok()?;
+                skip_bytes(packet, key_len)?;
+                let signature_len = read_varint(packet).ok()?;
+                skip_bytes(packet, signature_len)?;
+            }
+            match read_byte(packet) {
+                Ok(1) => read_int128(packet).ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Moves past a length the client sent, failing if it's negative or beyond the end of the packet
+fn skip_bytes(packet: &mut Cursor<Vec<u8>>, len: i32) -> Option<()> {
+    let end = packet.position().checked_add(u64::try_from(len).ok()?)?;
+    if end > packet.get_ref().len() as u64 {
+        return None;
+    }
+    packet.set_position(end);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Players, ServerListPingResponse, Version};
+
+    /// The part of a 1.19 (protocol 760) Login Start packet after the username
+    fn login_start_760(key_len: i32, signature_len: i32, uuid: u128) -> Cursor<Vec<u8>> {
+        let mut packet = vec![1];
+        packet.extend(0i64.to_be_bytes());
+        write_varint(&mut packet, key_len);
+        packet.extend(vec![0; key_len.max(0) as usize]);
+        write_varint(&mut packet, signature_len);
+        packet.extend(vec![0; signature_len.max(0) as usize]);
+        packet.push(1);
+        packet.extend(uuid.to_be_bytes());
+        Cursor::new(packet)
+    }
+
+    #[test]
+    fn reads_the_uuid_after_the_signature_data() {
+        assert_eq!(read_login_uuid(&mut login_start_760(4, 8, 42), 760), Some(42));
+    }
+
+    #[test]
+    fn rejects_negative_lengths() {
+        assert_eq!(read_login_uuid(&mut login_start_760(-1, 8, 42), 760), None);
+        assert_eq!(read_login_uuid(&mut login_start_760(4, i32::MIN, 42), 760), None);
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_the_packet() {
+        let mut packet = login_start_760(4, 8, 42);
+        // Claims a key longer than the packet
+        packet.get_mut()[9] = 100;
+        assert_eq!(read_login_uuid(&mut packet, 760), None);
+    }
+
+    #[test]
+    fn replays_a_captured_status_request() {
+        let directory = std::env::temp_dir().join(format!(
+            "mc-honeypot-replay-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut handshake = vec![0];
+        write_varint(&mut handshake, 767);
+        write_utf8_string(&mut handshake, String::from("play.example.com"));
+        handshake.extend(25565u16.to_be_bytes());
+        write_varint(&mut handshake, 1);
+        let mut inbound = Vec::new();
+        write_varint(&mut inbound, handshake.len() as i32);
+        inbound.extend(handshake);
+        inbound.extend([1, 0]);
+        let ping = [9, 1, 0, 0, 0, 0, 0, 0, 0, 42];
+        inbound.extend(ping);
+
+        let mut capture = CaptureDir::new(directory.clone())
+            .capture("192.0.2.1:50000".parse().unwrap(), "198.51.100.1:25565".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        capture.record(Direction::Inbound, &inbound);
+        drop(capture);
+        let path = std::fs::read_dir(&directory).unwrap().next().unwrap().unwrap().path();
+        let session = CapturedSession::load(&path).unwrap();
+        assert_eq!(session.bytes(Direction::Inbound), inbound);
+        assert_eq!(session.header.remote_address, "192.0.2.1:50000".parse().unwrap());
+
+        let handler: Handler = Arc::new(|_| {
+            Response::Status(ServerListPingResponse {
+                version: Version { name: String::from("1.21"), protocol: 767 },
+                players: Players { max: 20, online: 0, sample: Vec::new() },
+                description: Description { text: String::from("Replayed") },
+                favicon: None,
+                enforces_secure_chat: false,
+                previews_chat: false,
+            })
+        });
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reporter: Reporter = {
+            let reported = reported.clone();
+            Arc::new(move |connection: Connection| reported.lock().unwrap().push(connection))
+        };
+        let outbound = HoneypotServer::replay(&session, &handler, &reporter).unwrap();
+
+        assert!(String::from_utf8_lossy(&outbound).contains(r#"{"text":"Replayed"}"#));
+        assert!(outbound.ends_with(&ping));
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert!(matches!(
+            &reported[0].request.request_type,
+            RequestType::ModernPing(ServerListPingRequest { protocol_version: 767, server_address, server_port: 25565 })
+                if server_address == "play.example.com"
+        ));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,34 +1,44 @@
-use std::net::{Shutdown, TcpStream};
+use std::net::Shutdown;
 
 use color_eyre::Result;
 
-use crate::types::{Handler, Request, RequestType, ServerListPingRequest};
+use crate::server::tarpit::{Step, TarpitScheduler};
+use crate::server::transport::Transport;
+use crate::server::ConnectionState;
+use crate::types::{Handler, PacketKind, Request, RequestType, Response, ServerListPingRequest};
 use crate::utils::{
     read_byte, read_int, read_unsigned_short, read_utf16_string, write_bytes_to_stream,
 };
 
-pub fn handle_legacy_ping(stream: &mut TcpStream, handler: Handler) -> Result<()> {
+pub(crate) fn handle_legacy_ping<T: Transport>(
+    stream: &mut T,
+    handler: &Handler,
+    tarpit: &TarpitScheduler,
+    state: &mut ConnectionState,
+) -> Result<()> {
     let packet_id = read_byte(stream);
     if packet_id.is_err() {
-        send_response(stream, handler, 0, String::new(), 0)?;
+        send_response(stream, handler, tarpit, state, 0, String::new(), 0)?;
         return Ok(());
     }
+    state.record(PacketKind::LegacyPing);
 
     let payload = read_byte(stream);
     if payload.is_err() {
-        send_response(stream, handler, 0, String::new(), 0)?;
+        send_response(stream, handler, tarpit, state, 0, String::new(), 0)?;
         return Ok(());
     }
+    state.record(PacketKind::LegacyPingPayload);
 
     let packet_id = read_byte(stream);
     if let Err(_e) = packet_id {
-        send_response(stream, handler, 0, String::new(), 0)?;
+        send_response(stream, handler, tarpit, state, 0, String::new(), 0)?;
         return Ok(());
     }
 
     let channel_len = read_unsigned_short(stream);
     if channel_len.is_err() {
-        send_response(stream, handler, 0, String::new(), 0)?;
+        send_response(stream, handler, tarpit, state, 0, String::new(), 0)?;
         return Ok(());
     }
 
@@ -41,28 +51,45 @@ pub fn handle_legacy_ping(stream: &mut TcpStream, handler: Handler) -> Result<()
         .unwrap_or_default();
 
     let port = read_int(stream).unwrap_or(0);
+    state.record(PacketKind::LegacyPluginMessage);
 
-    send_response(stream, handler, protocol_version, hostname, port)?;
+    send_response(stream, handler, tarpit, state, protocol_version, hostname, port)?;
 
     Ok(())
 }
 
-fn send_response(
-    stream: &mut TcpStream,
-    handler: Handler,
+fn send_response<T: Transport>(
+    stream: &mut T,
+    handler: &Handler,
+    tarpit: &TarpitScheduler,
+    state: &mut ConnectionState,
     protocol_version: i32,
     hostname: String,
     port: u32,
 ) -> Result<()> {
     let request = Request {
         remote_address: stream.peer_addr().unwrap(),
+        local_address: stream.local_addr().unwrap(),
         request_type: RequestType::LegacyPing(ServerListPingRequest {
             protocol_version,
             server_address: hostname,
             server_port: port as u16,
         }),
     };
-    let response = handler(request);
+    let (response, slowdown) = match state.handle(handler, request) {
+        Response::Status(response) => (response, None),
+        Response::Tarpit(response, slowdown) => match *response {
+            Response::Status(response) => (response, Some(slowdown)),
+            _ => {
+                tarpit.hold(stream.try_clone()?, slowdown.hold);
+                return Ok(());
+            }
+        },
+        Response::Kick(_) | Response::Drop => {
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(());
+        }
+    };
 
     let resp_string = format!(
         "§1\0{}\0{}\0{}\0{}\0{}",
@@ -91,6 +118,16 @@ fn send_response(
         resp_buf.append(&mut utf16_be.align_to::<u8>().1.to_vec());
     }
 
+    if let Some(slowdown) = slowdown {
+        let step = if slowdown.byte_interval.is_zero() {
+            Step::Write(resp_buf)
+        } else {
+            Step::Dribble(resp_buf, slowdown.byte_interval)
+        };
+        tarpit.run(stream.try_clone()?, vec![step]);
+        return Ok(());
+    }
+
     write_bytes_to_stream(stream, resp_buf);
 
     stream.shutdown(Shutdown::Both)?;
@@ -0,0 +1,300 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::utils::{write_utf8_string, write_varint};
+
+/// Connections beyond this are closed right away, lowered to half of the open file limit where there is one,
+/// so tarpitted connections leave enough file descriptors for accepting and handling the others
+const MAX_TARPIT_CONNECTIONS: usize = 10_000;
+
+/// How often we check for a ping or retry a write the socket wasn't ready for
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often held connections are checked for being closed by the client
+const HOLD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long we wait for the ping once the status response was sent
+const PING_WAIT: Duration = Duration::from_secs(30);
+
+/// A ping request is always 10 bytes long: its length, id and payload. The pong is the same packet echoed back.
+const PING_LENGTH: usize = 10;
+
+/// Something done with a tarpitted connection, all durations start once the step is reached
+pub(crate) enum Step {
+    /// Writes the bytes at once
+    Write(Vec<u8>),
+    /// Writes the bytes one at a time with the given pause in between
+    Dribble(Vec<u8>, Duration),
+    /// Does nothing for the given time
+    Pause(Duration),
+    /// Waits for a ping request and echoes it back after the given delay
+    Pong(Duration),
+    /// Keeps the connection open for the given time, discarding what the client sends.
+    /// If an interval is given, a login plugin request is sent that often to keep the client from timing out.
+    Hold(Duration, Option<Duration>),
+}
+
+enum Progress {
+    /// Run the step again at the given time
+    Wait(Instant),
+    /// Continue with the next step
+    Done,
+    /// Replace the step and continue with the new one at the given time
+    Replace(Step, Instant),
+    /// Give up on the connection
+    Close,
+}
+
+struct Job {
+    stream: TcpStream,
+    steps: VecDeque<Step>,
+    /// When the current step was reached
+    started: Instant,
+    /// Bytes written or keep-alives sent in the current step
+    sent: usize,
+    /// Bytes read in the current step
+    received: Vec<u8>,
+    /// The part of a keep-alive packet the socket didn't take yet
+    pending: Vec<u8>,
+}
+
+impl Job {
+    /// Runs the job as far as possible and returns when it wants to continue, or `None` once it is finished
+    fn advance(&mut self) -> Option<Instant> {
+        loop {
+            let step = self.steps.pop_front()?;
+            let progress = match &step {
+                Step::Write(data) => self.write(data, data.len()),
+                Step::Dribble(data, interval) => match self.write(data, 1) {
+                    Progress::Wait(_) => Progress::Wait(Instant::now() + *interval),
+                    progress => progress,
+                },
+                Step::Pause(duration) => {
+                    if self.started.elapsed() >= *duration {
+                        Progress::Done
+                    } else {
+                        Progress::Wait(self.started + *duration)
+                    }
+                }
+                Step::Pong(delay) => self.await_ping(*delay),
+                Step::Hold(duration, keep_alive_interval) => {
+                    self.hold(*duration, *keep_alive_interval)
+                }
+            };
+
+            match progress {
+                Progress::Wait(at) => {
+                    self.steps.push_front(step);
+                    return Some(at);
+                }
+                Progress::Done => self.next_step(),
+                Progress::Replace(step, at) => {
+                    self.steps.push_front(step);
+                    self.next_step();
+                    return Some(at);
+                }
+                Progress::Close => return None,
+            }
+        }
+    }
+
+    fn next_step(&mut self) {
+        self.started = Instant::now();
+        self.sent = 0;
+        self.received.clear();
+    }
+
+    /// Writes up to `amount` bytes of `data`, waiting if the socket isn't ready
+    fn write(&mut self, data: &[u8], amount: usize) -> Progress {
+        if self.sent >= data.len() {
+            return Progress::Done;
+        }
+        let end = (self.sent + amount).min(data.len());
+        match self.stream.write(&data[self.sent..end]) {
+            Ok(written) => {
+                self.sent += written;
+                if self.sent >= data.len() {
+                    Progress::Done
+                } else {
+                    Progress::Wait(Instant::now() + POLL_INTERVAL)
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                Progress::Wait(Instant::now() + POLL_INTERVAL)
+            }
+            Err(_) => Progress::Close,
+        }
+    }
+
+    fn await_ping(&mut self, delay: Duration) -> Progress {
+        let mut buf = [0; PING_LENGTH];
+        match self.stream.read(&mut buf[..PING_LENGTH - self.received.len()]) {
+            Ok(0) => return Progress::Close,
+            Ok(len) => self.received.extend_from_slice(&buf[..len]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => return Progress::Close,
+        }
+
+        if self.received.len() == PING_LENGTH {
+            // Length 9, packet id 1
+            if self.received[0..2] != [0x09, 0x01] {
+                return Progress::Close;
+            }
+            Progress::Replace(Step::Write(self.received.clone()), Instant::now() + delay)
+        } else if self.started.elapsed() >= PING_WAIT {
+            Progress::Close
+        } else {
+            Progress::Wait(Instant::now() + POLL_INTERVAL)
+        }
+    }
+
+    fn hold(&mut self, duration: Duration, keep_alive_interval: Option<Duration>) -> Progress {
+        let mut buf = [0; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Progress::Close,
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return Progress::Close,
+            }
+        }
+
+        let until = self.started + duration;
+        if Instant::now() >= until {
+            return Progress::Done;
+        }
+        let mut next_check = Instant::now() + HOLD_CHECK_INTERVAL;
+        if let Some(interval) = keep_alive_interval {
+            let due = self.started + interval * self.sent as u32;
+            if self.pending.is_empty() && Instant::now() >= due {
+                self.pending = login_plugin_request(self.sent as i32);
+                self.sent += 1;
+            }
+            // The socket is nonblocking, so a packet may only be written in parts
+            if !self.pending.is_empty() {
+                match self.stream.write(&self.pending) {
+                    Ok(written) => {
+                        self.pending.drain(..written);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(_) => return Progress::Close,
+                }
+            }
+            next_check = if self.pending.is_empty() {
+                next_check.min(self.started + interval * self.sent as u32)
+            } else {
+                next_check.min(Instant::now() + POLL_INTERVAL)
+            };
+        }
+        Progress::Wait(next_check.min(until))
+    }
+}
+
+/// Clientbound Login Plugin Request on the channel Velocity uses for player info forwarding, clients answer it and keep waiting
+fn login_plugin_request(message_id: i32) -> Vec<u8> {
+    let mut packet: Vec<u8> = Vec::new();
+    write_varint(&mut packet, 4);
+    write_varint(&mut packet, message_id);
+    write_utf8_string(&mut packet, String::from("velocity:player_info"));
+
+    let mut buffer: Vec<u8> = Vec::new();
+    write_varint(&mut buffer, packet.len() as i32);
+    buffer.append(&mut packet);
+    buffer
+}
+
+/// How many connections the tarpit holds at most, see [`MAX_TARPIT_CONNECTIONS`]
+fn max_connections() -> usize {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes to the struct it is given
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 && limit.rlim_cur != libc::RLIM_INFINITY {
+            return MAX_TARPIT_CONNECTIONS.min(limit.rlim_cur as usize / 2);
+        }
+    }
+    MAX_TARPIT_CONNECTIONS
+}
+
+/// Slowly works through the steps of tarpitted connections, all on a single thread
+#[derive(Clone)]
+pub(crate) struct TarpitScheduler {
+    transmitter: Sender<Job>,
+}
+
+impl TarpitScheduler {
+    pub fn new() -> TarpitScheduler {
+        let (tx, rx) = channel::<Job>();
+        let max_connections = max_connections();
+
+        std::thread::spawn(move || {
+            let mut jobs: HashMap<u64, Job> = HashMap::new();
+            let mut queue: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+            let mut next_id: u64 = 0;
+            loop {
+                let timeout = queue
+                    .peek()
+                    .map(|Reverse((at, _))| at.saturating_duration_since(Instant::now()))
+                    .unwrap_or(HOLD_CHECK_INTERVAL);
+                match rx.recv_timeout(timeout) {
+                    Ok(job) if jobs.len() < max_connections => {
+                        jobs.insert(next_id, job);
+                        queue.push(Reverse((Instant::now(), next_id)));
+                        next_id += 1;
+                    }
+                    // Dropping the stream closes it
+                    Ok(_) => {}
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+
+                let now = Instant::now();
+                while let Some(Reverse((at, id))) = queue.peek().copied() {
+                    if at > now {
+                        break;
+                    }
+                    queue.pop();
+                    match jobs.get_mut(&id).and_then(|job| job.advance()) {
+                        Some(at) => queue.push(Reverse((at, id))),
+                        None => {
+                            jobs.remove(&id);
+                        }
+                    }
+                }
+            }
+        });
+
+        TarpitScheduler { transmitter: tx }
+    }
+
+    /// Takes over the connection and runs the steps, it is closed afterwards
+    pub fn run(&self, stream: TcpStream, steps: Vec<Step>) {
+        if let Err(e) = stream.set_nonblocking(true) {
+            log::debug!("Unable to tarpit connection: {}", e);
+            return;
+        }
+        let job = Job {
+            stream,
+            steps: steps.into(),
+            started: Instant::now(),
+            sent: 0,
+            received: Vec::new(),
+            pending: Vec::new(),
+        };
+        if let Err(e) = self.transmitter.send(job) {
+            log::error!("Error sending message to Tarpit Thread {}", e);
+        }
+    }
+
+    /// Keeps the connection open for the given time without answering anything
+    pub fn hold(&self, stream: TcpStream, duration: Duration) {
+        self.run(stream, vec![Step::Hold(duration, None)]);
+    }
+}
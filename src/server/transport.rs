@@ -0,0 +1,167 @@
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::capture::{Direction, SessionCapture};
+
+/// What the server needs from a connection, so sessions can be captured and replayed
+pub(crate) trait Transport: Read + Write {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    /// Returns the underlying socket, e.g. to hand it to the tarpit
+    fn try_clone(&self) -> io::Result<TcpStream>;
+}
+
+impl Transport for TcpStream {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+}
+
+/// Records everything read from and written to the inner connection
+pub(crate) struct Capturing<T: Transport> {
+    inner: T,
+    capture: SessionCapture,
+}
+
+impl<T: Transport> Capturing<T> {
+    pub fn new(inner: T, capture: SessionCapture) -> Capturing<T> {
+        Capturing { inner, capture }
+    }
+}
+
+impl<T: Transport> Read for Capturing<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.capture.record(Direction::Inbound, &buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<T: Transport> Write for Capturing<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.capture.record(Direction::Outbound, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Capturing<T> {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.peek(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        self.inner.try_clone()
+    }
+}
+
+/// Plays back recorded client bytes and collects what the server answers
+pub(crate) struct Replayed {
+    inbound: Cursor<Vec<u8>>,
+    pub outbound: Vec<u8>,
+    remote_address: SocketAddr,
+    local_address: SocketAddr,
+}
+
+impl Replayed {
+    pub fn new(inbound: Vec<u8>, remote_address: SocketAddr, local_address: SocketAddr) -> Replayed {
+        Replayed {
+            inbound: Cursor::new(inbound),
+            outbound: Vec::new(),
+            remote_address,
+            local_address,
+        }
+    }
+}
+
+impl Read for Replayed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf)
+    }
+}
+
+impl Write for Replayed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replayed {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.inbound.get_ref()[self.inbound.position() as usize..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        Ok(len)
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.remote_address)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_address)
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Replayed sessions have no socket",
+        ))
+    }
+}
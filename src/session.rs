@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Sender};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use timer::{Guard, Timer};
+
+use crate::event::{Event, EventKind};
+use crate::types::RequestType;
+
+/// Only the first steps of a visit are kept, the counters keep going
+const MAX_TIMELINE_LENGTH: usize = 100;
+/// Only the first distinct usernames, protocol versions, server addresses and fingerprints are kept, as clients choose them
+const MAX_DISTINCT_VALUES: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VisitStep {
+    pub timestamp: DateTime<Utc>,
+    pub port: u16,
+    pub request: String,
+    pub protocol_version: i32,
+    pub server_address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+/// All connections from one source that were less than the visit window apart
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Visit {
+    pub source: IpAddr,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub connections: usize,
+    pub pings: usize,
+    pub legacy_pings: usize,
+    pub joins: usize,
+    pub usernames: Vec<String>,
+    pub protocol_versions: Vec<i32>,
+    pub server_addresses: Vec<String>,
+    pub fingerprints: Vec<String>,
+    pub timeline: Vec<VisitStep>,
+    /// Set once one of the lists above was full and a value was left out
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl Visit {
+    fn new(source: IpAddr, timestamp: DateTime<Utc>) -> Visit {
+        Visit {
+            source,
+            first_seen: timestamp,
+            last_seen: timestamp,
+            connections: 0,
+            pings: 0,
+            legacy_pings: 0,
+            joins: 0,
+            usernames: vec![],
+            protocol_versions: vec![],
+            server_addresses: vec![],
+            fingerprints: vec![],
+            timeline: vec![],
+            truncated: false,
+        }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        self.last_seen - self.first_seen
+    }
+
+    fn add(&mut self, event: &Event) {
+        let EventKind::Connection(connection) = &event.kind else {
+            return;
+        };
+        self.last_seen = event.timestamp;
+        self.connections += 1;
+
+        let (request, handshake, username) = match &connection.request {
+            RequestType::Join(req) => {
+                self.joins += 1;
+                ("join", &req.handshake, Some(req.player.name.clone()))
+            }
+            RequestType::ModernPing(req) => {
+                self.pings += 1;
+                ("ping", req, None)
+            }
+            RequestType::LegacyPing(req) => {
+                self.legacy_pings += 1;
+                ("legacy_ping", req, None)
+            }
+        };
+        let mut complete = push_unique(&mut self.protocol_versions, handshake.protocol_version);
+        complete &= push_unique(&mut self.server_addresses, handshake.server_address.clone());
+        if let Some(username) = &username {
+            complete &= push_unique(&mut self.usernames, username.clone());
+        }
+        let fingerprint = connection.fingerprint.as_ref().map(|f| f.label.clone());
+        if let Some(label) = &fingerprint {
+            complete &= push_unique(&mut self.fingerprints, label.clone());
+        }
+        self.truncated |= !complete;
+
+        if self.timeline.len() < MAX_TIMELINE_LENGTH {
+            self.timeline.push(VisitStep {
+                timestamp: event.timestamp,
+                port: connection.remote_address.port(),
+                request: request.to_string(),
+                protocol_version: handshake.protocol_version,
+                server_address: handshake.server_address.clone(),
+                username,
+                fingerprint,
+            });
+        }
+    }
+}
+
+/// Returns false if the value is new but the list is full, the lists are short enough for a linear search
+fn push_unique<T: PartialEq>(values: &mut Vec<T>, value: T) -> bool {
+    if values.contains(&value) {
+        return true;
+    }
+    if values.len() >= MAX_DISTINCT_VALUES {
+        return false;
+    }
+    values.push(value);
+    true
+}
+
+enum Message {
+    Sweep,
+    Track(Box<Event>),
+}
+
+/// Groups connection events by source ip into visits.
+/// A visit ends once its source has been idle for the configured window, its summary is then passed to the callback.
+#[allow(unused)]
+pub struct SessionTracker {
+    timer: Timer,
+    guard: Guard,
+    transmitter: Sender<Message>,
+}
+
+impl SessionTracker {
+    pub fn new<F>(window: chrono::Duration, on_visit: F) -> SessionTracker
+    where
+        F: Fn(Visit) + Send + 'static,
+    {
+        let timer = Timer::new();
+        let (tx, rx) = channel();
+
+        let tx1 = tx.clone();
+        let guard = timer.schedule_repeating(chrono::Duration::seconds(1), move || {
+            if let Err(e) = tx1.send(Message::Sweep) {
+                log::error!("Error sending Sweep message to Session Thread {}", e);
+            }
+        });
+
+        std::thread::spawn(move || {
+            let mut visits: HashMap<IpAddr, Visit> = HashMap::new();
+            for received in rx {
+                match received {
+                    Message::Sweep => {
+                        let now = Utc::now();
+                        let idle = visits
+                            .iter()
+                            .filter(|(_, visit)| now - visit.last_seen >= window)
+                            .map(|(ip, _)| *ip)
+                            .collect::<Vec<IpAddr>>();
+                        for ip in idle {
+                            if let Some(visit) = visits.remove(&ip) {
+                                on_visit(visit);
+                            }
+                        }
+                    }
+                    Message::Track(event) => visits
+                        .entry(event.source)
+                        .or_insert_with(|| Visit::new(event.source, event.timestamp))
+                        .add(&event),
+                }
+            }
+        });
+
+        SessionTracker {
+            timer,
+            guard,
+            transmitter: tx,
+        }
+    }
+
+    pub fn track(&self, event: &Event) {
+        if let Err(e) = self.transmitter.send(Message::Track(Box::new(event.clone()))) {
+            log::error!("Error sending message to Session Thread {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Connection, ConnectionTrace, Request, ServerListPingRequest};
+
+    use super::*;
+
+    fn ping(server_address: &str) -> Event {
+        Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: server_address.to_string(),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        })
+    }
+
+    #[test]
+    fn lists_distinct_values_once() {
+        let mut visit = Visit::new("192.0.2.1".parse().unwrap(), Utc::now());
+        for address in ["a.example.com", "b.example.com", "a.example.com"] {
+            visit.add(&ping(address));
+        }
+        assert_eq!(visit.connections, 3);
+        assert_eq!(visit.pings, 3);
+        assert_eq!(visit.server_addresses, vec!["a.example.com", "b.example.com"]);
+        assert_eq!(visit.protocol_versions, vec![767]);
+        assert_eq!(visit.timeline.len(), 3);
+        assert!(!visit.truncated);
+    }
+
+    #[test]
+    fn caps_values_chosen_by_the_client() {
+        let mut visit = Visit::new("192.0.2.1".parse().unwrap(), Utc::now());
+        for i in 0..MAX_DISTINCT_VALUES + 50 {
+            visit.add(&ping(&format!("{}.example.com", i)));
+        }
+        assert_eq!(visit.connections, MAX_DISTINCT_VALUES + 50);
+        assert_eq!(visit.server_addresses.len(), MAX_DISTINCT_VALUES);
+        assert_eq!(visit.server_addresses.last().unwrap(), &format!("{}.example.com", MAX_DISTINCT_VALUES - 1));
+        assert_eq!(visit.timeline.len(), MAX_TIMELINE_LENGTH);
+        assert!(visit.truncated);
+    }
+}
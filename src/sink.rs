@@ -0,0 +1,377 @@
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use timer::{Guard, Timer};
+
+use crate::delivery::DeliveryConfig;
+use crate::elasticsearch::{ElasticsearchSink, ElasticsearchSinkConfig};
+use crate::event::Event;
+use crate::file::{FileSink, FileSinkConfig};
+use crate::gelf::{GelfSink, GelfSinkConfig};
+use crate::http::{HttpSink, HttpSinkConfig};
+use crate::loki::{LokiSink, LokiSinkConfig};
+use crate::mqtt::{MqttSink, MqttSinkConfig};
+use crate::redis::{RedisSink, RedisSinkConfig};
+use crate::report::Report;
+use crate::syslog::{SyslogSink, SyslogSinkConfig};
+use crate::webhook::BufferedWebhookClient;
+
+/// Event kinds sinks can be limited to
+const EVENT_KINDS: [&str; 4] = ["ping", "legacy_ping", "join", "visit"];
+/// Items waiting for the worker of a [`BufferedSender`] before `add` waits for it
+const PENDING_ITEMS: usize = 100;
+
+/// Somewhere events are delivered to, e.g. a Discord channel.
+/// Implement it to plug your own sinks into a [`Pipeline`](crate::pipeline::Pipeline).
+pub trait EventSink: Send + Sync {
+    fn send(&self, event: &Event);
+
+    /// Delivers the events the pipeline took from the queue of the sink at once
+    fn send_batch(&self, events: &[Event]) {
+        for event in events {
+            self.send(event);
+        }
+    }
+
+    /// Delivers a scheduled summary report
+    fn report(&self, _report: &Report) {}
+
+    /// Events that are rejected are never queued for the sink
+    fn accepts(&self, _event: &Event) -> bool {
+        true
+    }
+
+    /// Sends whatever the sink buffered right away instead of waiting for its next interval
+    fn flush(&self) {}
+}
+
+/// Lets a sink be shared with whatever else reads from it, e.g. the event store with the dashboard
+impl<T: EventSink + ?Sized> EventSink for Arc<T> {
+    fn send(&self, event: &Event) {
+        (**self).send(event)
+    }
+
+    fn send_batch(&self, events: &[Event]) {
+        (**self).send_batch(events)
+    }
+
+    fn report(&self, report: &Report) {
+        (**self).report(report)
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        (**self).accepts(event)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// Events are dropped while the queue of the sink is full
+    #[default]
+    Drop,
+    /// Connections wait until the sink caught up
+    Block,
+}
+
+/// How the pipeline feeds a sink, every section of a sinks file can set these
+#[derive(Deserialize, Clone, Debug)]
+pub struct SinkOptions {
+    /// Event kinds sent to the sink: ping, legacy_ping, join or visit, all if empty
+    #[serde(default)]
+    pub kinds: Vec<String>,
+    /// Events waiting for the sink before backpressure applies
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default)]
+    pub backpressure: Backpressure,
+    /// Events handed to the sink at once
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+fn default_queue_size() -> usize {
+    1000
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+impl Default for SinkOptions {
+    fn default() -> SinkOptions {
+        SinkOptions {
+            kinds: vec![],
+            queue_size: default_queue_size(),
+            backpressure: Backpressure::default(),
+            batch_size: default_batch_size(),
+        }
+    }
+}
+
+impl SinkOptions {
+    pub fn wants(&self, event: &Event) -> bool {
+        self.kinds.is_empty() || self.kinds.iter().any(|k| k == event.name())
+    }
+}
+
+pub struct NamedSink {
+    /// Used to refer to the sink in alert rules and reports
+    pub name: String,
+    pub sink: Box<dyn EventSink>,
+    pub options: SinkOptions,
+}
+
+impl NamedSink {
+    pub fn new<S: EventSink + 'static>(name: String, sink: S, options: SinkOptions) -> NamedSink {
+        NamedSink {
+            name,
+            sink: Box::new(sink),
+            options,
+        }
+    }
+}
+
+enum Message<T> {
+    Flush,
+    Add(T),
+}
+
+/// Collects items on a worker thread and hands them over in batches,
+/// once `max_batch` items are buffered or the flush interval passed.
+/// Flushes hand over empty batches too, so earlier failures can be retried.
+/// Adding waits while the worker is behind, so a slow delivery fills the queue of the pipeline in front of the sink
+#[allow(unused)]
+pub(crate) struct BufferedSender<T> {
+    timer: Timer,
+    guard: Guard,
+    transmitter: SyncSender<Message<T>>,
+}
+
+impl<T: Send + 'static> BufferedSender<T> {
+    pub fn new<F>(interval: chrono::Duration, max_batch: usize, mut deliver: F) -> BufferedSender<T>
+    where
+        F: FnMut(Vec<T>) + Send + 'static,
+    {
+        let timer = Timer::new();
+        let (tx, rx) = sync_channel::<Message<T>>(PENDING_ITEMS);
+
+        let tx1 = tx.clone();
+        let guard = timer.schedule_repeating(interval, move || {
+            // A full queue is handed over without the flush anyway
+            if let Err(TrySendError::Disconnected(_)) = tx1.try_send(Message::Flush) {
+                log::error!("Error sending Flush message to Receiver Thread");
+            }
+        });
+
+        std::thread::spawn(move || {
+            let mut buffer: Vec<T> = Vec::new();
+            for received in rx {
+                match received {
+                    Message::Flush => {}
+                    Message::Add(item) => {
+                        buffer.push(item);
+                        if buffer.len() < max_batch {
+                            continue;
+                        }
+                    }
+                }
+                deliver(std::mem::take(&mut buffer));
+            }
+        });
+
+        BufferedSender {
+            timer,
+            guard,
+            transmitter: tx,
+        }
+    }
+
+    pub fn add(&self, item: T) {
+        if let Err(e) = self.transmitter.send(Message::Add(item)) {
+            log::error!("Error sending message to Receiver Thread {}", e);
+        }
+    }
+
+    /// Drops the item instead of waiting while the worker is behind, returns whether it was added
+    pub fn try_add(&self, item: T) -> bool {
+        match self.transmitter.try_send(Message::Add(item)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("Error sending message to Receiver Thread");
+                false
+            }
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Err(e) = self.transmitter.send(Message::Flush) {
+            log::error!("Error sending Flush message to Receiver Thread {}", e);
+        }
+    }
+}
+
+impl<T> Drop for BufferedSender<T> {
+    fn drop(&mut self) {
+        let _ = self.transmitter.send(Message::Flush);
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscordConfig {
+    /// Used to refer to the sink in alert rules, defaults to discord-1, discord-2, ...
+    name: Option<String>,
+    url: String,
+    /// Merge events from the same source within one flush into a single embed
+    #[serde(default)]
+    digest: bool,
+}
+
+/// A sink in a sinks file, together with the options of its queue
+#[derive(Deserialize)]
+struct Section<T> {
+    #[serde(flatten)]
+    sink: T,
+    #[serde(flatten)]
+    options: SinkOptions,
+}
+
+#[derive(Deserialize)]
+struct SinksConfig {
+    #[serde(default)]
+    discord: Vec<Section<DiscordConfig>>,
+    #[serde(default)]
+    http: Vec<Section<HttpSinkConfig>>,
+    #[serde(default)]
+    file: Vec<Section<FileSinkConfig>>,
+    #[serde(default)]
+    syslog: Vec<Section<SyslogSinkConfig>>,
+    #[serde(default)]
+    elasticsearch: Vec<Section<ElasticsearchSinkConfig>>,
+    #[serde(default)]
+    loki: Vec<Section<LokiSinkConfig>>,
+    #[serde(default)]
+    gelf: Vec<Section<GelfSinkConfig>>,
+    #[serde(default)]
+    mqtt: Vec<Section<MqttSinkConfig>>,
+    #[serde(default)]
+    redis: Vec<Section<RedisSinkConfig>>,
+}
+
+/// Creates the sinks configured in a toml file
+pub fn load_sinks(path: &Path, delivery: &DeliveryConfig) -> Result<Vec<NamedSink>> {
+    let config: SinksConfig = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| eyre!("Unable to parse {}: {}", path.display(), e))?;
+
+    let mut sinks: Vec<NamedSink> = Vec::new();
+    for (i, Section { sink: discord, options }) in config.discord.into_iter().enumerate() {
+        let name = discord.name.unwrap_or_else(|| format!("discord-{}", i + 1));
+        let sink = BufferedWebhookClient::new(discord.url, discord.digest, delivery.clone());
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: http, options }) in config.http.into_iter().enumerate() {
+        let name = http.name.clone().unwrap_or_else(|| format!("http-{}", i + 1));
+        let sink = HttpSink::new(http, delivery.clone()).map_err(|e| eyre!("HTTP sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: file, options }) in config.file.into_iter().enumerate() {
+        let name = file.name.clone().unwrap_or_else(|| format!("file-{}", i + 1));
+        sinks.push(NamedSink::new(name, FileSink::new(file), options));
+    }
+    for (i, Section { sink: syslog, options }) in config.syslog.into_iter().enumerate() {
+        let name = syslog.name.clone().unwrap_or_else(|| format!("syslog-{}", i + 1));
+        let sink = SyslogSink::new(syslog, delivery.clone()).map_err(|e| eyre!("Syslog sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: elasticsearch, options }) in config.elasticsearch.into_iter().enumerate() {
+        let name = elasticsearch
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("elasticsearch-{}", i + 1));
+        let sink = ElasticsearchSink::new(elasticsearch, delivery.clone())
+            .map_err(|e| eyre!("Elasticsearch sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: loki, options }) in config.loki.into_iter().enumerate() {
+        let name = loki.name.clone().unwrap_or_else(|| format!("loki-{}", i + 1));
+        let sink = LokiSink::new(loki, delivery.clone()).map_err(|e| eyre!("Loki sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: gelf, options }) in config.gelf.into_iter().enumerate() {
+        let name = gelf.name.clone().unwrap_or_else(|| format!("gelf-{}", i + 1));
+        let sink = GelfSink::new(gelf, delivery.clone()).map_err(|e| eyre!("GELF sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: mqtt, options }) in config.mqtt.into_iter().enumerate() {
+        let name = mqtt.name.clone().unwrap_or_else(|| format!("mqtt-{}", i + 1));
+        let sink = MqttSink::new(mqtt, delivery.clone()).map_err(|e| eyre!("MQTT sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, Section { sink: redis, options }) in config.redis.into_iter().enumerate() {
+        let name = redis.name.clone().unwrap_or_else(|| format!("redis-{}", i + 1));
+        let sink = RedisSink::new(redis, delivery.clone()).map_err(|e| eyre!("Redis sink {}: {}", name, e))?;
+        sinks.push(NamedSink::new(name, sink, options));
+    }
+    for (i, sink) in sinks.iter().enumerate() {
+        if sinks[..i].iter().any(|other| other.name == sink.name) {
+            return Err(eyre!("Unable to parse {}: the sink name \"{}\" is used twice", path.display(), sink.name));
+        }
+        if let Some(kind) = sink.options.kinds.iter().find(|k| !EVENT_KINDS.contains(&k.as_str())) {
+            return Err(eyre!(
+                "Unable to parse {}: unknown event kind \"{}\" for sink {}, expected one of {}",
+                path.display(),
+                kind,
+                sink.name,
+                EVENT_KINDS.join(", ")
+            ));
+        }
+    }
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn hands_over_full_batches_and_flushes() {
+        let (tx, rx) = channel();
+        let sender = BufferedSender::new(chrono::Duration::hours(1), 2, move |items: Vec<u32>| {
+            tx.send(items).unwrap();
+        });
+        for item in 0..3 {
+            sender.add(item);
+        }
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![0, 1]);
+        sender.flush();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn drops_items_while_the_worker_is_behind() {
+        let (release, gate) = channel::<()>();
+        let gate = Mutex::new(gate);
+        let sender = BufferedSender::new(chrono::Duration::hours(1), 1, move |_: Vec<usize>| {
+            let _ = gate.lock().unwrap().recv();
+        });
+        // The worker holds one item, the others fill the channel
+        let added = (0..PENDING_ITEMS + 10).filter(|item| sender.try_add(*item)).count();
+        assert!(added <= PENDING_ITEMS + 1);
+        assert!(added >= PENDING_ITEMS);
+        drop(release);
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// A server that stops reading would otherwise block the worker of the sink forever
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Udp,
+    Tcp,
+    #[cfg(unix)]
+    Unix,
+}
+
+/// A socket sinks write to, given as `udp://host:port`, `tcp://host:port` or `unix:///path`.
+/// It is opened on first use and reopened after errors.
+pub(crate) struct SocketTarget {
+    protocol: Protocol,
+    address: String,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>,
+    #[cfg(unix)]
+    unix: Option<UnixDatagram>,
+}
+
+impl SocketTarget {
+    pub fn parse(address: &str) -> Result<SocketTarget> {
+        let (protocol, target) = match address.split_once("://") {
+            Some(("udp", target)) => (Protocol::Udp, target),
+            Some(("tcp", target)) => (Protocol::Tcp, target),
+            #[cfg(unix)]
+            Some(("unix", path)) => (Protocol::Unix, path),
+            _ => {
+                return Err(eyre!(
+                    "Invalid address \"{}\", expected udp://host:port, tcp://host:port or unix:///path",
+                    address
+                ))
+            }
+        };
+        Ok(SocketTarget {
+            protocol,
+            address: target.to_string(),
+            udp: None,
+            tcp: None,
+            #[cfg(unix)]
+            unix: None,
+        })
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Sends one datagram, or writes the bytes to the stream for tcp
+    pub fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let result = self.try_send(bytes);
+        if result.is_err() {
+            self.udp = None;
+            self.tcp = None;
+            #[cfg(unix)]
+            {
+                self.unix = None;
+            }
+        }
+        result
+    }
+
+    fn try_send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Udp => {
+                if self.udp.is_none() {
+                    let socket = UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+                    socket.connect(&self.address)?;
+                    self.udp = Some(socket);
+                }
+                self.udp.as_ref().map_or(Ok(()), |s| s.send(bytes).map(|_| ()))
+            }
+            Protocol::Tcp => {
+                if self.tcp.is_none() {
+                    let address = self
+                        .address
+                        .to_socket_addrs()?
+                        .next()
+                        .ok_or_else(|| io::Error::other(format!("Unable to resolve {}", self.address)))?;
+                    let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+                    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                    self.tcp = Some(stream);
+                }
+                self.tcp.as_mut().map_or(Ok(()), |s| s.write_all(bytes))
+            }
+            #[cfg(unix)]
+            Protocol::Unix => {
+                if self.unix.is_none() {
+                    let socket = UnixDatagram::unbound()?;
+                    socket.connect(&self.address)?;
+                    self.unix = Some(socket);
+                }
+                self.unix.as_ref().map_or(Ok(()), |s| s.send(bytes).map(|_| ()))
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::event::{Event, EventKind};
+use crate::sink::EventSink;
+use crate::types::RequestType;
+
+/// Events waiting for a slow subscriber before further ones are dropped for it
+const SUBSCRIBER_BUFFER: usize = 256;
+
+/// Keeps the most recent events in memory and hands new ones to subscribers, e.g. the dashboard
+pub struct EventStore {
+    capacity: usize,
+    /// Shared, so [`EventStore::stats`] can walk a copy without holding up new events
+    events: Mutex<VecDeque<Arc<Event>>>,
+    subscribers: Mutex<Vec<SyncSender<Event>>>,
+}
+
+#[derive(Serialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct Bucket {
+    pub start: DateTime<Utc>,
+    pub connections: usize,
+    pub joins: usize,
+    pub visits: usize,
+}
+
+#[derive(Serialize)]
+pub struct StoreStats {
+    /// Number of events held, the oldest are dropped once the store is full
+    pub events: usize,
+    pub since: Option<DateTime<Utc>>,
+    pub unique_sources: usize,
+    pub top_sources: Vec<Count>,
+    pub top_usernames: Vec<Count>,
+    pub top_asns: Vec<Count>,
+    pub top_fingerprints: Vec<Count>,
+    pub top_personas: Vec<Count>,
+    /// Hourly counts over the last day, oldest first
+    pub timeline: Vec<Bucket>,
+}
+
+impl EventStore {
+    pub fn new(capacity: usize) -> EventStore {
+        EventStore {
+            capacity: capacity.max(1),
+            events: Mutex::new(VecDeque::new()),
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Adds the events of a JSON lines file as written by a file sink, skipping reports and lines that don't parse
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let mut loaded = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            if let Ok(event) = serde_json::from_str::<Event>(&line?) {
+                self.push(event);
+                loaded += 1;
+            }
+        }
+        Ok(loaded.min(self.capacity))
+    }
+
+    fn push(&self, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        events.push_back(Arc::new(event));
+        if events.len() > self.capacity {
+            events.pop_front();
+        }
+    }
+
+    /// Receives every event added from now on until the receiver is dropped, events are skipped while it falls behind
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// The most recent events, newest first
+    pub fn recent(&self, limit: usize) -> Vec<Event> {
+        self.events.lock().unwrap().iter().rev().take(limit).map(|e| Event::clone(e)).collect()
+    }
+
+    /// Returns the first value `f` returns for the events, newest first
+    pub fn find_map<T>(&self, f: impl FnMut(&Event) -> Option<T>) -> Option<T> {
+        self.events.lock().unwrap().iter().rev().map(Arc::as_ref).find_map(f)
+    }
+
+    /// The events of a single source, newest first
+    pub fn by_source(&self, source: IpAddr, limit: usize) -> Vec<Event> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| e.source == source)
+            .take(limit)
+            .map(|e| Event::clone(e))
+            .collect()
+    }
+
+    pub fn stats(&self, top: usize) -> StoreStats {
+        let events = self.events.lock().unwrap().iter().cloned().collect::<Vec<Arc<Event>>>();
+        let mut sources = HashMap::new();
+        let mut usernames = HashMap::new();
+        let mut asns = HashMap::new();
+        let mut fingerprints = HashMap::new();
+        let mut personas = HashMap::new();
+
+        let now = Utc::now().duration_trunc(Duration::hours(1)).unwrap_or_else(|_| Utc::now());
+        let mut timeline = (0..24)
+            .rev()
+            .map(|hours| Bucket {
+                start: now - Duration::hours(hours),
+                connections: 0,
+                joins: 0,
+                visits: 0,
+            })
+            .collect::<Vec<Bucket>>();
+
+        for event in events.iter() {
+            *sources.entry(event.source.to_string()).or_insert(0) += 1;
+            if let Some(geo) = &event.geo {
+                match (geo.asn, &geo.organization) {
+                    (Some(asn), Some(org)) => *asns.entry(format!("AS{} {}", asn, org)).or_insert(0) += 1,
+                    (Some(asn), None) => *asns.entry(format!("AS{}", asn)).or_insert(0) += 1,
+                    _ => {}
+                }
+            }
+            let bucket = timeline
+                .iter_mut()
+                .rev()
+                .find(|b| b.start <= event.timestamp && event.timestamp < b.start + Duration::hours(1));
+            match &event.kind {
+                EventKind::Connection(c) => {
+                    if let RequestType::Join(req) = &c.request {
+                        *usernames.entry(req.player.name.clone()).or_insert(0) += 1;
+                    }
+                    if let Some(fingerprint) = &c.fingerprint {
+                        *fingerprints.entry(fingerprint.label.clone()).or_insert(0) += 1;
+                    }
+                    if !c.persona.is_empty() {
+                        *personas.entry(c.persona.clone()).or_insert(0) += 1;
+                    }
+                    if let Some(bucket) = bucket {
+                        bucket.connections += 1;
+                        if matches!(c.request, RequestType::Join(_)) {
+                            bucket.joins += 1;
+                        }
+                    }
+                }
+                EventKind::Visit(_) => {
+                    if let Some(bucket) = bucket {
+                        bucket.visits += 1;
+                    }
+                }
+            }
+        }
+
+        StoreStats {
+            events: events.len(),
+            since: events.first().map(|e| e.timestamp),
+            unique_sources: sources.len(),
+            top_sources: top_counts(sources, top),
+            top_usernames: top_counts(usernames, top),
+            top_asns: top_counts(asns, top),
+            top_fingerprints: top_counts(fingerprints, top),
+            top_personas: top_counts(personas, top),
+            timeline,
+        }
+    }
+}
+
+fn top_counts(counts: HashMap<String, usize>, top: usize) -> Vec<Count> {
+    let mut counts = counts
+        .into_iter()
+        .map(|(name, count)| Count { name, count })
+        .collect::<Vec<Count>>();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    counts.truncate(top);
+    counts
+}
+
+impl EventSink for EventStore {
+    fn send(&self, event: &Event) {
+        self.push(event.clone());
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| !matches!(subscriber.try_send(event.clone()), Err(TrySendError::Disconnected(_))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Connection, ConnectionTrace, Request, ServerListPingRequest};
+
+    use super::*;
+
+    fn ping() -> Event {
+        Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        })
+    }
+
+    #[test]
+    fn skips_events_for_slow_subscribers() {
+        let store = EventStore::new(10);
+        let events = store.subscribe();
+        for _ in 0..SUBSCRIBER_BUFFER + 5 {
+            store.send(&ping());
+        }
+        assert_eq!(events.try_iter().count(), SUBSCRIBER_BUFFER);
+        store.send(&ping());
+        assert_eq!(events.try_iter().count(), 1);
+        assert_eq!(store.recent(100).len(), 10);
+    }
+
+    #[test]
+    fn forgets_dropped_subscribers() {
+        let store = EventStore::new(10);
+        drop(store.subscribe());
+        let events = store.subscribe();
+        store.send(&ping());
+        assert_eq!(store.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(events.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn counts_the_held_events() {
+        let store = EventStore::new(3);
+        for _ in 0..4 {
+            store.send(&ping());
+        }
+        let stats = store.stats(10);
+        assert_eq!(stats.events, 3);
+        assert_eq!(stats.unique_sources, 1);
+        assert_eq!(stats.top_sources[0].name, "192.0.2.1");
+        assert_eq!(stats.top_sources[0].count, 3);
+        assert_eq!(stats.timeline.len(), 24);
+        assert_eq!(stats.timeline.iter().map(|b| b.connections).sum::<usize>(), 3);
+        assert_eq!(stats.timeline.iter().map(|b| b.joins + b.visits).sum::<usize>(), 0);
+    }
+}
@@ -0,0 +1,485 @@
+use std::fs;
+use std::sync::atomic::Ordering;
+
+use chrono::SecondsFormat;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+
+use crate::delivery::{DeliveryConfig, DeliveryStats};
+use crate::event::{Event, EventKind};
+use crate::report::Report;
+use crate::rules::Severity;
+use crate::sink::{BufferedSender, EventSink};
+use crate::socket::{Protocol, SocketTarget};
+use crate::types::RequestType;
+
+const APP_NAME: &str = "mc-honeypot";
+const VENDOR: &str = "mc-honeypot";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Syslog severities, see RFC 5424 section 6.2.1
+const SYSLOG_CRITICAL: u8 = 2;
+const SYSLOG_WARNING: u8 = 4;
+const SYSLOG_NOTICE: u8 = 5;
+const SYSLOG_INFO: u8 = 6;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyslogFormat {
+    /// The event as JSON
+    #[default]
+    Json,
+    /// ArcSight Common Event Format
+    Cef,
+    /// IBM QRadar Log Event Extended Format
+    Leef,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SyslogSinkConfig {
+    /// Used to refer to the sink in alert rules, defaults to syslog-1, syslog-2, ...
+    pub name: Option<String>,
+    /// `udp://host:port`, `tcp://host:port` or `unix:///dev/log`
+    pub address: String,
+    #[serde(default)]
+    pub format: SyslogFormat,
+    #[serde(default = "default_facility")]
+    pub facility: String,
+    /// Sent as the hostname, defaults to the one of the system
+    pub hostname: Option<String>,
+    /// The address of the honeypot, sent as `dst` in CEF and LEEF
+    pub destination: Option<String>,
+}
+
+fn default_facility() -> String {
+    String::from("local0")
+}
+
+fn facility_code(name: &str) -> Option<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "authpriv" => 10,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Sends events as RFC 5424 syslog messages, e.g. to a SIEM
+pub struct SyslogSink {
+    format: SyslogFormat,
+    facility: u8,
+    hostname: String,
+    destination: Option<String>,
+    buffer: BufferedSender<String>,
+}
+
+impl SyslogSink {
+    pub fn new(config: SyslogSinkConfig, delivery: DeliveryConfig) -> Result<SyslogSink> {
+        let mut target = SocketTarget::parse(&config.address)?;
+        let facility = facility_code(&config.facility)
+            .ok_or_else(|| eyre!("Unknown facility \"{}\"", config.facility))?;
+        let hostname = config
+            .hostname
+            .or_else(|| {
+                fs::read_to_string("/proc/sys/kernel/hostname")
+                    .ok()
+                    .map(|h| h.trim().to_string())
+            })
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| String::from("-"));
+
+        let address = config.address.clone();
+        let stats = delivery.stats;
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(5),
+            1,
+            move |messages: Vec<String>| {
+                for message in messages {
+                    deliver(&mut target, &address, &message, &stats);
+                }
+            },
+        );
+
+        Ok(SyslogSink {
+            format: config.format,
+            facility,
+            hostname,
+            destination: config.destination,
+            buffer,
+        })
+    }
+
+    fn enqueue(&self, severity: u8, message_id: &str, timestamp: chrono::DateTime<chrono::Utc>, message: String) {
+        let line = format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            self.facility as u16 * 8 + severity as u16,
+            timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            APP_NAME,
+            std::process::id(),
+            message_id,
+            message
+        );
+        self.buffer.add(line);
+    }
+}
+
+fn deliver(target: &mut SocketTarget, address: &str, message: &str, stats: &DeliveryStats) {
+    // Messages over tcp are framed by octet counting, see RFC 6587
+    let frame = match target.protocol() {
+        Protocol::Tcp => format!("{} {}", message.len(), message),
+        _ => message.to_string(),
+    };
+    // One retry covers connections the server closed in the meantime
+    let result = target.send(frame.as_bytes()).or_else(|_| target.send(frame.as_bytes()));
+    match result {
+        Ok(()) => {
+            stats.delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            log::error!("Unable to send to syslog server {}: {}", address, e);
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl EventSink for SyslogSink {
+    fn send(&self, event: &Event) {
+        let severity = match &event.alert {
+            Some(alert) => match alert.severity {
+                Severity::Info => SYSLOG_INFO,
+                Severity::Notice => SYSLOG_NOTICE,
+                Severity::Warning => SYSLOG_WARNING,
+                Severity::Critical => SYSLOG_CRITICAL,
+            },
+            None => match &event.kind {
+                EventKind::Connection(c) if matches!(c.request, RequestType::Join(_)) => SYSLOG_NOTICE,
+                _ => SYSLOG_INFO,
+            },
+        };
+        let message = match self.format {
+            SyslogFormat::Json => match serde_json::to_string(event) {
+                Ok(json) => json,
+                Err(e) => {
+                    log::error!("Unable to serialize event: {}", e);
+                    return;
+                }
+            },
+            SyslogFormat::Cef => cef(event, severity, self.destination.as_deref()),
+            SyslogFormat::Leef => leef(event, severity, self.destination.as_deref()),
+        };
+        self.enqueue(severity, event.name(), event.timestamp, message);
+    }
+
+    fn report(&self, report: &Report) {
+        let message = match self.format {
+            SyslogFormat::Json => match serde_json::to_string(report) {
+                Ok(json) => json,
+                Err(e) => {
+                    log::error!("Unable to serialize report: {}", e);
+                    return;
+                }
+            },
+            SyslogFormat::Cef => format!(
+                "CEF:0|{}|{}|{}|report|{} report|3|cnt={} msg={}",
+                VENDOR,
+                APP_NAME,
+                VERSION,
+                report.period,
+                report.connections,
+                cef_value(&report.summary())
+            ),
+            SyslogFormat::Leef => format!(
+                "LEEF:1.0|{}|{}|{}|report|cnt={}\tmsg={}",
+                VENDOR,
+                APP_NAME,
+                VERSION,
+                report.connections,
+                leef_value(&report.summary())
+            ),
+        };
+        self.enqueue(SYSLOG_INFO, "report", report.end, message);
+    }
+
+    fn flush(&self) {
+        self.buffer.flush();
+    }
+}
+
+/// The common fields of CEF and LEEF, with CEF keys
+fn fields(event: &Event, destination: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut fields = vec![("src", event.source.to_string())];
+    if let Some(destination) = destination {
+        fields.push(("dst", destination.to_string()));
+    }
+    match &event.kind {
+        EventKind::Connection(c) => {
+            let handshake = match &c.request {
+                RequestType::Join(req) => &req.handshake,
+                RequestType::ModernPing(req) | RequestType::LegacyPing(req) => req,
+            };
+            fields.push(("spt", c.remote_address.port().to_string()));
+            fields.push(("dhost", handshake.server_address.clone()));
+            fields.push(("dpt", handshake.server_port.to_string()));
+            if let RequestType::Join(req) = &c.request {
+                fields.push(("suser", req.player.name.clone()));
+            }
+            fields.push(("act", c.action.clone()));
+            fields.push(("app", format!("minecraft/{}", handshake.protocol_version)));
+            if let Some(fingerprint) = &c.fingerprint {
+                fields.push(("fingerprint", format!("{} #{}", fingerprint.label, fingerprint.hash)));
+            }
+        }
+        EventKind::Visit(visit) => {
+            fields.push(("cnt", visit.connections.to_string()));
+            if !visit.usernames.is_empty() {
+                fields.push(("suser", visit.usernames.join(",")));
+            }
+        }
+    }
+    if let Some(ptr) = &event.ptr {
+        fields.push(("shost", ptr.clone()));
+    }
+    if let Some(geo) = &event.geo {
+        if let Some(country) = &geo.country {
+            fields.push(("country", country.clone()));
+        }
+        if let Some(asn) = geo.asn {
+            fields.push(("asn", format!("AS{}", asn)));
+        }
+    }
+    if !event.tags.is_empty() {
+        fields.push(("tags", event.tags.join(",")));
+    }
+    if let Some(alert) = &event.alert {
+        fields.push(("rule", alert.rule.clone()));
+    }
+    fields
+}
+
+/// `CEF:Version|Vendor|Product|Version|Signature ID|Name|Severity|Extension`, custom fields use the labeled cs slots
+fn cef(event: &Event, severity: u8, destination: Option<&str>) -> String {
+    // CEF severities go from 0 to 10, where 10 is the most important
+    let cef_severity = match severity {
+        SYSLOG_CRITICAL => 10,
+        SYSLOG_WARNING => 7,
+        SYSLOG_NOTICE => 5,
+        _ => 3,
+    };
+    let mut custom = 0;
+    let extension = fields(event, destination)
+        .into_iter()
+        .map(|(key, value)| match key {
+            "src" | "dst" | "spt" | "dpt" | "dhost" | "shost" | "suser" | "act" | "app" | "cnt" => {
+                format!("{}={}", key, cef_value(&value))
+            }
+            _ => {
+                custom += 1;
+                format!("cs{}Label={} cs{}={}", custom, key, custom, cef_value(&value))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|rt={} {}",
+        VENDOR,
+        APP_NAME,
+        VERSION,
+        event.name(),
+        event.title(),
+        cef_severity,
+        event.timestamp.timestamp_millis(),
+        extension
+    )
+}
+
+/// `LEEF:1.0|Vendor|Product|Version|EventID|` followed by tab separated attributes
+fn leef(event: &Event, severity: u8, destination: Option<&str>) -> String {
+    let leef_severity = match severity {
+        SYSLOG_CRITICAL => 10,
+        SYSLOG_WARNING => 7,
+        SYSLOG_NOTICE => 5,
+        _ => 3,
+    };
+    let mut attributes = vec![format!("sev={}", leef_severity)];
+    for (key, value) in fields(event, destination) {
+        let key = match key {
+            "spt" => "srcPort",
+            "dpt" => "dstPort",
+            "suser" => "usrName",
+            "act" => "action",
+            "dhost" => "dstHost",
+            "shost" => "srcHost",
+            other => other,
+        };
+        attributes.push(format!("{}={}", key, leef_value(&value)));
+    }
+    format!(
+        "LEEF:1.0|{}|{}|{}|{}|{}",
+        VENDOR,
+        APP_NAME,
+        VERSION,
+        event.name(),
+        attributes.join("\t")
+    )
+}
+
+/// Escapes a CEF extension value
+fn cef_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// LEEF 1.0 values may not contain the tab delimiter
+fn leef_value(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::geoip::GeoInfo;
+    use crate::types::{Connection, ConnectionTrace, Request, ServerListPingRequest};
+
+    use super::*;
+
+    fn ping() -> Event {
+        let mut event = Event::connection(Connection {
+            request: Request {
+                request_type: RequestType::ModernPing(ServerListPingRequest {
+                    protocol_version: 767,
+                    server_address: String::from("play.example.com"),
+                    server_port: 25565,
+                }),
+                remote_address: "192.0.2.1:50000".parse().unwrap(),
+                local_address: "198.51.100.1:25565".parse().unwrap(),
+            },
+            trace: ConnectionTrace::default(),
+        });
+        event.timestamp = Utc.with_ymd_and_hms(2024, 5, 31, 23, 59, 0).unwrap();
+        if let EventKind::Connection(c) = &mut event.kind {
+            c.action = String::from("status");
+        }
+        event
+    }
+
+    fn sink(address: String, format: SyslogFormat) -> SyslogSink {
+        SyslogSink::new(
+            SyslogSinkConfig {
+                name: None,
+                address,
+                format,
+                facility: default_facility(),
+                hostname: Some(String::from("sensor-1")),
+                destination: None,
+            },
+            DeliveryConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn writes_rfc_5424_lines() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let sink = sink(format!("udp://{}", server.local_addr().unwrap()), SyslogFormat::Json);
+        sink.send(&ping());
+        let mut buf = [0; 4096];
+        let len = server.recv(&mut buf).unwrap();
+        let line = String::from_utf8_lossy(&buf[..len]).to_string();
+        // local0 * 8 + info
+        let header = format!(
+            "<134>1 2024-05-31T23:59:00.000000Z sensor-1 mc-honeypot {} ping - ",
+            std::process::id()
+        );
+        assert!(line.starts_with(&header), "{}", line);
+        let json: serde_json::Value = serde_json::from_str(&line[header.len()..]).unwrap();
+        assert_eq!(json["source"], "192.0.2.1");
+    }
+
+    #[test]
+    fn frames_tcp_messages_by_octet_count() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink = sink(format!("tcp://{}", server.local_addr().unwrap()), SyslogFormat::Cef);
+        sink.send(&ping());
+        sink.send(&ping());
+        let (stream, _) = server.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream);
+        for _ in 0..2 {
+            let mut length = Vec::new();
+            reader.read_until(b' ', &mut length).unwrap();
+            let length = String::from_utf8(length).unwrap().trim_end().parse::<usize>().unwrap();
+            let mut message = vec![0; length];
+            reader.read_exact(&mut message).unwrap();
+            let message = String::from_utf8(message).unwrap();
+            assert!(message.starts_with("<134>1 "), "{}", message);
+            assert!(message.ends_with("act=status app=minecraft/767"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn formats_cef_with_labeled_custom_fields() {
+        let mut event = ping();
+        event.tags = vec![String::from("a=b")];
+        event.geo = Some(GeoInfo {
+            country: Some(String::from("DE")),
+            country_name: None,
+            city: None,
+            asn: Some(3320),
+            organization: None,
+        });
+        let line = cef(&event, SYSLOG_INFO, Some("198.51.100.1"));
+        assert_eq!(
+            line,
+            format!(
+                "CEF:0|mc-honeypot|mc-honeypot|{}|ping|Ping|3|rt=1717199940000 src=192.0.2.1 dst=198.51.100.1 spt=50000 \
+                 dhost=play.example.com dpt=25565 act=status app=minecraft/767 cs1Label=country cs1=DE cs2Label=asn \
+                 cs2=AS3320 cs3Label=tags cs3=a\\=b",
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn formats_leef_with_tab_separated_attributes() {
+        let mut event = ping();
+        event.tags = vec![String::from("a\tb")];
+        let line = leef(&event, SYSLOG_NOTICE, None);
+        assert_eq!(
+            line,
+            format!(
+                "LEEF:1.0|mc-honeypot|mc-honeypot|{}|ping|sev=5\tsrc=192.0.2.1\tsrcPort=50000\tdstHost=play.example.com\t\
+                 dstPort=25565\taction=status\tapp=minecraft/767\ttags=a b",
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn escapes_values() {
+        assert_eq!(cef_value("a=b\\c\nd\r"), "a\\=b\\\\c\\nd\\r");
+        assert_eq!(cef_value("a|b"), "a|b");
+        assert_eq!(leef_value("a\tb\nc\rd"), "a b c d");
+    }
+}
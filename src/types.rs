@@ -1,22 +1,138 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-pub type Handler = Arc<dyn Fn(Request) -> ServerListPingResponse + Send + Sync + 'static>;
+use crate::utils::PlayerAnalysis;
+
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+
+pub type Reporter = Arc<dyn Fn(Connection) + Send + Sync + 'static>;
+
+/// Decides what happens to a new connection before anything is read from it.
+/// Also gets the number of connections that are currently being handled.
+pub type Filter = Arc<dyn Fn(&SocketAddr, usize) -> Admission + Send + Sync + 'static>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    /// Close the connection right away
+    Refuse,
+    /// Handle the connection as usual, but don't report it
+    Silent,
+    /// Keep the connection open for the given time without reading or answering anything
+    Tarpit(Duration),
+}
 
 pub struct Request {
     pub request_type: RequestType,
     pub remote_address: SocketAddr,
+    /// The address of the listener that accepted the connection
+    pub local_address: SocketAddr,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum RequestType {
-    Join(SamplePlayer),
+    Join(JoinRequest),
     ModernPing(ServerListPingRequest),
     LegacyPing(ServerListPingRequest),
 }
 
-#[derive(Debug)]
+impl RequestType {
+    /// The hostname the client used to connect, as sent in the handshake
+    pub fn server_address(&self) -> &str {
+        match self {
+            RequestType::Join(req) => &req.handshake.server_address,
+            RequestType::ModernPing(req) | RequestType::LegacyPing(req) => &req.server_address,
+        }
+    }
+}
+
+/// Everything that happened on a single connection, reported once it is closed
+pub struct Connection {
+    pub request: Request,
+    pub trace: ConnectionTrace,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketKind {
+    Handshake,
+    StatusRequest,
+    PingRequest,
+    LoginStart,
+    /// The 0xFE byte that starts every legacy ping
+    LegacyPing,
+    /// The 0x01 payload byte sent by 1.4+ clients
+    LegacyPingPayload,
+    /// The MC|PingHost plugin message sent by 1.6 clients
+    LegacyPluginMessage,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PacketRecord {
+    pub kind: PacketKind,
+    /// Milliseconds since the connection was accepted
+    pub offset_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConnectionTrace {
+    pub packets: Vec<PacketRecord>,
+    pub next_state: Option<i32>,
+    pub ping_payload: Option<i64>,
+    pub duration_ms: u64,
+}
+
+impl ConnectionTrace {
+    pub fn sent(&self, kind: PacketKind) -> bool {
+        self.packets.iter().any(|p| p.kind == kind)
+    }
+
+    /// The longest pause between two consecutive packets
+    pub fn max_gap_ms(&self) -> u64 {
+        self.packets
+            .windows(2)
+            .map(|w| w[1].offset_ms.saturating_sub(w[0].offset_ms))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub enum Response {
+    Status(ServerListPingResponse),
+    /// Disconnects a joining client with the given reason
+    Kick(String),
+    /// Closes the connection without answering
+    Drop,
+    /// Sends the inner response as slowly as possible
+    Tarpit(Box<Response>, Tarpit),
+}
+
+/// How a tarpitted connection is slowed down
+#[derive(Clone, Copy, Debug)]
+pub struct Tarpit {
+    /// The pause between the bytes of a status response, zero sends it at once
+    pub byte_interval: Duration,
+    /// How long we wait before answering the ping
+    pub pong_delay: Duration,
+    /// How long join attempts are held open before they are kicked or dropped
+    pub hold: Duration,
+    /// How often a held login is sent a plugin request, so the client doesn't time out
+    pub keep_alive_interval: Duration,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JoinRequest {
+    pub handshake: ServerListPingRequest,
+    /// The id is empty if the client did not send a UUID
+    pub player: SamplePlayer,
+    pub analysis: PlayerAnalysis,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServerListPingRequest {
     pub protocol_version: i32,
     pub server_address: String,
@@ -49,7 +165,7 @@ pub struct Players {
     pub sample: Vec<SamplePlayer>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SamplePlayer {
     pub name: String,
     pub id: String,
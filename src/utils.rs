@@ -1,29 +1,29 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
 
 use color_eyre::Result;
+use serde::{Deserialize, Serialize};
 
-pub fn read_bytes(stream: &mut TcpStream, amount: usize) -> Result<Vec<u8>> {
+pub fn read_bytes<R: Read>(stream: &mut R, amount: usize) -> Result<Vec<u8>> {
     let mut buf = vec![0; amount];
     stream.read_exact(&mut buf)?;
     Ok(buf)
 }
 
-pub fn read_byte(stream: &mut TcpStream) -> Result<u8> {
+pub fn read_byte<R: Read>(stream: &mut R) -> Result<u8> {
     let mut buf = [0];
     stream.read_exact(&mut buf)?;
     Ok(buf[0])
 }
 
-pub fn read_unsigned_short(stream: &mut TcpStream) -> Result<u16> {
+pub fn read_unsigned_short<R: Read>(stream: &mut R) -> Result<u16> {
     Ok((read_byte(stream)? as u16) << 8 | read_byte(stream)? as u16)
 }
 
-pub fn read_short_le(stream: &mut TcpStream) -> Result<u16> {
+pub fn read_short_le<R: Read>(stream: &mut R) -> Result<u16> {
     Ok(u16::from_le_bytes([read_byte(stream)?, read_byte(stream)?]))
 }
 
-pub fn read_int(stream: &mut TcpStream) -> Result<u32> {
+pub fn read_int<R: Read>(stream: &mut R) -> Result<u32> {
     Ok(u32::from_be_bytes([
         read_byte(stream)?,
         read_byte(stream)?,
@@ -32,18 +32,18 @@ pub fn read_int(stream: &mut TcpStream) -> Result<u32> {
     ]))
 }
 
-pub fn read_int128(stream: &mut TcpStream) -> Result<u128> {
+pub fn read_int128<R: Read>(stream: &mut R) -> Result<u128> {
     Ok(u128::from_be_bytes(read_bytes(stream, 16)?.try_into().unwrap()))
 }
 
-pub fn read_long(stream: &mut TcpStream) -> Result<i64> {
+pub fn read_long<R: Read>(stream: &mut R) -> Result<i64> {
     let bytes = read_bytes(stream, 8)?;
     Ok(i64::from_be_bytes([
         bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
     ]))
 }
 
-pub fn read_varint(stream: &mut TcpStream) -> Result<i32> {
+pub fn read_varint<R: Read>(stream: &mut R) -> Result<i32> {
     let mut buf = [0];
     let mut ans = 0;
     for i in 0..5 {
@@ -56,13 +56,13 @@ pub fn read_varint(stream: &mut TcpStream) -> Result<i32> {
     Ok(ans)
 }
 
-pub fn read_utf8_string(stream: &mut TcpStream) -> Result<String> {
+pub fn read_utf8_string<R: Read>(stream: &mut R) -> Result<String> {
     let len = read_varint(stream)? as usize;
     let data: Vec<u8> = read_bytes(stream, len)?;
     Ok(String::from_utf8(data).unwrap_or_default())
 }
 
-pub fn read_utf16_string(stream: &mut TcpStream, chars: u16) -> Result<String> {
+pub fn read_utf16_string<R: Read>(stream: &mut R, chars: u16) -> Result<String> {
     let mut shorts = Vec::new();
     for _ in 0..chars {
         shorts.push(read_unsigned_short(stream)?);
@@ -92,13 +92,13 @@ pub fn write_utf8_string(buffer: &mut Vec<u8>, value: String) {
     buffer.append(&mut data);
 }
 
-pub fn write_bytes_to_stream(stream: &mut TcpStream, bytes: Vec<u8>) {
+pub fn write_bytes_to_stream<W: Write>(stream: &mut W, bytes: Vec<u8>) {
     stream
         .write_all(bytes.as_slice())
         .expect("Failed to write bytes to stream");
 }
 
-pub fn write_varint_to_stream(stream: &mut TcpStream, value: i32) {
+pub fn write_varint_to_stream<W: Write>(stream: &mut W, value: i32) {
     let mut buf = Vec::new();
     write_varint(&mut buf, value);
     write_bytes_to_stream(stream, buf);
@@ -114,3 +114,85 @@ pub fn format_uuid(value: u128) -> String {
 
     uuid
 }
+
+/// Builds the UUID the server assigns to `name` in offline mode, a v3 UUID of "OfflinePlayer:<name>"
+pub fn offline_uuid(name: &str) -> u128 {
+    let mut hash = md5::compute(format!("OfflinePlayer:{}", name)).0;
+    hash[6] = (hash[6] & 0x0f) | 0x30;
+    hash[8] = (hash[8] & 0x3f) | 0x80;
+    u128::from_be_bytes(hash)
+}
+
+pub fn uuid_version(value: u128) -> u8 {
+    ((value >> 76) & 0xf) as u8
+}
+
+/// Checks the rules the vanilla client enforces: 3 to 16 characters out of a-z, A-Z, 0-9 and _
+pub fn is_valid_username(name: &str) -> bool {
+    (3..=16).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UuidKind {
+    /// Clients before 1.19 don't send a UUID
+    Missing,
+    Nil,
+    /// Matches the offline mode UUID of the username, typical for cracked clients and bots
+    Offline,
+    /// A random v4 UUID as used by premium accounts
+    Online,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerAnalysis {
+    pub uuid_kind: UuidKind,
+    pub uuid_version: Option<u8>,
+    pub valid_username: bool,
+}
+
+pub fn analyze_player(name: &str, uuid: Option<u128>) -> PlayerAnalysis {
+    let uuid_kind = match uuid {
+        None => UuidKind::Missing,
+        Some(0) => UuidKind::Nil,
+        Some(uuid) if uuid == offline_uuid(name) => UuidKind::Offline,
+        Some(uuid) if uuid_version(uuid) == 4 => UuidKind::Online,
+        Some(_) => UuidKind::Other,
+    };
+    PlayerAnalysis {
+        uuid_kind,
+        uuid_version: uuid.filter(|u| *u != 0).map(uuid_version),
+        valid_username: is_valid_username(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_uuid_matches_the_server() {
+        assert_eq!(format_uuid(offline_uuid("Notch")), "b50ad385-829d-3141-a216-7e7d7539ba7f");
+        assert_eq!(uuid_version(offline_uuid("Notch")), 3);
+    }
+
+    #[test]
+    fn classifies_uuids() {
+        let online = 0x069a79f4_44e9_4726_a5be_fca90e38aaf5;
+        assert_eq!(analyze_player("Notch", Some(offline_uuid("Notch"))).uuid_kind, UuidKind::Offline);
+        assert_eq!(analyze_player("Notch", Some(online)).uuid_kind, UuidKind::Online);
+        assert_eq!(analyze_player("Notch", Some(0)).uuid_kind, UuidKind::Nil);
+        assert_eq!(analyze_player("Notch", None).uuid_kind, UuidKind::Missing);
+        // The offline UUID of another name
+        assert_eq!(analyze_player("jeb_", Some(offline_uuid("Notch"))).uuid_kind, UuidKind::Other);
+    }
+
+    #[test]
+    fn validates_usernames() {
+        assert!(is_valid_username("jeb_"));
+        assert!(!is_valid_username("ab"));
+        assert!(!is_valid_username("seventeen_chars__"));
+        assert!(!is_valid_username("no-dashes"));
+    }
+}
@@ -1,13 +1,30 @@
-use std::net::SocketAddr;
-use std::sync::mpsc::{channel, Sender};
+use std::net::IpAddr;
 
+use chrono::{DateTime, Utc};
 use serde::Serialize;
-use timer::{Guard, Timer};
 
 use crate::color::RgbColor;
+use crate::delivery::{sink_name, Delivery, DeliveryConfig, OutgoingRequest};
+use crate::event::{ConnectionEvent, Event, EventKind};
+use crate::report::{Count, Report};
+use crate::reputation::{Profile, Reputation};
+use crate::session::Visit;
+use crate::sink::{BufferedSender, EventSink};
 use crate::types::RequestType;
 
 const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+/// Limits from https://discord.com/developers/docs/resources/message#embed-object-embed-limits
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_FIELDS: usize = 25;
+const MAX_FIELD_NAME_LENGTH: usize = 256;
+const MAX_FIELD_VALUE_LENGTH: usize = 1024;
+const MAX_FOOTER_LENGTH: usize = 2048;
+/// Applies to the sum of all embeds in a message as well
+const MAX_EMBED_LENGTH: usize = 6000;
+const FLUSH_INTERVAL_SECONDS: i64 = 5;
+/// Digests are sent early once this many events are buffered
+const MAX_DIGEST_EVENTS: usize = 500;
 
 #[derive(Serialize)]
 struct WebhookPayload {
@@ -25,120 +42,451 @@ impl WebhookPayload {
 #[derive(Serialize, Clone)]
 struct Embed {
     title: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     description: String,
     color: i32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<EmbedField>,
+    timestamp: DateTime<Utc>,
+    footer: EmbedFooter,
 }
 
-struct WebhookBuffer {
-    pub embeds: Vec<Embed>,
-    pub url: String,
+#[derive(Serialize, Clone)]
+struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+#[derive(Serialize, Clone)]
+struct EmbedFooter {
+    text: String,
 }
 
-impl WebhookBuffer {
-    fn new(url: String) -> WebhookBuffer {
-        WebhookBuffer {
-            embeds: vec![],
-            url,
+impl Embed {
+    fn new(title: String, color: i32, timestamp: DateTime<Utc>, footer: String) -> Embed {
+        Embed {
+            title,
+            description: String::new(),
+            color,
+            fields: vec![],
+            timestamp,
+            footer: EmbedFooter { text: footer },
         }
     }
 
-    fn add_message(&mut self, embed: Embed) {
-        self.embeds.push(embed);
-        if self.embeds.len() >= MAX_EMBEDS_PER_MESSAGE {
-            self.flush();
-        }
+    /// Adds a field, Discord rejects empty values so they are replaced with a dash
+    fn field(&mut self, name: &str, value: impl Into<String>, inline: bool) {
+        let value = value.into();
+        self.fields.push(EmbedField {
+            name: name.to_string(),
+            value: if value.is_empty() { String::from("-") } else { value },
+            inline,
+        });
     }
 
-    fn flush(&mut self) {
-        if !self.embeds.is_empty() {
-            let payload = WebhookPayload::new(&self.embeds);
-            send_webhook_payload(self.url.clone(), payload);
-            self.embeds.clear();
+    /// Counts the characters Discord limits, see `MAX_EMBED_LENGTH`
+    fn length(&self) -> usize {
+        self.title.chars().count()
+            + self.description.chars().count()
+            + self.footer.text.chars().count()
+            + self
+                .fields
+                .iter()
+                .map(|f| f.name.chars().count() + f.value.chars().count())
+                .sum::<usize>()
+    }
+
+    /// Shortens everything to Discord's limits, dropping the last fields if the embed is still too large
+    fn fit(mut self) -> Embed {
+        self.title = truncate(&self.title, MAX_TITLE_LENGTH);
+        self.description = truncate(&self.description, MAX_DESCRIPTION_LENGTH);
+        self.footer.text = truncate(&self.footer.text, MAX_FOOTER_LENGTH);
+        self.fields.truncate(MAX_FIELDS);
+        for field in &mut self.fields {
+            field.name = truncate(&field.name, MAX_FIELD_NAME_LENGTH);
+            field.value = truncate(&field.value, MAX_FIELD_VALUE_LENGTH);
+        }
+        while self.length() > MAX_EMBED_LENGTH && self.fields.pop().is_some() {}
+        if self.length() > MAX_EMBED_LENGTH {
+            let excess = self.length() - MAX_EMBED_LENGTH;
+            let keep = self.description.chars().count().saturating_sub(excess);
+            self.description = truncate(&self.description, keep);
         }
+        self
     }
 }
 
-enum Message {
-    Flush,
-    AddEmbed(Embed),
+/// An embed together with what is needed to merge it in digest mode
+struct Entry {
+    /// Not set for reports, which are never merged
+    source: Option<IpAddr>,
+    /// What happened, e.g. "join", see [`Event::name`]
+    name: &'static str,
+    /// Used to count events in digests, e.g. "Ping"
+    title: &'static str,
+    embed: Embed,
 }
 
-#[allow(unused)]
+/// Sends events as embeds to a Discord webhook, up to ten per message every five seconds.
+/// In digest mode, all events from the same source within those five seconds are merged into one embed.
 pub struct BufferedWebhookClient {
-    timer: Timer,
-    guard: Guard,
-    transmitter: Sender<Message>,
+    buffer: BufferedSender<Entry>,
 }
 
 impl BufferedWebhookClient {
-    pub fn new(url: String) -> BufferedWebhookClient {
-        let timer = Timer::new();
-        let (tx, rx) = channel();
-
-        let tx1 = tx.clone();
-        let guard = timer.schedule_repeating(chrono::Duration::seconds(5), move || {
-            if let Err(e) = tx1.send(Message::Flush) {
-                log::error!("Error sending Flush message to Receiver Thread {}", e);
-            }
+    pub fn new(url: String, digest: bool, delivery: DeliveryConfig) -> BufferedWebhookClient {
+        let mut delivery = Delivery::new(sink_name("discord", &url), delivery);
+        let buffer = BufferedSender::new(
+            chrono::Duration::seconds(FLUSH_INTERVAL_SECONDS),
+            if digest { MAX_DIGEST_EVENTS } else { MAX_EMBEDS_PER_MESSAGE },
+            move |entries: Vec<Entry>| {
+                if entries.is_empty() {
+                    delivery.retry_spooled();
+                    return;
+                }
+                let embeds = if digest {
+                    build_digest(entries)
+                } else {
+                    entries.into_iter().map(|e| e.embed).collect()
+                };
+                for message in split_messages(embeds) {
+                    match serde_json::to_string(&WebhookPayload::new(&message)) {
+                        Ok(body) => delivery.deliver(OutgoingRequest {
+                            method: String::from("POST"),
+                            url: url.clone(),
+                            headers: vec![(String::from("Content-Type"), String::from("application/json"))],
+                            body,
+                        }),
+                        Err(e) => log::error!("Unable to serialize discord webhook payload {}", e),
+                    }
+                }
+            },
+        );
+        BufferedWebhookClient { buffer }
+    }
+
+    pub fn send_flush(&mut self) {
+        self.buffer.flush();
+    }
+}
+
+impl EventSink for BufferedWebhookClient {
+    fn send(&self, event: &Event) {
+        self.buffer.add(Entry {
+            source: Some(event.source),
+            name: event.name(),
+            title: event.title(),
+            embed: build_embed(event).fit(),
         });
+    }
 
-        std::thread::spawn(move || {
-            let mut buf = WebhookBuffer::new(url);
-            for received in rx {
-                match received {
-                    Message::Flush => buf.flush(),
-                    Message::AddEmbed(embed) => buf.add_message(embed),
-                }
-            }
+    fn report(&self, report: &Report) {
+        self.buffer.add(Entry {
+            source: None,
+            name: "report",
+            title: "Report",
+            embed: build_report_embed(report).fit(),
         });
+    }
 
-        BufferedWebhookClient {
-            timer,
-            guard,
-            transmitter: tx,
-        }
+    fn flush(&self) {
+        self.buffer.flush();
     }
+}
 
-    pub fn send_flush(&mut self) {
-        if let Err(e) = self.transmitter.send(Message::Flush) {
-            log::error!("Error sending Flush message to Receiver Thread {}", e);
+fn build_embed(event: &Event) -> Embed {
+    let mut embed = match &event.kind {
+        EventKind::Connection(connection) => build_connection_embed(event, connection),
+        EventKind::Visit(visit) => build_visit_embed(event, visit),
+    };
+    if let Some(geo) = &event.geo {
+        if let Some(country) = &geo.country {
+            let mut location = format!(":flag_{}: {}", country.to_lowercase(), country);
+            if let Some(name) = &geo.country_name {
+                location.push_str(&format!(" {}", name));
+            }
+            if let Some(city) = &geo.city {
+                location.push_str(&format!(", {}", city));
+            }
+            embed.field("Country", location, true);
+        }
+        match (geo.asn, &geo.organization) {
+            (Some(asn), Some(org)) => embed.field("ASN", format!("AS{} {}", asn, org), true),
+            (Some(asn), None) => embed.field("ASN", format!("AS{}", asn), true),
+            (None, Some(org)) => embed.field("ASN", org.clone(), true),
+            (None, None) => {}
         }
     }
+    if let Some(ptr) = &event.ptr {
+        embed.field("Reverse DNS", format!("`{}`", ptr), true);
+    }
+    if let Some(sensor) = &event.sensor {
+        embed.field("Sensor", format!("`{}`", sensor), true);
+    }
+    if !event.tags.is_empty() {
+        embed.field("Tags", format!("`{}`", event.tags.join("`, `")), false);
+    }
+    if let Some(alert) = &event.alert {
+        embed.title = format!("[{}] {}", alert.severity.name().to_uppercase(), embed.title);
+        embed.footer.text = format!("Rule {} · {}", alert.rule, embed.footer.text);
+    }
+    embed
+}
 
-    pub fn send(&self, address: &SocketAddr, request_type: &RequestType) {
-        if let Err(e) = self
-            .transmitter
-            .send(Message::AddEmbed(build_embed(address, request_type)))
-        {
-            log::error!("Error sending message to Receiver Thread {}", e);
+fn build_connection_embed(event: &Event, connection: &ConnectionEvent) -> Embed {
+    let address = connection.remote_address;
+    let request_type = &connection.request;
+    let handshake = match request_type {
+        RequestType::Join(req) => &req.handshake,
+        RequestType::ModernPing(req) | RequestType::LegacyPing(req) => req,
+    };
+    let mut embed = Embed::new(
+        format!("{} from {}", event.title(), address.ip()),
+        get_color_from_request_type(request_type),
+        event.timestamp,
+        format!("Persona {} · port {}", connection.persona, address.port()),
+    );
+    embed.field("IP", format!("[`{}`](https://{}/)", address, address), true);
+    embed.field("Protocol", format!("`{}`", handshake.protocol_version), true);
+    embed.field(
+        "Hostname",
+        format!("`{}:{}`", handshake.server_address, handshake.server_port),
+        true,
+    );
+
+    if let RequestType::Join(req) = request_type {
+        embed.field(
+            "Username",
+            format!(
+                "`{}`{}",
+                req.player.name,
+                if req.analysis.valid_username { "" } else { " (invalid)" }
+            ),
+            true,
+        );
+        embed.field(
+            "UUID",
+            format!(
+                "`{}` ({:?}, version {})",
+                req.player.id,
+                req.analysis.uuid_kind,
+                req.analysis.uuid_version.map(|v| v.to_string()).unwrap_or("-".to_string())
+            ),
+            false,
+        );
+        match &connection.reputation {
+            Some(Reputation {
+                profile:
+                    Profile {
+                        exists: true,
+                        canonical_name: Some(name),
+                        uuid: Some(uuid),
+                        skin_hash,
+                        ..
+                    },
+                uuid_matches,
+            }) => embed.field(
+                "Premium account",
+                format!(
+                    "[`{}`](https://namemc.com/profile/{}) (`{}`), UUID matches: `{}`, skin: `{}`",
+                    name,
+                    uuid,
+                    uuid,
+                    uuid_matches,
+                    skin_hash.as_deref().unwrap_or("-")
+                ),
+                false,
+            ),
+            Some(_) => embed.field("Premium account", "No premium account with this name exists", false),
+            None => {}
         }
     }
+    if let Some(fingerprint) = &connection.fingerprint {
+        embed.field(
+            "Fingerprint",
+            format!("`{}` (`{}`)", fingerprint.label, fingerprint.hash),
+            true,
+        );
+    }
+    embed
 }
 
-impl Drop for BufferedWebhookClient {
-    fn drop(&mut self) {
-        self.send_flush();
+fn build_visit_embed(event: &Event, visit: &Visit) -> Embed {
+    let mut embed = Embed::new(
+        format!("Visit from {} ended", visit.source),
+        RgbColor::new(20, 150, 250).rgb(),
+        event.timestamp,
+        format!("First seen {}", visit.first_seen.format("%Y-%m-%d %H:%M:%S UTC")),
+    );
+    embed.field("IP", format!("[`{}`](https://{}/)", visit.source, visit.source), true);
+    embed.field(
+        "Connections",
+        format!(
+            "{} over {}s: {} ping(s), {} legacy ping(s), {} join(s)",
+            visit.connections,
+            visit.duration().num_seconds(),
+            visit.pings,
+            visit.legacy_pings,
+            visit.joins
+        ),
+        false,
+    );
+    embed.field("Protocol", format!("`{:?}`", visit.protocol_versions), true);
+    embed.field("Hostname", code_list(&visit.server_addresses), true);
+    if !visit.usernames.is_empty() {
+        embed.field("Username", code_list(&visit.usernames), true);
+    }
+    if !visit.fingerprints.is_empty() {
+        embed.field("Fingerprint", code_list(&visit.fingerprints), true);
     }
+    embed
 }
 
-fn build_embed(address: &SocketAddr, request_type: &RequestType) -> Embed {
-    let information = match request_type {
-        RequestType::Join(ref req) => format!(
-            "[`{}` (`{}`)](https://namemc.com/profile/{}) tried joining the Server",
-            req.name, req.id, req.id
-        ),
-        RequestType::LegacyPing(ref req) => format!("Player sent legacy Ping: {:?}", req),
-        RequestType::ModernPing(ref req) => format!("Player sent regular Ping: {:?}", req),
+fn build_report_embed(report: &Report) -> Embed {
+    let title = match report.period {
+        "daily" => "Daily report",
+        "weekly" => "Weekly report",
+        _ => "Report",
     };
-    let msg = format!(
-        "Received Ping from [`{}`](https://{}/)\n\n {}",
-        address, address, information
+    let mut embed = Embed::new(
+        title.to_string(),
+        RgbColor::new(150, 100, 250).rgb(),
+        report.end,
+        format!(
+            "{} to {}",
+            report.start.format("%Y-%m-%d %H:%M"),
+            report.end.format("%Y-%m-%d %H:%M UTC")
+        ),
+    );
+    embed.field(
+        "Connections",
+        format!(
+            "{}: {} ping(s), {} legacy ping(s), {} join(s)",
+            report.connections, report.pings, report.legacy_pings, report.joins
+        ),
+        false,
     );
-    Embed {
-        title: String::from("Ping!"),
-        description: msg,
-        color: get_color_from_request_type(request_type),
+    embed.field("Unique sources", report.unique_sources.to_string(), true);
+    embed.field("Visits", report.visits.to_string(), true);
+    embed.field("Top ASNs", count_list(&report.top_asns), false);
+    embed.field("Top countries", count_list(&report.top_countries), true);
+    embed.field("Top usernames", count_list(&report.top_usernames), true);
+    embed.field("Protocol versions", count_list(&report.protocol_versions), true);
+    embed.field("New fingerprints", code_list(&report.new_fingerprints), false);
+    embed
+}
+
+/// Merges the entries of every source with more than one into one embed.
+/// The embed of the latest join, or the latest event without one, stands in for the rest.
+fn build_digest(entries: Vec<Entry>) -> Vec<Embed> {
+    let mut groups: Vec<(Option<IpAddr>, Vec<Entry>)> = Vec::new();
+    for entry in entries {
+        match groups
+            .iter_mut()
+            .find(|(source, _)| source.is_some() && *source == entry.source)
+        {
+            Some((_, group)) => group.push(entry),
+            None => groups.push((entry.source, vec![entry])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(_, mut group)| {
+            if group.len() == 1 {
+                return group.remove(0).embed;
+            }
+            let first = group[0].embed.timestamp;
+            let last = group[group.len() - 1].embed.timestamp;
+            let mut counts: Vec<(&'static str, usize)> = Vec::new();
+            for entry in &group {
+                match counts.iter_mut().find(|(title, _)| *title == entry.title) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((entry.title, 1)),
+                }
+            }
+            let total = group.len();
+            let index = group
+                .iter()
+                .rposition(|e| e.name == "join")
+                .unwrap_or(total - 1);
+            let mut embed = group.swap_remove(index).embed;
+
+            embed.title = format!("{} ({} events)", embed.title, total);
+            embed.timestamp = last;
+            embed.footer.text = format!(
+                "{} · {} to {}",
+                embed.footer.text,
+                first.format("%H:%M:%S"),
+                last.format("%H:%M:%S")
+            );
+            let counts = counts
+                .iter()
+                .map(|(title, count)| {
+                    format!("{} {}{}", count, title.to_lowercase(), if *count == 1 { "" } else { "s" })
+                })
+                .collect::<Vec<String>>();
+            embed.fields.insert(
+                0,
+                EmbedField {
+                    name: String::from("Events"),
+                    value: counts.join(", "),
+                    inline: false,
+                },
+            );
+            embed.fit()
+        })
+        .collect()
+}
+
+/// Packs embeds into messages of at most ten embeds and `MAX_EMBED_LENGTH` characters
+fn split_messages(embeds: Vec<Embed>) -> Vec<Vec<Embed>> {
+    let mut messages: Vec<Vec<Embed>> = Vec::new();
+    let mut current: Vec<Embed> = Vec::new();
+    let mut length = 0;
+    for embed in embeds {
+        let embed_length = embed.length();
+        if !current.is_empty()
+            && (current.len() >= MAX_EMBEDS_PER_MESSAGE || length + embed_length > MAX_EMBED_LENGTH)
+        {
+            messages.push(std::mem::take(&mut current));
+            length = 0;
+        }
+        length += embed_length;
+        current.push(embed);
+    }
+    if !current.is_empty() {
+        messages.push(current);
     }
+    messages
+}
+
+/// One `value` count per line
+fn count_list(counts: &[Count]) -> String {
+    counts
+        .iter()
+        .map(|c| format!("`{}` {}", c.value, c.count))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn code_list(values: &[String]) -> String {
+    if values.is_empty() {
+        String::new()
+    } else {
+        format!("`{}`", values.join("`, `"))
+    }
+}
+
+/// Cuts `text` to at most `max` characters, marking the cut with an ellipsis
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(max.saturating_sub(1)).collect::<String>();
+    truncated.push('…');
+    truncated
 }
 
 fn get_color_from_request_type(request_type: &RequestType) -> i32 {
@@ -149,13 +497,72 @@ fn get_color_from_request_type(request_type: &RequestType) -> i32 {
     }
 }
 
-fn send_webhook_payload(url: String, payload: WebhookPayload) {
-    std::thread::spawn(move || {
-        let client = reqwest::blocking::Client::new();
-        let res = client.post(url).json(&payload).send();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if let Err(e) = res {
-            log::error!("There was an error executing discord webhook {}", e);
+    fn embed(title: &str) -> Embed {
+        Embed::new(title.to_string(), 0, Utc::now(), String::from("footer"))
+    }
+
+    fn entry(source: &str, name: &'static str, title: &'static str) -> Entry {
+        Entry {
+            source: Some(source.parse().unwrap()),
+            name,
+            title,
+            embed: embed(&format!("{} from {}", title, source)),
         }
-    });
+    }
+
+    #[test]
+    fn fits_embeds_into_the_limits() {
+        let mut large = embed(&"t".repeat(300));
+        large.description = "d".repeat(5000);
+        for i in 0..30 {
+            large.field(&format!("field {}", i), "v".repeat(2000), false);
+        }
+        let fitted = large.fit();
+        assert_eq!(fitted.title.chars().count(), MAX_TITLE_LENGTH);
+        assert!(fitted.title.ends_with('…'));
+        assert!(fitted.fields.len() <= MAX_FIELDS);
+        assert!(fitted.fields.iter().all(|f| f.value.chars().count() <= MAX_FIELD_VALUE_LENGTH));
+        assert!(fitted.length() <= MAX_EMBED_LENGTH);
+    }
+
+    #[test]
+    fn keeps_small_embeds_as_they_are() {
+        let mut small = embed("Ping from 192.0.2.1");
+        small.field("Empty", "", true);
+        let fitted = small.fit();
+        assert_eq!(fitted.title, "Ping from 192.0.2.1");
+        assert_eq!(fitted.fields[0].value, "-");
+    }
+
+    #[test]
+    fn splits_messages_by_count_and_length() {
+        let messages = split_messages((0..25).map(|i| embed(&i.to_string())).collect());
+        assert_eq!(messages.iter().map(Vec::len).collect::<Vec<usize>>(), vec![10, 10, 5]);
+
+        let mut large = embed("large");
+        large.description = "d".repeat(MAX_DESCRIPTION_LENGTH);
+        let messages = split_messages(vec![large.clone(), large.clone(), embed("small")]);
+        assert_eq!(messages.iter().map(Vec::len).collect::<Vec<usize>>(), vec![1, 2]);
+        assert!(messages
+            .iter()
+            .all(|m| m.iter().map(Embed::length).sum::<usize>() <= MAX_EMBED_LENGTH));
+    }
+
+    #[test]
+    fn merges_digests_by_source() {
+        let embeds = build_digest(vec![
+            entry("192.0.2.1", "ping", "Ping"),
+            entry("192.0.2.1", "join", "Join attempt"),
+            entry("198.51.100.1", "ping", "Ping"),
+            entry("192.0.2.1", "ping", "Ping"),
+        ]);
+        assert_eq!(embeds.len(), 2);
+        assert_eq!(embeds[0].title, "Join attempt from 192.0.2.1 (3 events)");
+        assert_eq!(embeds[0].fields[0].value, "2 pings, 1 join attempt");
+        assert_eq!(embeds[1].title, "Ping from 198.51.100.1");
+    }
 }